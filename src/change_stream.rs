@@ -0,0 +1,66 @@
+//! Change streams yielding typed `Model` changes.
+
+use crate::Model;
+use futures_core::Stream;
+use mongodb::bson::Document;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::change_stream::ChangeStream;
+use mongodb::error::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A change on a `Model`, with the state of the document before and after the change.
+#[derive(Debug, Clone)]
+pub struct ModelChange<M> {
+    /// Type of operation that caused this change
+    pub operation_type: OperationType,
+    /// `_id` of the changed document
+    pub document_key: Option<Document>,
+    /// Document before the change, if available
+    pub before: Option<M>,
+    /// Document after the change, if available
+    pub after: Option<M>,
+}
+
+impl<M> From<ChangeStreamEvent<M>> for ModelChange<M> {
+    fn from(event: ChangeStreamEvent<M>) -> Self {
+        Self {
+            operation_type: event.operation_type,
+            document_key: event.document_key,
+            before: event.full_document_before_change,
+            after: event.full_document,
+        }
+    }
+}
+
+/// Stream of `ModelChange` created by `Repository` watch methods.
+#[derive(Debug)]
+pub struct ModelChangeStream<M: Model> {
+    inner: ChangeStream<ChangeStreamEvent<M>>,
+}
+
+impl<M: Model> ModelChangeStream<M> {
+    pub(crate) fn new(inner: ChangeStream<ChangeStreamEvent<M>>) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the cached resume token that can be used to resume after the most recently returned change.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.inner.resume_token()
+    }
+
+    /// Returns the underlying `mongodb::change_stream::ChangeStream`.
+    pub fn into_inner(self) -> ChangeStream<ChangeStreamEvent<M>> {
+        self.inner
+    }
+}
+
+impl<M: Model> Stream for ModelChangeStream<M> {
+    type Item = Result<ModelChange<M>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|item| item.map(|event| event.map(ModelChange::from)))
+    }
+}
@@ -0,0 +1,417 @@
+//! Cursors decoding documents into typed values.
+
+use futures_core::Stream;
+use futures_util::io::{AsyncWrite, AsyncWriteExt};
+use futures_util::TryStreamExt;
+use mongodb::bson::{
+    from_bson, from_document, from_slice, Bson, Document, RawDocument, RawDocumentBuf,
+};
+use mongodb::error::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Cursor over the results of a MongODM find helper, yielding values of type `T`.
+///
+/// Documents failing to deserialize are reported with the `_id` of the offending document.
+/// Values are deserialized directly from the raw BSON returned by the server, without building
+/// an intermediate `Document`.
+#[derive(Debug)]
+pub struct ModelCursor<T> {
+    inner: mongodb::Cursor<RawDocumentBuf>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> ModelCursor<T> {
+    pub(crate) fn new(inner: mongodb::Cursor<Document>) -> Self {
+        Self {
+            inner: inner.with_type(),
+            _type: PhantomData,
+        }
+    }
+
+    /// Returns the underlying `mongodb::Cursor`.
+    pub fn into_inner(self) -> mongodb::Cursor<Document> {
+        self.inner.with_type()
+    }
+
+    /// Collect at most `max` values, along with a flag telling whether the cursor had more values.
+    ///
+    /// No additional batch is fetched once `max` values are collected: the flag is determined from
+    /// the current batch and the state of the server cursor. As such it may be `true` when the last
+    /// collected value happens to be the last one of the results, if the server didn't close the cursor yet.
+    pub async fn collect_up_to(mut self, max: usize) -> Result<(Vec<T>, bool)> {
+        let mut items = Vec::new();
+        while items.len() < max {
+            match self.try_next().await? {
+                Some(item) => items.push(item),
+                None => return Ok((items, false)),
+            }
+        }
+        Ok((items, self.inner.has_next()))
+    }
+
+    /// Wrap this cursor into an `IdModelCursor`, yielding the `_id` of each document along with its value.
+    ///
+    /// Useful for models without an `_id` field, such as to build a map keyed by id. The `_id` is deserialized as
+    /// `K`, typically `ObjectId`: documents without `_id` or with an `_id` of another type are reported as errors.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use futures_util::TryStreamExt;
+    /// use std::collections::HashMap;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let users: HashMap<ObjectId, User> = db
+    ///     .repository::<User>()
+    ///     .find_with_defaults(doc! {}, None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_ids()
+    ///     .try_collect()
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn with_ids<K: DeserializeOwned>(self) -> IdModelCursor<K, T> {
+        IdModelCursor {
+            inner: self.inner,
+            _type: PhantomData,
+        }
+    }
+
+    /// Wrap this cursor into a `PeekableModelCursor`, allowing to look at the next value without consuming it.
+    pub fn peekable(self) -> PeekableModelCursor<T> {
+        PeekableModelCursor {
+            cursor: self,
+            peeked: None,
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Serialize> ModelCursor<T> {
+    /// Write each value as a line of JSON (NDJSON) into `writer`, returning the number of written lines.
+    ///
+    /// Values are streamed: only the current batch is held in memory. Bson specific types are written
+    /// using their serde representation, such as `{"$oid": "..."}` for an `ObjectId`.
+    ///
+    /// Documents failing to deserialize as `T` or to serialize as JSON are reported with their `_id`.
+    /// If `skip_invalid` is `true`, such documents are skipped instead, and not counted. Errors from the
+    /// server or from `writer` always stop the export. The writer is flushed but not closed.
+    pub async fn write_ndjson<W>(mut self, mut writer: W, skip_invalid: bool) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        let mut line = Vec::new();
+        while let Some(doc) = self.inner.try_next().await? {
+            let id = h_raw_id(&doc);
+            let res = h_from_raw_document::<T>(&doc).and_then(|value| {
+                line.clear();
+                serde_json::to_writer(&mut line, &value).map_err(|e| {
+                    let msg = match &id {
+                        Some(id) => format!("failed to serialize document with _id {}: {}", id, e),
+                        None => format!("failed to serialize document: {}", e),
+                    };
+                    std::io::Error::other(msg).into()
+                })
+            });
+            match res {
+                Ok(()) => {
+                    line.push(b'\n');
+                    writer.write_all(&line).await?;
+                    written += 1;
+                }
+                Err(_) if skip_invalid => {}
+                Err(e) => return Err(e),
+            }
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+}
+
+impl<T: DeserializeOwned> Stream for ModelCursor<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|item| item.map(|doc| h_from_raw_document(&doc?)))
+    }
+}
+
+/// Cursor yielding `(id, value)` pairs, created by `ModelCursor::with_ids`.
+#[derive(Debug)]
+pub struct IdModelCursor<K, T> {
+    inner: mongodb::Cursor<RawDocumentBuf>,
+    _type: PhantomData<fn() -> (K, T)>,
+}
+
+impl<K: DeserializeOwned, T: DeserializeOwned> Stream for IdModelCursor<K, T> {
+    type Item = Result<(K, T)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|item| item.map(|doc| h_from_raw_document_with_id(&doc?)))
+    }
+}
+
+/// Deserialize the `_id` of a document as `K`, and the document itself as `T`.
+fn h_from_raw_document_with_id<K: DeserializeOwned, T: DeserializeOwned>(
+    doc: &RawDocument,
+) -> Result<(K, T)> {
+    let id = h_raw_id(doc).ok_or_else(|| std::io::Error::other("document has no _id"))?;
+    let key = from_bson(id.clone())
+        .map_err(|e| std::io::Error::other(format!("failed to deserialize _id {}: {}", id, e)))?;
+    Ok((key, h_from_raw_document(doc)?))
+}
+
+/// `ModelCursor` with one value of lookahead, created by `ModelCursor::peekable`.
+///
+/// Useful to merge two cursors sorted by the same key client-side, when a `$lookup` would be too heavy.
+/// At most one value is held in addition to the current batch of the underlying cursor.
+///
+/// # Example
+///
+/// ```no_run
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct User { name: String }
+/// # impl Model for User { type CollConf = UserCollConf; }
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf { fn collection_name() -> &'static str { "user" } }
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Login { user: String }
+/// # impl Model for Login { type CollConf = LoginCollConf; }
+/// # struct LoginCollConf;
+/// # impl CollectionConfig for LoginCollConf { fn collection_name() -> &'static str { "login" } }
+/// use mongodm::prelude::*;
+/// use futures_util::TryStreamExt;
+///
+/// # async fn demo(db: mongodb::Database) -> mongodb::error::Result<()> {
+/// // Count logins of each user, both cursors being sorted by user name
+/// let sort = |key| MongoFindOptions::builder().sort(doc! { key: 1 }).build();
+/// let mut users = db
+///     .repository::<User>()
+///     .find_with_defaults(doc! {}, sort(f!(name in User)))
+///     .await?;
+/// let mut logins = db
+///     .repository::<Login>()
+///     .find_with_defaults(doc! {}, sort(f!(user in Login)))
+///     .await?
+///     .peekable();
+///
+/// while let Some(user) = users.try_next().await? {
+///     let mut count = 0;
+///     while let Some(login) = logins.peek().await? {
+///         if login.user > user.name {
+///             break;
+///         }
+///         if login.user == user.name {
+///             count += 1;
+///         }
+///         logins.advance().await?;
+///     }
+///     println!("{}: {} logins", user.name, count);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PeekableModelCursor<T> {
+    cursor: ModelCursor<T>,
+    peeked: Option<Option<Result<T>>>,
+}
+
+impl<T: DeserializeOwned> PeekableModelCursor<T> {
+    /// Returns a reference to the next value without consuming it, or `None` once the cursor is exhausted.
+    ///
+    /// Errors are not discarded: failing to fetch or deserialize the next value returns the error on
+    /// each call to `peek`, until it's consumed by `advance` or by polling the stream.
+    pub async fn peek(&mut self) -> Result<Option<&T>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.cursor.try_next().await.transpose());
+        }
+        match self.peeked.as_ref().and_then(Option::as_ref) {
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(e)) => Err(e.clone()),
+            None => Ok(None),
+        }
+    }
+
+    /// Discard the next value, returning `false` if the cursor was already exhausted.
+    ///
+    /// If the next value couldn't be fetched or deserialized, the error is returned (and consumed).
+    pub async fn advance(&mut self) -> Result<bool> {
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.cursor.try_next().await.transpose(),
+        };
+        next.transpose().map(|value| value.is_some())
+    }
+
+    /// Returns the underlying `ModelCursor`, along with the peeked value if any.
+    pub fn into_inner(self) -> (ModelCursor<T>, Option<Result<T>>) {
+        (self.cursor, self.peeked.flatten())
+    }
+}
+
+impl<T: DeserializeOwned + Unpin> Stream for PeekableModelCursor<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(peeked) = self.peeked.take() {
+            return Poll::Ready(peeked);
+        }
+        Pin::new(&mut self.cursor).poll_next(cx)
+    }
+}
+
+/// Deserialize a document, adding the `_id` of the document to the error message on failure.
+pub(crate) fn h_from_document<T: DeserializeOwned>(doc: Document) -> Result<T> {
+    let id = doc.get("_id").cloned();
+    from_document(doc).map_err(|e| {
+        let msg = match id {
+            Some(id) => format!("failed to deserialize document with _id {}: {}", id, e),
+            None => format!("failed to deserialize document: {}", e),
+        };
+        std::io::Error::other(msg).into()
+    })
+}
+
+/// Same as `h_from_document`, deserializing directly from raw BSON.
+pub(crate) fn h_from_raw_document<T: DeserializeOwned>(doc: &RawDocument) -> Result<T> {
+    from_slice(doc.as_bytes()).map_err(|e| {
+        let msg = match h_raw_id(doc) {
+            Some(id) => format!("failed to deserialize document with _id {}: {}", id, e),
+            None => format!("failed to deserialize document: {}", e),
+        };
+        std::io::Error::other(msg).into()
+    })
+}
+
+fn h_raw_id(doc: &RawDocument) -> Option<Bson> {
+    let id = doc.get("_id").ok()??;
+    Bson::try_from(id.to_raw_bson()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct User {
+        name: String,
+        age: i32,
+    }
+
+    #[test]
+    fn deserialization_error_mentions_id() {
+        let id = ObjectId::new();
+        let err = h_from_document::<User>(doc! { "_id": id, "name": "David", "age": "35" })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&id.to_hex()), "{}", err);
+        assert!(err.contains("invalid type"), "{}", err);
+
+        let err = h_from_document::<User>(doc! { "name": "David" })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing field `age`"), "{}", err);
+    }
+
+    #[test]
+    fn raw_deserialization_error_mentions_id() {
+        let id = ObjectId::new();
+        let raw = RawDocumentBuf::from_document(&doc! { "_id": id, "name": "David", "age": "35" })
+            .unwrap();
+        let err = h_from_raw_document::<User>(&raw).unwrap_err().to_string();
+        assert!(err.contains(&id.to_hex()), "{}", err);
+        assert!(err.contains("invalid type"), "{}", err);
+
+        let raw = RawDocumentBuf::from_document(&doc! { "name": "David", "age": 35 }).unwrap();
+        let user = h_from_raw_document::<User>(&raw).unwrap();
+        assert_eq!(user.age, 35);
+    }
+
+    #[test]
+    fn deserialization_with_id() {
+        let id = ObjectId::new();
+        let raw =
+            RawDocumentBuf::from_document(&doc! { "_id": id, "name": "David", "age": 35 }).unwrap();
+        let (key, user) = h_from_raw_document_with_id::<ObjectId, User>(&raw).unwrap();
+        assert_eq!(key, id);
+        assert_eq!(user.name, "David");
+
+        let err = h_from_raw_document_with_id::<i64, User>(&raw)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("failed to deserialize _id"), "{}", err);
+
+        let raw = RawDocumentBuf::from_document(&doc! { "name": "David", "age": 35 }).unwrap();
+        let err = h_from_raw_document_with_id::<ObjectId, User>(&raw)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("document has no _id"), "{}", err);
+    }
+
+    /// Compares decoding through an intermediate `Document` (as done before `ModelCursor` decoded raw BSON)
+    /// with decoding directly from raw BSON. Run with:
+    /// `cargo test --release --lib decode_wide_document_timing -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn decode_wide_document_timing() {
+        use std::collections::HashMap;
+        use std::time::Instant;
+
+        const FIELDS: usize = 200;
+        const ITERATIONS: usize = 20_000;
+
+        let mut wide = Document::new();
+        for i in 0..FIELDS {
+            wide.insert(format!("field_{}", i), format!("value of field {}", i));
+        }
+        let raw = RawDocumentBuf::from_document(&wide).unwrap();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let doc = Document::try_from(raw.as_ref()).unwrap();
+            let value: HashMap<String, String> = h_from_document(doc).unwrap();
+            assert_eq!(value.len(), FIELDS);
+        }
+        let through_document = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let value: HashMap<String, String> = h_from_raw_document(&raw).unwrap();
+            assert_eq!(value.len(), FIELDS);
+        }
+        let from_raw = start.elapsed();
+
+        println!(
+            "decoding {} documents of {} fields: {:?} through a Document, {:?} from raw BSON",
+            ITERATIONS, FIELDS, through_document, from_raw
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Terse constructors for common BSON values.
+
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use std::time::SystemTime;
+
+/// Current time as a `bson::DateTime`.
+///
+/// Shorthand for `bson::DateTime::now()`.
+pub fn now() -> DateTime {
+    DateTime::now()
+}
+
+/// A fresh `ObjectId`.
+///
+/// Shorthand for `ObjectId::new()`.
+pub fn new_object_id() -> ObjectId {
+    ObjectId::new()
+}
+
+/// Current time as milliseconds since the Unix epoch, the precision of BSON dates.
+pub fn bson_now_millis() -> i64 {
+    DateTime::now().timestamp_millis()
+}
+
+/// Current time truncated to the millisecond.
+///
+/// BSON dates only store milliseconds: a `SystemTime` (or a `chrono::DateTime`) usually carries
+/// sub-millisecond precision which is silently dropped when the value is stored. As a consequence,
+/// a value read back from the database isn't equal to the in-memory value it was saved from, a common
+/// source of failing equality checks. Values returned by this function survive such a round trip.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// let now = bson_now_truncated();
+/// let stored = BsonDateTime::from_system_time(now);
+/// assert_eq!(stored.to_system_time(), now);
+/// ```
+pub fn bson_now_truncated() -> SystemTime {
+    DateTime::now().to_system_time()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn truncated_round_trip() {
+        let precise = SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        let stored = DateTime::from_system_time(precise);
+        assert_ne!(stored.to_system_time(), precise);
+        assert_eq!(stored.timestamp_millis(), 1_600_000_000_123);
+
+        let now = bson_now_truncated();
+        assert_eq!(DateTime::from_system_time(now).to_system_time(), now);
+    }
+}
@@ -1,10 +1,12 @@
 //! Indexes are used for efficient mongo queries.
 
-use crate::CollectionConfig;
-use mongodb::bson::{doc, from_bson, Bson, Document};
-use mongodb::options::ReadPreference;
+use crate::query::BsonTypeName;
+use crate::repository::{h_create_collection_options, h_to_document};
+use crate::{CollectionConfig, Model};
+use mongodb::bson::{doc, from_bson, from_document, to_document, Bson, Document};
+use mongodb::options::{Collation, ReadPreference};
 use mongodb::options::{RunCommandOptions, SelectionCriteria};
-use mongodb::Database;
+use mongodb::{Database, IndexModel};
 use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -94,10 +96,41 @@ struct TextIndexKey {
 ///     }
 /// )
 /// ```
+///
+/// Fields of embedded documents are indexed using their dotted path, which is best obtained
+/// with `field!` to statically check the path exists.
+///
+/// ```
+/// use mongodm::{field, Index, mongo::bson::doc};
+///
+/// struct User {
+///     profile: Profile,
+/// }
+///
+/// struct Profile {
+///     city: String,
+///     country: String,
+/// }
+///
+/// let index = Index::new(field!((profile in User).(country in Profile)))
+///     .with_key(field!((profile in User).(city in Profile)));
+///
+/// assert_eq!(
+///     index.into_document(),
+///     doc! {
+///         "key": { "profile.country": 1, "profile.city": 1 },
+///         "name": "profile.country_1_profile.city_1",
+///     }
+/// )
+/// ```
+///
+/// An index needs at least one key: `Index::default()` has none and must be completed using
+/// `add_key_with_direction` or `add_key_with_text` before use, otherwise `sync_indexes` fails.
 #[derive(Default, Clone, Debug)]
 pub struct Index {
     keys: Vec<IndexKey>,
     options: Vec<IndexOption>,
+    without_auto_name: bool,
 }
 
 impl Index {
@@ -126,6 +159,60 @@ impl Index {
         index
     }
 
+    /// Make a new TTL index expiring each document at the date stored in its `key` field.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/tutorial/expire-data/#expire-documents-at-a-specific-clock-time)
+    ///
+    /// See `Repository::insert_expiring` which stores the expiration date in the `EXPIRE_AT_FIELD` field.
+    /// Expired documents are removed by a background task running every 60 seconds:
+    /// documents may still be returned by queries for a short time after their expiration date.
+    pub fn new_expire_at(key: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(key).with_option(IndexOption::ExpireAfterSeconds(0))
+    }
+
+    /// Make a new unique index on `key`, only enforced for documents where `key` holds a value of type `bson_type`.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/core/index-partial/#partial-index-with-unique-constraint)
+    ///
+    /// This is the usual "unique, but optional" constraint: any number of documents may lack the field or
+    /// have it set to `null` (which is how `None` is serialized unless skipped), while present values must
+    /// be unique. It is built as a unique index with a `partialFilterExpression` on the type of the field.
+    ///
+    /// A `sparse` unique index is not a good fit: `null` values are still indexed, so two documents with
+    /// a `None` field collide, and a compound sparse index indexes documents holding any of its keys.
+    /// Filtering on `{ "$exists": true }` has the same issue with `null` values.
+    ///
+    /// Note that queries only use a partial index when the query filter implies the partial filter.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::prelude::*;
+    ///
+    /// let index = Index::new_unique_if_present("email", BsonTypeName::String);
+    ///
+    /// assert_eq!(
+    ///     index.into_document(),
+    ///     doc! {
+    ///         "key": { "email": 1 },
+    ///         "unique": true,
+    ///         "partialFilterExpression": { "email": { "$type": "string" } },
+    ///         "name": "email_1",
+    ///     }
+    /// );
+    /// ```
+    pub fn new_unique_if_present(
+        key: impl Into<Cow<'static, str>>,
+        bson_type: BsonTypeName,
+    ) -> Self {
+        let key = key.into();
+        let mut partial_filter = Document::new();
+        partial_filter.insert(key.as_ref(), doc! { "$type": bson_type });
+        Self::new(key)
+            .with_option(IndexOption::Unique)
+            .with_option(IndexOption::PartialFilterExpression(partial_filter))
+    }
+
     /// Make this index compound adding the given key with ascending direction.
     ///
     /// [Mongo manual](https://docs.mongodb.com/manual/core/index-compound/).
@@ -184,19 +271,265 @@ impl Index {
         self
     }
 
+    /// Specify the collation of this index.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/collation/)
+    ///
+    /// Typed alternative to `IndexOption::Collation`.
+    ///
+    /// # Example
+    /// ```
+    /// use mongodm::{Index, IndexOption, mongo::bson::doc, mongo::options::{Collation, CollationStrength}};
+    ///
+    /// let index = Index::new("username")
+    ///     .with_option(IndexOption::Unique)
+    ///     .with_collation(
+    ///         Collation::builder()
+    ///             .locale("en")
+    ///             .strength(CollationStrength::Secondary)
+    ///             .build(),
+    ///     );
+    ///
+    /// assert_eq!(
+    ///     index.into_document(),
+    ///     doc! {
+    ///         "key": { "username": 1 },
+    ///         "unique": true,
+    ///         "collation": { "locale": "en", "strength": 2 },
+    ///         "name": "username_1",
+    ///     }
+    /// );
+    /// ```
+    pub fn with_collation(self, collation: Collation) -> Self {
+        let collation = to_document(&collation).expect("collation is always serializable");
+        self.with_option(IndexOption::Collation(collation))
+    }
+
+    /// Don't generate a name for this index when none is given with `IndexOption::Name`,
+    /// letting the server assign one.
+    ///
+    /// `sync_indexes` identifies such indexes by their keys only, whatever name the server chose.
+    /// As a consequence, two indexes without name can't share the same keys (they would collide anyway
+    /// with the default naming scheme).
+    ///
+    /// # Example
+    /// ```
+    /// use mongodm::{Index, mongo::bson::doc};
+    ///
+    /// let index = Index::new("username").without_auto_name();
+    ///
+    /// assert_eq!(index.into_document(), doc! { "key": { "username": 1 } });
+    /// ```
+    pub fn without_auto_name(mut self) -> Self {
+        self.without_auto_name = true;
+        self
+    }
+
+    /// Keys and their values in declaration order, identifying this index.
+    fn key_spec(&self) -> Vec<(String, Bson)> {
+        self.keys
+            .iter()
+            .map(|key| (key.get_name(), key.get_value()))
+            .collect()
+    }
+
+    /// Document to compare with the output of `listIndexes`, along with the original keys of a text index.
+    fn h_comparison_document(&self) -> (Document, Option<Bson>) {
+        let mut doc = self.clone().into_document();
+        if self
+            .keys
+            .iter()
+            .any(|ind| matches!(ind, IndexKey::TextIndex(_)))
+        {
+            // There can only be 1 text index per collection so when a text index is saved, the text keys are automatically changed to this,
+            // in place of the first text key. Scalar keys of a compound text index are kept as is.
+            // We keep a copy of the text keys for the weight comparison.
+            let mut stored_keys = Document::new();
+            let mut text_index_keys = Document::new();
+            for key in &self.keys {
+                match key {
+                    IndexKey::TextIndex(_) => {
+                        if text_index_keys.is_empty() {
+                            stored_keys.insert("_fts", "text");
+                            stored_keys.insert("_ftsx", 1);
+                        }
+                        text_index_keys.insert(key.get_name(), key.get_value());
+                    }
+                    IndexKey::SortIndex(_) => {
+                        stored_keys.insert(key.get_name(), key.get_value());
+                    }
+                }
+            }
+            doc.insert("key", stored_keys);
+            (doc, Some(Bson::Document(text_index_keys)))
+        } else {
+            (doc, None)
+        }
+    }
+
+    /// Whether an existing index, as returned by `listIndexes`, matches this specification.
+    ///
+    /// This is the comparison used by `sync_indexes` to decide if an index can be kept as is, so
+    /// it accounts for how the server stores indexes:
+    /// - `ns` and `v` fields, as well as the obsolete `background` option, are ignored;
+    /// - any name is accepted when this index has none (see `without_auto_name`);
+    /// - text indexes keys are compared using the `_fts` key and the `weights` field;
+    /// - collation fields filled with the locale defaults by the server are ignored.
+    ///
+    /// ```
+    /// use mongodm::prelude::*;
+    ///
+    /// let index = Index::new("username").with_option(IndexOption::Unique);
+    ///
+    /// let existing = doc! { "v": 2, "key": { "username": 1 }, "name": "username_1", "unique": true };
+    /// assert!(index.matches_existing(&existing));
+    ///
+    /// let existing = doc! { "v": 2, "key": { "username": 1 }, "name": "username_1" };
+    /// assert!(!index.matches_existing(&existing));
+    /// ```
+    pub fn matches_existing(&self, existing: &Document) -> bool {
+        let (mut index_doc, text_index_keys) = self.h_comparison_document();
+
+        // Document equality ignores the order of fields, but the order of keys defines the index
+        let key = index_doc.get("key").map(ToString::to_string);
+        if existing.get("key").map(ToString::to_string) != key {
+            return false;
+        }
+
+        let mut existing_index = existing.clone();
+
+        // "ns" and "v" in the response should not be used for the comparison
+        existing_index.remove("ns");
+        existing_index.remove("v");
+
+        // Ignored since MongoDB 4.2, and only stored by older versions
+        index_doc.remove("background");
+        existing_index.remove("background");
+
+        // Index without name: whatever name was assigned by the server is fine
+        if !index_doc.contains_key("name") {
+            existing_index.remove("name");
+        }
+
+        h_normalize_collation(&index_doc, &mut existing_index);
+
+        // We compare the text index here, the keys become weights of 1 after saving in the DB. Custom weights not supported yet.
+        if let Some(Bson::Document(mut keys_to_set)) = text_index_keys {
+            if let Some(Bson::Document(existing_weights)) = existing_index.get("weights") {
+                // Changing all text values to the default weight of 1
+                for keys in keys_to_set.iter_mut() {
+                    match keys.1 {
+                        Bson::String(t) if t == "text" => {
+                            *keys.1 = Bson::Int32(1);
+                        }
+                        _ => (),
+                    }
+                }
+
+                return existing_weights.eq(&keys_to_set);
+            }
+        }
+
+        doc_are_eq(&index_doc, &existing_index)
+    }
+
+    /// Same as `into_document`, but fails if this index has no key.
+    ///
+    /// ```
+    /// use mongodm::Index;
+    ///
+    /// assert!(Index::default().try_into_document().is_err());
+    /// assert!(Index::new("username").try_into_document().is_ok());
+    /// ```
+    pub fn try_into_document(self) -> Result<Document, mongodb::error::Error> {
+        self.h_check_keys()?;
+        Ok(self.into_document())
+    }
+
+    fn h_check_keys(&self) -> Result<(), mongodb::error::Error> {
+        if self.keys.is_empty() {
+            let name = self.options.iter().find_map(|option| match option {
+                IndexOption::Name(name) => Some(name),
+                _ => None,
+            });
+            let msg = match name {
+                Some(name) => format!("index \"{}\" has no key", name),
+                None => "index has no key".to_owned(),
+            };
+            return Err(std::io::Error::other(msg).into());
+        }
+        Ok(())
+    }
+
+    /// Name of this index, either given with `IndexOption::Name` or auto-generated from the keys.
+    ///
+    /// Returns `None` for an index declared with `without_auto_name` and no explicit name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::{Index, IndexOption, SortOrder};
+    ///
+    /// let index = Index::new("username").with_key_with_direction("last_seen", SortOrder::Descending);
+    /// assert_eq!(index.name().as_deref(), Some("username_1_last_seen_-1"));
+    ///
+    /// let index = Index::new("email").with_option(IndexOption::Name("by_email".to_owned()));
+    /// assert_eq!(index.name().as_deref(), Some("by_email"));
+    ///
+    /// assert_eq!(Index::new("email").without_auto_name().name(), None);
+    /// ```
+    pub fn name(&self) -> Option<String> {
+        // The last name option wins, as it overrides the previous ones in `into_document`
+        match self
+            .options
+            .iter()
+            .rev()
+            .find(|option| option.name() == "name")
+        {
+            Some(IndexOption::Name(name)) => Some(name.clone()),
+            Some(IndexOption::Custom {
+                value: Bson::String(name),
+                ..
+            }) => Some(name.clone()),
+            Some(_) => None,
+            None if self.without_auto_name => None,
+            None => Some(self.h_auto_name()),
+        }
+    }
+
+    /// Default name of an index, as generated by mongo.
+    ///
+    /// > The default name for an index is the concatenation of the
+    /// > indexed keys and each key’s direction in the index ( i.e. 1 or -1)
+    /// > using underscores as a separator.
+    ///
+    /// https://docs.mongodb.com/manual/indexes/#index-names
+    fn h_auto_name(&self) -> String {
+        self.keys
+            .iter()
+            .map(IndexKey::get_key_name)
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    /// Keys of this index and their values (direction or `"text"`), in declaration order.
+    pub fn keys(&self) -> Document {
+        self.key_spec().into_iter().collect()
+    }
+
+    /// Options of this index, in declaration order.
+    pub fn options(&self) -> &[IndexOption] {
+        &self.options
+    }
+
     /// Convert this structure into a `Document` version structured as expected by mongo.
     pub fn into_document(self) -> Document {
         // If document is missing "name" we follow default name generation as described in mongodb doc and
         // add it.
-        // https://docs.mongodb.com/manual/indexes/#index-names
-        // > The default name for an index is the concatenation of the
-        // > indexed keys and each key’s direction in the index ( i.e. 1 or -1)
-        // > using underscores as a separator.
+        let auto_name = (!self.without_auto_name).then(|| self.h_auto_name());
 
-        let mut names = Vec::with_capacity(self.keys.len());
         let mut keys_doc = Document::new();
         for key in self.keys {
-            names.push(key.get_key_name());
             keys_doc.insert(key.get_name(), key.get_value());
         }
 
@@ -207,15 +540,84 @@ impl Index {
             index_doc.insert(key, value);
         }
 
-        if !index_doc.contains_key("name") {
-            let name = names.join("_");
-            index_doc.insert("name", name);
+        if let Some(name) = auto_name {
+            if !index_doc.contains_key("name") {
+                index_doc.insert("name", name);
+            }
         }
 
         index_doc
     }
 }
 
+/// Conversion from the driver's index representation, for reuse with `sync_indexes`.
+///
+/// Keys must be ascending (`1`), descending (`-1`) or `"text"`, other index types (`"2dsphere"`,
+/// `"hashed"`...) are not supported by `Index` and make the conversion fail. Options with a dedicated
+/// `IndexOption` variant are mapped to it, others become `IndexOption::Custom`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::{Index, mongo::bson::doc, mongo::options::IndexOptions, mongo::IndexModel};
+///
+/// let model = IndexModel::builder()
+///     .keys(doc! { "username": 1 })
+///     .options(IndexOptions::builder().unique(true).hidden(true).build())
+///     .build();
+/// let index = Index::try_from(model).unwrap();
+///
+/// assert_eq!(
+///     index.into_document(),
+///     doc! { "key": { "username": 1 }, "unique": true, "hidden": true, "name": "username_1" },
+/// );
+/// ```
+impl TryFrom<IndexModel> for Index {
+    type Error = mongodb::error::Error;
+
+    fn try_from(model: IndexModel) -> Result<Self, Self::Error> {
+        let mut index = Index::default();
+        for (key, value) in model.keys {
+            match value {
+                Bson::String(ref kind) if kind == "text" => index.add_key_with_text(key),
+                Bson::Int32(1) | Bson::Int64(1) | Bson::Double(1.0) => {
+                    index.add_key_with_direction(key, SortOrder::Ascending)
+                }
+                Bson::Int32(-1) | Bson::Int64(-1) | Bson::Double(-1.0) => {
+                    index.add_key_with_direction(key, SortOrder::Descending)
+                }
+                other => {
+                    return Err(std::io::Error::other(format!(
+                        "unsupported index key type {} for key \"{}\"",
+                        other, key
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        if let Some(options) = model.options {
+            for (name, value) in to_document(&options)? {
+                index.add_option(IndexOption::h_from_name_value(name, value));
+            }
+        }
+
+        Ok(index)
+    }
+}
+
+/// Conversion into the driver's index representation, to be used with `Collection::create_index`.
+///
+/// The conversion is lossy: `IndexOption::Custom` options unknown to `IndexOptions` are dropped.
+/// It fails if the index has no key, or when a custom option known to `IndexOptions` has an unexpected type.
+impl TryFrom<Index> for IndexModel {
+    type Error = mongodb::error::Error;
+
+    fn try_from(index: Index) -> Result<Self, Self::Error> {
+        Ok(from_document(index.try_into_document()?)?)
+    }
+}
+
 /// Collection of indexes. Provides function to build database commands.
 ///
 /// [Mongo manual](https://docs.mongodb.com/manual/indexes/)
@@ -234,6 +636,15 @@ impl From<Vec<Index>> for Indexes {
     }
 }
 
+impl<'a> IntoIterator for &'a Indexes {
+    type Item = &'a Index;
+    type IntoIter = std::slice::Iter<'a, Index>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
 impl Indexes {
     /// New empty index list.
     pub fn new() -> Self {
@@ -246,8 +657,192 @@ impl Indexes {
         self
     }
 
+    /// Number of declared indexes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no index is declared.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the declared indexes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::{Index, IndexOption, Indexes};
+    ///
+    /// let indexes = Indexes::new()
+    ///     .with(Index::new("username").with_option(IndexOption::Unique))
+    ///     .with(Index::new("last_seen"));
+    ///
+    /// assert_eq!(indexes.len(), 2);
+    /// let username = indexes
+    ///     .iter()
+    ///     .find(|index| index.name().as_deref() == Some("username_1"))
+    ///     .unwrap();
+    /// assert!(matches!(username.options(), [IndexOption::Unique]));
+    /// ```
+    pub fn iter(&self) -> std::slice::Iter<'_, Index> {
+        self.0.iter()
+    }
+
+    /// Combine two index lists, dropping duplicates.
+    ///
+    /// Indexes are identified by their keys (and the direction of each key): mongo doesn't allow
+    /// two indexes sharing the same key pattern. When the same key pattern is found more than
+    /// once, the last declared index wins (ie: indexes from `other` take precedence over `self`),
+    /// even if options differ.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::{Index, IndexOption, Indexes, mongo::bson::doc};
+    ///
+    /// let base = Indexes::new()
+    ///     .with(Index::new("username"))
+    ///     .with(Index::new("last_seen"));
+    /// let extras = Indexes::new()
+    ///     .with(Index::new("username").with_option(IndexOption::Unique))
+    ///     .with(Index::new("email"));
+    ///
+    /// assert_eq!(
+    ///     base.merge(extras).create_indexes_command("user"),
+    ///     doc! {
+    ///         "createIndexes": "user",
+    ///         "indexes": [
+    ///             { "key": { "last_seen": 1 }, "name": "last_seen_1" },
+    ///             { "key": { "username": 1 }, "unique": true, "name": "username_1" },
+    ///             { "key": { "email": 1 }, "name": "email_1" },
+    ///         ]
+    ///     }
+    /// );
+    /// ```
+    pub fn merge(self, other: Indexes) -> Self {
+        let mut merged = Vec::with_capacity(self.0.len() + other.0.len());
+        for index in self.0.into_iter().chain(other.0) {
+            let key_spec = index.key_spec();
+            merged.retain(|existing: &Index| existing.key_spec() != key_spec);
+            merged.push(index);
+        }
+        Self(merged)
+    }
+
+    /// Check that every key of these indexes is a field path present in `sample`.
+    ///
+    /// Index keys are plain strings: an index on a removed or renamed field is silently kept, indexing nothing.
+    /// `sample` is a serialized document, so serde renames are accounted for. Paths through arrays match if any
+    /// element contains the rest of the path. `_id` and wildcard keys (`$**`) are always accepted.
+    /// See `check_index_fields` to check the indexes of a `Model`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::{doc, Index, Indexes};
+    ///
+    /// let indexes = Indexes::new()
+    ///     .with(Index::new("email"))
+    ///     .with(Index::new("address.city"));
+    ///
+    /// let sample = doc! { "email": "a@b.c", "address": { "city": "Paris" } };
+    /// assert!(indexes.check_fields(&sample).is_ok());
+    ///
+    /// let sample = doc! { "mail": "a@b.c", "address": { "city": "Paris" } };
+    /// let err = indexes.check_fields(&sample).unwrap_err();
+    /// assert!(err.to_string().contains("\"email\""));
+    /// ```
+    pub fn check_fields(&self, sample: &Document) -> Result<(), mongodb::error::Error> {
+        let mut missing = Vec::new();
+        for index in &self.0 {
+            for key in index.keys().keys() {
+                if key == "_id" || key.ends_with("$**") {
+                    continue;
+                }
+                let path: Vec<&str> = key.split('.').collect();
+                if !h_has_path(sample.get(path[0]), &path[1..]) {
+                    missing.push(format!(
+                        "index {} references \"{}\"",
+                        h_describe_index(index.name().as_deref()),
+                        key
+                    ));
+                }
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "fields missing from the document: {}",
+                missing.join(", ")
+            ))
+            .into())
+        }
+    }
+
+    /// Check that no two indexes share the same key pattern or the same name.
+    ///
+    /// Auto-generated names are derived from keys only, so two indexes differing only by options
+    /// (e.g. one unique and one not) end up with the same name. MongoDB doesn't allow two indexes
+    /// with the same key pattern anyway, and `sync_indexes` identifies indexes by their keys: this
+    /// surfaces such a mistake with a clear error instead of a failing or flip-flopping sync.
+    /// Use `merge` to intentionally override an index with another one sharing its keys.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mongodm::{Index, IndexOption, Indexes};
+    ///
+    /// let indexes = Indexes::new()
+    ///     .with(Index::new("username"))
+    ///     .with(Index::new("username").with_option(IndexOption::Unique));
+    ///
+    /// let err = indexes.check_conflicts().unwrap_err();
+    /// assert!(err.to_string().contains("\"username_1\""));
+    /// ```
+    pub fn check_conflicts(&self) -> Result<(), mongodb::error::Error> {
+        for (i, index) in self.0.iter().enumerate() {
+            let key_spec = index.key_spec();
+            let name = index.name();
+            for other in &self.0[..i] {
+                let other_name = other.name();
+                let msg = if other.key_spec() == key_spec {
+                    format!(
+                        "indexes {} and {} share the same key pattern",
+                        h_describe_index(other_name.as_deref()),
+                        h_describe_index(name.as_deref()),
+                    )
+                } else if name.is_some() && name == other_name {
+                    format!(
+                        "indexes with different keys are both named {}",
+                        h_describe_index(name.as_deref()),
+                    )
+                } else {
+                    continue;
+                };
+                return Err(std::io::Error::other(msg).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `create_indexes_command`, but fails if an index has no key or if indexes conflict (see `check_conflicts`).
+    pub fn try_create_indexes_command(
+        self,
+        collection_name: &str,
+    ) -> Result<Document, mongodb::error::Error> {
+        for index in &self.0 {
+            index.h_check_keys()?;
+        }
+        self.check_conflicts()?;
+        Ok(self.create_indexes_command(collection_name))
+    }
+
     /// Generate `createIndexes` command document to submit to `Database::run_command`.
     ///
+    /// No validation is performed, see `try_create_indexes_command`.
+    ///
     /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/createIndexes/)
     pub fn create_indexes_command(self, collection_name: &str) -> Document {
         let mut indexes = Vec::with_capacity(self.0.len());
@@ -262,12 +857,46 @@ impl Indexes {
     }
 }
 
+fn h_has_path(value: Option<&Bson>, path: &[&str]) -> bool {
+    let (value, (first, rest)) = match (value, path.split_first()) {
+        (None, _) => return false,
+        (Some(_), None) => return true,
+        (Some(value), Some(split)) => (value, split),
+    };
+    match value {
+        Bson::Document(doc) => h_has_path(doc.get(*first), rest),
+        Bson::Array(elements) => {
+            // Either an array index, or a field of the elements
+            let by_index = first
+                .parse::<usize>()
+                .is_ok_and(|i| h_has_path(elements.get(i), rest));
+            by_index
+                || elements
+                    .iter()
+                    .any(|element| h_has_path(Some(element), path))
+        }
+        _ => false,
+    }
+}
+
+fn h_describe_index(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("\"{}\"", name),
+        None => "<unnamed>".to_owned(),
+    }
+}
+
 /// Option to be used at index creation.
 ///
 /// [Mongo manual](https://docs.mongodb.com/manual/reference/method/db.collection.createIndex/#options)
 #[derive(Debug, Clone)]
 pub enum IndexOption {
     /// Enable background builds
+    ///
+    /// Ignored since MongoDB 4.2: all index builds use an optimized process holding exclusive locks only
+    /// at their beginning and end. It's still sent to the server, but not taken into account when comparing
+    /// with existing indexes.
+    #[deprecated(note = "ignored by MongoDB 4.2 and later, where all index builds are optimized")]
     Background,
     /// Creates a unique index
     Unique,
@@ -289,6 +918,7 @@ pub enum IndexOption {
     Custom { name: String, value: Bson },
 }
 
+#[allow(deprecated)]
 impl IndexOption {
     pub fn name(&self) -> &str {
         match self {
@@ -326,6 +956,35 @@ impl IndexOption {
         }
     }
 
+    /// Option named `name`, using a dedicated variant when the value has the expected type.
+    fn h_from_name_value(name: String, value: Bson) -> Self {
+        match (name.as_str(), value) {
+            ("background", Bson::Boolean(true)) => IndexOption::Background,
+            ("unique", Bson::Boolean(true)) => IndexOption::Unique,
+            ("sparse", Bson::Boolean(true)) => IndexOption::Sparse,
+            ("name", Bson::String(name)) => IndexOption::Name(name),
+            ("partialFilterExpression", Bson::Document(doc)) => {
+                IndexOption::PartialFilterExpression(doc)
+            }
+            ("storageEngine", Bson::Document(doc)) => IndexOption::StorageEngine(doc),
+            ("collation", Bson::Document(doc)) => IndexOption::Collation(doc),
+            ("expireAfterSeconds", Bson::Int32(secs)) => IndexOption::ExpireAfterSeconds(secs),
+            ("expireAfterSeconds", Bson::Int64(secs)) if i32::try_from(secs).is_ok() => {
+                IndexOption::ExpireAfterSeconds(secs as i32)
+            }
+            ("weights", Bson::Document(doc))
+                if doc.values().all(|w| matches!(w, Bson::Int32(_))) =>
+            {
+                let weights = doc
+                    .into_iter()
+                    .filter_map(|(field, w)| w.as_i32().map(|w| (field, w)))
+                    .collect();
+                IndexOption::Weights(weights)
+            }
+            (_, value) => IndexOption::Custom { name, value },
+        }
+    }
+
     pub fn into_key_value(self) -> (String, Bson) {
         let name = self.name().to_owned();
         let value = self.into_value();
@@ -333,34 +992,334 @@ impl IndexOption {
     }
 }
 
+/// Typed view of an existing index, as returned by `Repository::list_index_infos`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/command/listIndexes/)
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexInfo {
+    /// Name of the index
+    pub name: String,
+    /// Index key pattern
+    pub key: Document,
+    /// Index version
+    pub v: Option<i32>,
+    /// Whether the index is unique
+    #[serde(default)]
+    pub unique: bool,
+    /// Whether the index is sparse
+    #[serde(default)]
+    pub sparse: bool,
+    /// TTL of the documents, in seconds
+    #[serde(rename = "expireAfterSeconds")]
+    pub expire_after_seconds: Option<i64>,
+    /// Filter expression of a partial index
+    #[serde(rename = "partialFilterExpression")]
+    pub partial_filter_expression: Option<Document>,
+    /// Collation of the index
+    pub collation: Option<Document>,
+    /// Weights of a text index
+    pub weights: Option<Document>,
+    /// All remaining fields returned by the server
+    #[serde(flatten)]
+    pub other: Document,
+}
+
 /// Synchronize backend mongo collection for a given `CollectionConfig`.
 ///
 /// This should be called once per `CollectionConfig` on startup to synchronize indexes.
-/// Indexes found in the backend and not defined in the model are destroyed except for the special index "_id".
+/// Indexes found in the backend and not defined in the model are destroyed except for the special index "_id"
+/// and the index supporting `CollectionConfig::shard_key`, if any.
+///
+/// Existing indexes are listed using the read preference configured on the `Database` (primary if none).
+/// See `sync_indexes_with_selection_criteria` to override it.
+///
+/// Indexes are managed with raw `listIndexes`, `createIndexes` and `dropIndexes` commands. With the
+/// `driver-index-management` feature, the index API of the driver (available since mongodb 2.0) is used
+/// instead, with the same synchronization semantics. In such case existing indexes are always listed on
+/// the primary, and `IndexOption::Custom` options unknown to the driver's `IndexOptions` are not sent
+/// (see the conversion from `Index` to `IndexModel`).
 pub async fn sync_indexes<CollConf: CollectionConfig>(
     db: &Database,
 ) -> Result<(), mongodb::error::Error> {
-    let mut indexes = CollConf::indexes();
+    let selection_criteria = h_selection_criteria(db.selection_criteria());
+    sync_indexes_with_selection_criteria::<CollConf>(db, selection_criteria).await
+}
 
-    match h_run_command(db, doc! { "listIndexes": CollConf::collection_name() }).await {
-        Ok(ret) => {
-            let parsed_ret: ListIndexesRet = from_bson(Bson::Document(ret))
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+/// Same as `sync_indexes`, but listing existing indexes using the given selection criteria.
+///
+/// The selection criteria only applies to the read-only `listIndexes` command.
+/// `createIndexes` and `dropIndexes` are write operations and as such are always sent to the primary:
+/// using a secondary would be rejected by the server.
+pub async fn sync_indexes_with_selection_criteria<CollConf: CollectionConfig>(
+    db: &Database,
+    selection_criteria: SelectionCriteria,
+) -> Result<(), mongodb::error::Error> {
+    h_sync_indexes(
+        db,
+        CollConf::collection_name(),
+        CollConf::indexes(),
+        CollConf::shard_key(),
+        selection_criteria,
+        false,
+    )
+    .await?;
+    Ok(())
+}
 
-            if parsed_ret.cursor.id != 0 {
-                // batch isn't complete
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!(
-                        "couldn't list all indexes from '{}'",
-                        CollConf::collection_name()
-                    ),
-                )
-                .into());
+/// Same as `sync_indexes`, but only returns once created indexes are ready on a majority of the replica set members.
+///
+/// `sync_indexes` returns as soon as the primary acknowledged the index creation: depending on the server
+/// version, secondaries may still be building the new indexes at that time, and queries reading from them
+/// won't use these indexes yet. Here `createIndexes` is sent with a `majority` write concern, so that the call
+/// returns after a majority of members applied the index build.
+///
+/// Standalone servers build indexes synchronously: this is then the same as `sync_indexes`.
+/// Note that this waits indefinitely if a majority of members is not available.
+pub async fn sync_indexes_and_wait<CollConf: CollectionConfig>(
+    db: &Database,
+) -> Result<(), mongodb::error::Error> {
+    let selection_criteria = h_selection_criteria(db.selection_criteria());
+    h_sync_indexes(
+        db,
+        CollConf::collection_name(),
+        CollConf::indexes(),
+        CollConf::shard_key(),
+        selection_criteria,
+        true,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Same as `sync_indexes`, but for a collection named `collection_name` instead of `CollectionConfig::collection_name`.
+///
+/// To be used with repositories created by `Repository::new_with_collection_name`, for instance when the same
+/// `CollectionConfig` is used for multiple per-tenant collections.
+pub async fn sync_indexes_with_collection_name<CollConf: CollectionConfig>(
+    db: &Database,
+    collection_name: &str,
+) -> Result<(), mongodb::error::Error> {
+    let selection_criteria = h_selection_criteria(db.selection_criteria());
+    h_sync_indexes(
+        db,
+        collection_name,
+        CollConf::indexes(),
+        CollConf::shard_key(),
+        selection_criteria,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Shard the collection of a given `CollectionConfig` using `CollectionConfig::shard_key`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/command/shardCollection/)
+///
+/// Sharding is enabled on the database, then the collection is sharded over the declared key, creating
+/// an index supporting the shard key first unless an existing index starts with the shard key.
+/// This index is kept by subsequent `sync_indexes` calls even when not declared in `CollectionConfig::indexes`.
+/// Indexes should be synchronized before calling this function so that a declared index can support the
+/// shard key.
+///
+/// Must be run through a `mongos` router, with a user allowed to run administrative commands.
+/// Does nothing if `shard_key` returns `None` or if the collection is already sharded, even with another key:
+/// changing the shard key requires `reshardCollection`.
+pub async fn sync_sharding<CollConf: CollectionConfig>(
+    db: &Database,
+) -> Result<(), mongodb::error::Error> {
+    let shard_key = match CollConf::shard_key() {
+        Some(shard_key) => shard_key,
+        None => return Ok(()),
+    };
+    let collection_name = CollConf::collection_name();
+    let primary = SelectionCriteria::ReadPreference(ReadPreference::Primary);
+
+    match h_run_command_with_selection_criteria(
+        db,
+        doc! { "collStats": collection_name },
+        primary.clone(),
+    )
+    .await
+    {
+        Ok(stats) if stats.get_bool("sharded").unwrap_or(false) => return Ok(()),
+        Ok(_) => {}
+        Err(e) => match e.kind.as_ref() {
+            mongodb::error::ErrorKind::Command(err) if err.code == 26 => {
+                // Namespace doesn't exists yet, it will be created along with the index.
             }
+            _ => return Err(e),
+        },
+    }
+
+    let existing = match h_list_indexes(db, collection_name, primary).await {
+        Ok(existing) => existing,
+        Err(e) => match e.kind.as_ref() {
+            mongodb::error::ErrorKind::Command(err) if err.code == 26 => Vec::new(),
+            _ => return Err(e),
+        },
+    };
+    let has_supporting_index = existing.iter().any(|index| {
+        index
+            .get_document("key")
+            .map(|key| h_is_key_prefix(&shard_key, key))
+            .unwrap_or(false)
+    });
+    if !has_supporting_index {
+        let name = shard_key
+            .iter()
+            .map(|(field, value)| match value {
+                Bson::String(s) => format!("{}_{}", field, s),
+                other => format!("{}_{}", field, other),
+            })
+            .collect::<Vec<_>>()
+            .join("_");
+        h_run_command(
+            db,
+            doc! {
+                "createIndexes": collection_name,
+                "indexes": [{ "key": &shard_key, "name": name }],
+            },
+        )
+        .await?;
+    }
+
+    let admin = db.client().database("admin");
+    h_run_command(&admin, doc! { "enableSharding": db.name() }).await?;
+    h_run_command(
+        &admin,
+        doc! {
+            "shardCollection": format!("{}.{}", db.name(), collection_name),
+            "key": shard_key,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Outcome of `provision`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProvisionReport {
+    /// Whether the collection was created by this call
+    pub collection_created: bool,
+    /// Names of the created indexes (key pattern for indexes declared without name)
+    pub indexes_created: Vec<String>,
+    /// Names of the dropped indexes, no longer declared or whose options changed
+    pub indexes_dropped: Vec<String>,
+}
 
+/// Create the collection of a given `CollectionConfig` if absent, then synchronize its indexes.
+///
+/// This is what most applications want to run at startup: the collection exists right away with the
+/// options of `CollectionConfig::create_collection_options` (validator, collation...) instead of being
+/// implicitly created with default options on first write, then indexes are synchronized as done by
+/// `sync_indexes`.
+///
+/// An existing collection is left untouched, even if its options differ (see the `collMod` command).
+/// Several instances provisioning concurrently is fine: a collection created by another instance
+/// between the existence check and the creation is not an error.
+///
+/// # Example
+///
+/// ```no_run
+/// use mongodm::prelude::*;
+/// use mongodm::provision;
+///
+/// struct UserCollConf;
+///
+/// impl CollectionConfig for UserCollConf {
+///     fn collection_name() -> &'static str {
+///         "user"
+///     }
+///
+///     fn create_collection_options() -> Option<MongoCreateCollectionOptions> {
+///         Some(
+///             MongoCreateCollectionOptions::builder()
+///                 .validator(doc! { "name": { "$type": "string" } })
+///                 .build(),
+///         )
+///     }
+///
+///     fn indexes() -> Indexes {
+///         Indexes::new().with(Index::new("name").with_option(IndexOption::Unique))
+///     }
+/// }
+///
+/// # async fn demo(db: mongodb::Database) {
+/// let report = provision::<UserCollConf>(&db).await.unwrap();
+/// println!("created indexes: {:?}", report.indexes_created);
+/// # }
+/// ```
+pub async fn provision<CollConf: CollectionConfig>(
+    db: &Database,
+) -> Result<ProvisionReport, mongodb::error::Error> {
+    let collection_name = CollConf::collection_name();
+
+    let existing = db
+        .list_collection_names()
+        .filter(doc! { "name": collection_name })
+        .await?;
+    let mut collection_created = false;
+    if !existing.iter().any(|name| name == collection_name) {
+        let options = h_create_collection_options::<CollConf>();
+        match db
+            .create_collection(collection_name)
+            .with_options(options)
+            .await
+        {
+            Ok(()) => collection_created = true,
+            Err(e) => match e.kind.as_ref() {
+                // NamespaceExists: created concurrently since the check
+                mongodb::error::ErrorKind::Command(err) if err.code == 48 => {}
+                _ => return Err(e),
+            },
+        }
+    }
+
+    let selection_criteria = h_selection_criteria(db.selection_criteria());
+    let sync = h_sync_indexes(
+        db,
+        collection_name,
+        CollConf::indexes(),
+        CollConf::shard_key(),
+        selection_criteria,
+        false,
+    )
+    .await?;
+
+    Ok(ProvisionReport {
+        collection_created,
+        indexes_created: sync.created,
+        indexes_dropped: sync.dropped,
+    })
+}
+
+/// Whether the fields of `prefix` are the first fields of `key`, in the same order and with the same values.
+fn h_is_key_prefix(prefix: &Document, key: &Document) -> bool {
+    prefix.len() <= key.len() && prefix.iter().zip(key.iter()).all(|(a, b)| a == b)
+}
+
+async fn h_sync_indexes(
+    db: &Database,
+    collection_name: &str,
+    mut indexes: Indexes,
+    shard_key: Option<Document>,
+    selection_criteria: SelectionCriteria,
+    wait_for_majority: bool,
+) -> Result<IndexSyncReport, mongodb::error::Error> {
+    let mut report = IndexSyncReport::default();
+
+    // Rejected by the server anyway, but with a confusing error message
+    for index in &indexes.0 {
+        index.h_check_keys()?;
+    }
+    indexes.check_conflicts()?;
+
+    match h_sync_list_indexes(db, collection_name, selection_criteria).await {
+        Ok(existing) => {
             let mut existing_indexes = HashMap::new();
-            for index in parsed_ret.cursor.first_batch {
+            for index in existing {
                 if let Some(key) = index.get("key") {
                     existing_indexes.insert(key.to_string(), index);
                 }
@@ -368,72 +1327,21 @@ pub async fn sync_indexes<CollConf: CollectionConfig>(
 
             let mut already_sync = Vec::new();
             let mut to_drop = Vec::new();
-            for (i, index) in indexes.0.clone().into_iter().enumerate() {
-                let mut text_index_keys = None;
-                let index_doc = if index
-                    .keys
-                    .iter()
-                    .any(|ind| matches!(ind, IndexKey::TextIndex(_)))
-                {
-                    let mut doc = index.into_document();
-
-                    // There can only be 1 text index per collection so when a text index is saved, the keys are automatically changed to this. We keep a copy for the weight comparison.
-                    text_index_keys = doc.get("key").cloned();
-                    doc.insert("key", doc! { "_fts": "text", "_ftsx": 1 });
-                    doc
-                } else {
-                    index.into_document()
-                };
-
-                let key = index_doc.get("key").ok_or_else(|| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "index doc is missing 'key'")
-                })?;
-                if let Some(mut existing_index) = existing_indexes.remove(&key.to_string()) {
-                    // "ns" and "v" in the response should not be used for the comparison
-                    existing_index.remove("ns");
-                    existing_index.remove("v");
-
-                    // We compare the text index here, the keys become weights of 1 after saving in the DB. Custom weights not supported yet.
-                    if let Some(Bson::Document(mut keys_to_set)) = text_index_keys {
-                        if let Some(Bson::Document(existing_weights)) =
-                            existing_index.get("weights")
-                        {
-                            // Changing all text values to the default weight of 1
-                            for keys in keys_to_set.iter_mut() {
-                                match keys.1 {
-                                    Bson::String(t) if t == "text" => {
-                                        *keys.1 = Bson::Int32(1);
-                                    }
-                                    _ => (),
-                                }
-                            }
-
-                            if existing_weights.eq(&keys_to_set) {
-                                already_sync.push(i);
-                            } else {
-                                to_drop.push(
-                                    index_doc
-                                        .get_str("name")
-                                        .map_err(|e| {
-                                            std::io::Error::new(std::io::ErrorKind::Other, e)
-                                        })?
-                                        .to_owned(),
-                                );
-                            }
-                            continue;
-                        }
-                    }
-
-                    if doc_are_eq(&index_doc, &existing_index) {
+            for (i, index) in indexes.0.iter().enumerate() {
+                let (index_doc, _) = index.h_comparison_document();
+                let key = index_doc
+                    .get("key")
+                    .ok_or_else(|| std::io::Error::other("index doc is missing 'key'"))?;
+                if let Some(existing_index) = existing_indexes.remove(&key.to_string()) {
+                    if index.matches_existing(&existing_index) {
                         already_sync.push(i);
                     } else {
                         // An index with the same specification already exists, we need to drop it.
-                        to_drop.push(
-                            index_doc
-                                .get_str("name")
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
-                                .to_owned(),
-                        );
+                        let existing_name = existing_index
+                            .get_str("name")
+                            .map_err(std::io::Error::other)?
+                            .to_owned();
+                        to_drop.push(existing_name);
                     }
                 }
             }
@@ -441,37 +1349,27 @@ pub async fn sync_indexes<CollConf: CollectionConfig>(
             // Drop all remaining existing index expect "_id_" (for the "_id" key)
             // "_id" is special and cannot be deleted.
             // https://api.mongodb.com/wiki/current/Indexes.html#Indexes-The%5CidIndex
+            // The index supporting the shard key can't be deleted either, see `sync_sharding`.
+            let shard_key = shard_key.map(|key| key.to_string());
             for existing_index in existing_indexes.values() {
+                if shard_key.is_some()
+                    && existing_index.get("key").map(ToString::to_string) == shard_key
+                {
+                    continue;
+                }
+
                 let name = existing_index
                     .get_str("name")
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+                    .map_err(std::io::Error::other)?
                     .to_owned();
                 if name != "_id_" {
                     to_drop.push(name);
                 }
             }
 
+            report.dropped.clone_from(&to_drop);
             if !to_drop.is_empty() {
-                // Actually send the drop command
-                // Dropping multiple indexes is available only starting MongoDB 4.2
-                // If this fails, we fallback to a loop dropping all indexes individually
-                // TODO: it would be better to select the method by checking mongo version, but db.version()
-                // is not yet exposed by the driver.
-                if h_run_command(
-                    db,
-                    doc! { "dropIndexes": CollConf::collection_name(), "index": &to_drop },
-                )
-                .await
-                .is_err()
-                {
-                    for index_name in to_drop {
-                        h_run_command(
-                            db,
-                            doc! { "dropIndexes": CollConf::collection_name(), "index": index_name },
-                        )
-                        .await?;
-                    }
-                }
+                h_sync_drop_indexes(db, collection_name, to_drop).await?;
             }
 
             // Ignore index already in sync
@@ -490,34 +1388,233 @@ pub async fn sync_indexes<CollConf: CollectionConfig>(
     }
 
     if !indexes.0.is_empty() {
-        h_run_command(
-            db,
-            indexes.create_indexes_command(CollConf::collection_name()),
-        )
+        report.created = indexes
+            .iter()
+            .map(|index| index.name().unwrap_or_else(|| index.keys().to_string()))
+            .collect();
+        h_sync_create_indexes(db, collection_name, indexes, wait_for_majority).await?;
+    }
+
+    Ok(report)
+}
+
+// Index management backends used by `h_sync_indexes`: raw commands by default, or the driver's native
+// index API with the `driver-index-management` feature. Existing indexes are compared in both cases as
+// documents shaped like the output of `listIndexes`.
+
+#[cfg(not(feature = "driver-index-management"))]
+async fn h_sync_list_indexes(
+    db: &Database,
+    collection_name: &str,
+    selection_criteria: SelectionCriteria,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    h_list_indexes(db, collection_name, selection_criteria).await
+}
+
+#[cfg(feature = "driver-index-management")]
+async fn h_sync_list_indexes(
+    db: &Database,
+    collection_name: &str,
+    _selection_criteria: SelectionCriteria,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    use futures_util::TryStreamExt;
+
+    // The driver always lists indexes on the primary
+    let models: Vec<IndexModel> = db
+        .collection::<Document>(collection_name)
+        .list_indexes()
+        .await?
+        .try_collect()
         .await?;
+    let mut existing = Vec::with_capacity(models.len());
+    for model in models {
+        existing.push(to_document(&model)?);
     }
+    Ok(existing)
+}
 
+#[cfg(not(feature = "driver-index-management"))]
+async fn h_sync_drop_indexes(
+    db: &Database,
+    collection_name: &str,
+    to_drop: Vec<String>,
+) -> Result<(), mongodb::error::Error> {
+    // Actually send the drop command
+    // Dropping multiple indexes is available only starting MongoDB 4.2
+    // If this fails, we fallback to a loop dropping all indexes individually
+    // TODO: it would be better to select the method by checking mongo version, but db.version()
+    // is not yet exposed by the driver.
+    if h_run_command(
+        db,
+        doc! { "dropIndexes": collection_name, "index": &to_drop },
+    )
+    .await
+    .is_err()
+    {
+        for index_name in to_drop {
+            h_run_command(
+                db,
+                doc! { "dropIndexes": collection_name, "index": index_name },
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "driver-index-management")]
+async fn h_sync_drop_indexes(
+    db: &Database,
+    collection_name: &str,
+    to_drop: Vec<String>,
+) -> Result<(), mongodb::error::Error> {
+    let coll = db.collection::<Document>(collection_name);
+    for index_name in to_drop {
+        coll.drop_index(index_name).await?;
+    }
     Ok(())
 }
 
-async fn h_run_command(
+#[cfg(not(feature = "driver-index-management"))]
+async fn h_sync_create_indexes(
+    db: &Database,
+    collection_name: &str,
+    indexes: Indexes,
+    wait_for_majority: bool,
+) -> Result<(), mongodb::error::Error> {
+    let mut command = indexes.create_indexes_command(collection_name);
+    if wait_for_majority {
+        command.insert("writeConcern", doc! { "w": "majority" });
+    }
+    h_run_command(db, command).await?;
+    Ok(())
+}
+
+#[cfg(feature = "driver-index-management")]
+async fn h_sync_create_indexes(
+    db: &Database,
+    collection_name: &str,
+    indexes: Indexes,
+    wait_for_majority: bool,
+) -> Result<(), mongodb::error::Error> {
+    let mut models = Vec::with_capacity(indexes.len());
+    for index in indexes.0 {
+        models.push(IndexModel::try_from(index)?);
+    }
+    let mut options = mongodb::options::CreateIndexOptions::default();
+    if wait_for_majority {
+        options.write_concern = Some(mongodb::options::WriteConcern::majority());
+    }
+    db.collection::<Document>(collection_name)
+        .create_indexes(models)
+        .with_options(options)
+        .await?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct IndexSyncReport {
+    created: Vec<String>,
+    dropped: Vec<String>,
+}
+
+/// Returns all indexes of the given collection, as returned by the `listIndexes` command.
+pub(crate) async fn h_list_indexes(
+    db: &Database,
+    collection_name: &str,
+    selection_criteria: SelectionCriteria,
+) -> Result<Vec<Document>, mongodb::error::Error> {
+    let ret = h_run_command_with_selection_criteria(
+        db,
+        doc! { "listIndexes": collection_name },
+        selection_criteria,
+    )
+    .await?;
+    let parsed_ret: ListIndexesRet =
+        from_bson(Bson::Document(ret)).map_err(std::io::Error::other)?;
+
+    if parsed_ret.cursor.id != 0 {
+        // batch isn't complete
+        return Err(std::io::Error::other(format!(
+            "couldn't list all indexes from '{}'",
+            collection_name
+        ))
+        .into());
+    }
+
+    Ok(parsed_ret.cursor.first_batch)
+}
+
+/// The given selection criteria, or the primary when none is set.
+pub(crate) fn h_selection_criteria(
+    selection_criteria: Option<&SelectionCriteria>,
+) -> SelectionCriteria {
+    selection_criteria
+        .cloned()
+        .unwrap_or(SelectionCriteria::ReadPreference(ReadPreference::Primary))
+}
+
+/// Run a command on the primary, turning command failures into errors.
+pub(crate) async fn h_run_command(
+    db: &Database,
+    command_doc: Document,
+) -> Result<Document, mongodb::error::Error> {
+    h_run_command_with_selection_criteria(
+        db,
+        command_doc,
+        SelectionCriteria::ReadPreference(ReadPreference::Primary),
+    )
+    .await
+}
+
+pub(crate) async fn h_run_command_with_selection_criteria(
     db: &Database,
     command_doc: Document,
+    selection_criteria: SelectionCriteria,
 ) -> Result<Document, mongodb::error::Error> {
-    let primary_options = RunCommandOptions::builder()
-        .selection_criteria(SelectionCriteria::ReadPreference(ReadPreference::Primary))
+    let options = RunCommandOptions::builder()
+        .selection_criteria(selection_criteria)
         .build();
 
-    let ret = db
-        .run_command(command_doc)
-        .with_options(primary_options)
-        .await?;
-    if let Ok(err) = from_bson::<mongodb::error::CommandError>(Bson::Document(ret.clone())) {
+    let ret = db.run_command(command_doc).with_options(options).await?;
+    check_command_reply(ret)
+}
+
+/// Check that every index key declared by the `CollectionConfig` of `M` is a field of the serialized `sample`.
+///
+/// Meant to be run from a unit test, typically through `assert_indexes_valid!`, to catch indexes referencing
+/// removed or renamed fields. `sample` should have every field populated: an `Option` field set to `None` and
+/// skipped on serialization, or an empty array, hides the fields below it. See `Indexes::check_fields`.
+pub fn check_index_fields<M: Model>(sample: &M) -> Result<(), mongodb::error::Error> {
+    let sample = h_to_document(sample)?;
+    M::CollConf::indexes().check_fields(&sample)
+}
+
+/// Turn a command reply describing a failure into a `CommandError`.
+///
+/// `Database::run_command` returns the server reply as is when the command itself reports an
+/// error that the driver didn't catch (`ok: 0` along with `code`, `codeName` and `errmsg`).
+/// This returns such replies as `ErrorKind::Command` errors, and any other reply unchanged.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::{check_command_reply, mongo::bson::doc, mongo::error::ErrorKind};
+///
+/// let reply = doc! { "ok": 0.0, "code": 26, "codeName": "NamespaceNotFound", "errmsg": "ns does not exist" };
+/// let err = check_command_reply(reply).unwrap_err();
+/// assert!(matches!(err.kind.as_ref(), ErrorKind::Command(err) if err.code == 26));
+///
+/// let reply = doc! { "ok": 1.0 };
+/// assert_eq!(check_command_reply(reply.clone()).unwrap(), reply);
+/// ```
+pub fn check_command_reply(reply: Document) -> Result<Document, mongodb::error::Error> {
+    if let Ok(err) = from_bson::<mongodb::error::CommandError>(Bson::Document(reply.clone())) {
         Err(mongodb::error::Error::from(
             mongodb::error::ErrorKind::Command(err),
         ))
     } else {
-        Ok(ret)
+        Ok(reply)
     }
 }
 
@@ -533,6 +1630,21 @@ struct Cursor {
     pub first_batch: Vec<Document>,
 }
 
+/// The server expands the collation of an index with the defaults of its locale (`caseLevel`,
+/// `normalization`, `version`...): only fields specified in the wanted index are kept for the comparison.
+fn h_normalize_collation(index_doc: &Document, existing_index: &mut Document) {
+    if let (Ok(wanted), Ok(existing)) = (
+        index_doc.get_document("collation"),
+        existing_index.get_document("collation"),
+    ) {
+        let normalized: Document = wanted
+            .keys()
+            .filter_map(|key| existing.get(key).map(|value| (key.clone(), value.clone())))
+            .collect();
+        existing_index.insert("collation", normalized);
+    }
+}
+
 fn doc_are_eq(a: &Document, b: &Document) -> bool {
     if a.len() != b.len() {
         return false;
@@ -558,6 +1670,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(deprecated)]
     fn create_indexes_command() {
         let index = Index::new_with_direction("id", SortOrder::Descending)
             .with_key("last_seen")
@@ -588,4 +1701,400 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn without_auto_name() {
+        let indexes = Indexes::new()
+            .with(Index::new("a").without_auto_name())
+            .with(
+                Index::new("b")
+                    .without_auto_name()
+                    .with_option(IndexOption::Name("custom".to_owned())),
+            );
+
+        assert_eq!(
+            indexes.create_indexes_command("my_collection"),
+            doc! {
+                "createIndexes": "my_collection",
+                "indexes": [
+                    { "key": { "a": 1 } },
+                    { "key": { "b": 1 }, "name": "custom" },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn collation_comparison() {
+        let index_doc = Index::new("username")
+            .with_collation(Collation::builder().locale("en").build())
+            .into_document();
+        let mut existing = doc! {
+            "key": { "username": 1 },
+            "name": "username_1",
+            "collation": {
+                "locale": "en",
+                "caseLevel": false,
+                "caseFirst": "off",
+                "strength": 3,
+                "numericOrdering": false,
+                "alternate": "non-ignorable",
+                "maxVariable": "punct",
+                "normalization": false,
+                "backwards": false,
+                "version": "57.1",
+            },
+        };
+
+        h_normalize_collation(&index_doc, &mut existing);
+        assert!(doc_are_eq(&index_doc, &existing));
+
+        let index_doc = Index::new("username")
+            .with_collation(Collation::builder().locale("fr").build())
+            .into_document();
+        h_normalize_collation(&index_doc, &mut existing);
+        assert!(!doc_are_eq(&index_doc, &existing));
+    }
+
+    #[test]
+    fn matches_existing_text_index() {
+        let mut index = Index::default();
+        index.add_key_with_text("title");
+        index.add_key_with_text("body");
+
+        let existing = doc! {
+            "v": 2,
+            "key": { "_fts": "text", "_ftsx": 1 },
+            "name": "title_text_body_text",
+            "ns": "db.coll",
+            "weights": { "title": 1, "body": 1 },
+            "default_language": "english",
+            "language_override": "language",
+            "textIndexVersion": 3,
+        };
+        assert!(index.matches_existing(&existing));
+
+        let mut other_weights = existing.clone();
+        other_weights.insert("weights", doc! { "title": 1 });
+        assert!(!index.matches_existing(&other_weights));
+
+        let not_text = doc! { "v": 2, "key": { "title": 1 }, "name": "title_1", "weights": { "title": 1, "body": 1 } };
+        assert!(!index.matches_existing(&not_text));
+    }
+
+    #[test]
+    fn empty_keys() {
+        let err = Index::default()
+            .try_into_document()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("index has no key"), "{}", err);
+
+        let err = Index::default()
+            .with_option(IndexOption::Name("by_name".into()))
+            .try_into_document()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("index \"by_name\" has no key"), "{}", err);
+    }
+
+    #[test]
+    fn matches_existing_compound_text_index() {
+        let mut index = Index::new("tenant");
+        index.add_key_with_text("body");
+
+        let existing = doc! {
+            "v": 2,
+            "key": { "tenant": 1, "_fts": "text", "_ftsx": 1 },
+            "name": "tenant_1_body_text",
+            "weights": { "body": 1 },
+            "default_language": "english",
+            "language_override": "language",
+            "textIndexVersion": 3,
+        };
+        assert!(index.matches_existing(&existing));
+
+        // Key order matters
+        let mut reordered = existing.clone();
+        reordered.insert("key", doc! { "_fts": "text", "_ftsx": 1, "tenant": 1 });
+        assert!(!index.matches_existing(&reordered));
+
+        let (index_doc, _) = index.h_comparison_document();
+        assert_eq!(
+            index_doc.get_document("key").unwrap().to_string(),
+            doc! { "tenant": 1, "_fts": "text", "_ftsx": 1 }.to_string()
+        );
+    }
+
+    #[test]
+    fn shard_key_prefix() {
+        let shard_key = doc! { "tenant": 1, "user_id": 1 };
+        assert!(h_is_key_prefix(
+            &shard_key,
+            &doc! { "tenant": 1, "user_id": 1, "at": -1 }
+        ));
+        assert!(h_is_key_prefix(&shard_key, &shard_key));
+        assert!(!h_is_key_prefix(
+            &shard_key,
+            &doc! { "user_id": 1, "tenant": 1 }
+        ));
+        assert!(!h_is_key_prefix(&shard_key, &doc! { "tenant": 1 }));
+        assert!(!h_is_key_prefix(
+            &doc! { "tenant": "hashed" },
+            &doc! { "tenant": 1 }
+        ));
+    }
+
+    #[test]
+    fn merge_dedup() {
+        let base = Indexes::new()
+            .with(Index::new("a"))
+            .with(Index::new("a").with_key("b"))
+            .with(Index::new("a"));
+        let other = Indexes::new()
+            .with(Index::new("b").with_key("a"))
+            .with(Index::new("a").with_key("b"));
+
+        let names: Vec<_> = base
+            .merge(other)
+            .0
+            .into_iter()
+            .map(|index| index.into_document().get_str("name").unwrap().to_owned())
+            .collect();
+
+        assert_eq!(names, vec!["a_1", "b_1_a_1", "a_1_b_1"]);
+    }
+
+    #[test]
+    fn merge_conflict_last_wins() {
+        let base = Indexes::new().with(
+            Index::new_with_direction("a", SortOrder::Descending).with_option(IndexOption::Unique),
+        );
+        let other = Indexes::new()
+            .with(
+                Index::new_with_direction("a", SortOrder::Descending)
+                    .with_option(IndexOption::Sparse),
+            )
+            .with(Index::new("a"));
+
+        assert_eq!(
+            base.merge(other).create_indexes_command("my_collection"),
+            doc! {
+                "createIndexes": "my_collection",
+                "indexes": [
+                    { "key": { "a": -1 }, "sparse": true, "name": "a_-1" },
+                    { "key": { "a": 1 }, "name": "a_1" },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn index_info_from_list_indexes() {
+        let info: IndexInfo = mongodb::bson::from_document(doc! {
+            "v": 2,
+            "unique": true,
+            "key": { "last_seen": -1 },
+            "name": "last_seen_-1",
+            "expireAfterSeconds": 60,
+            "ns": "db.coll",
+        })
+        .unwrap();
+
+        assert_eq!(info.name, "last_seen_-1");
+        assert_eq!(info.key, doc! { "last_seen": -1 });
+        assert_eq!(info.v, Some(2));
+        assert!(info.unique);
+        assert!(!info.sparse);
+        assert_eq!(info.expire_after_seconds, Some(60));
+        assert_eq!(info.partial_filter_expression, None);
+        assert_eq!(info.other, doc! { "ns": "db.coll" });
+    }
+
+    #[test]
+    fn colliding_indexes() {
+        let err = Indexes::new()
+            .with(Index::new("username"))
+            .with(Index::new("username").with_option(IndexOption::Unique))
+            .try_create_indexes_command("user")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("indexes \"username_1\" and \"username_1\" share the same key pattern"),
+            "{}",
+            err
+        );
+
+        let err = Indexes::new()
+            .with(Index::new("username"))
+            .with(Index::new("email").with_option(IndexOption::Name("username_1".to_owned())))
+            .check_conflicts()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("indexes with different keys are both named \"username_1\""),
+            "{}",
+            err
+        );
+
+        // Same keys with different directions are distinct indexes
+        Indexes::new()
+            .with(Index::new("username"))
+            .with(Index::new_with_direction("username", SortOrder::Descending))
+            .with(Index::new("email").without_auto_name())
+            .with(Index::new("last_seen").without_auto_name())
+            .check_conflicts()
+            .unwrap();
+    }
+
+    #[test]
+    fn introspection() {
+        let indexes = Indexes::new()
+            .with(Index::new_with_text("bio").with_key("country"))
+            .with(Index::new("email").without_auto_name());
+
+        assert!(!indexes.is_empty());
+        assert_eq!(indexes.len(), 2);
+        assert!(Indexes::new().is_empty());
+
+        let names: Vec<_> = indexes.iter().map(Index::name).collect();
+        assert_eq!(names, vec![Some("bio_text_country_1".to_owned()), None]);
+
+        let text_index = (&indexes).into_iter().next().unwrap();
+        assert_eq!(text_index.keys(), doc! { "bio": "text", "country": 1 });
+        assert!(text_index.options().is_empty());
+
+        // Same name as sent to the server
+        for index in [
+            Index::new("a").with_key_with_direction("b", SortOrder::Descending),
+            Index::new("a").with_option(IndexOption::Name("by_a".to_owned())),
+            Index::new("a")
+                .with_option(IndexOption::Name("first".to_owned()))
+                .with_option(IndexOption::Custom {
+                    name: "name".to_owned(),
+                    value: Bson::from("last"),
+                }),
+            Index::new("a").without_auto_name(),
+        ] {
+            let from_doc = index
+                .clone()
+                .into_document()
+                .get_str("name")
+                .ok()
+                .map(ToOwned::to_owned);
+            assert_eq!(index.name(), from_doc);
+        }
+    }
+
+    #[test]
+    fn check_fields() {
+        let indexes = Indexes::new()
+            .with(Index::new("_id").with_key("lastSeen"))
+            .with(Index::new("tags.name"))
+            .with(Index::new("scores.0"))
+            .with(Index::new("$**"));
+        let sample = doc! {
+            "_id": 1,
+            "lastSeen": 2,
+            "tags": [{ "label": "a" }, { "name": "b" }],
+            "scores": [1, 2],
+        };
+        assert!(indexes.check_fields(&sample).is_ok());
+
+        let err = indexes
+            .with(Index::new("last_seen"))
+            .with(Index::new("tags.label.id"))
+            .check_fields(&sample)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(r#"index "last_seen_1" references "last_seen", index "tags.label.id_1" references "tags.label.id""#),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn matches_existing_background() {
+        let index = Index::new("a")
+            .with_option(IndexOption::Background)
+            .with_option(IndexOption::Unique);
+        // Stored as is by MongoDB prior to 4.2, ignored since then
+        assert!(index
+            .matches_existing(&doc! { "v": 2, "key": { "a": 1 }, "name": "a_1", "unique": true }));
+        assert!(index.matches_existing(
+            &doc! { "v": 2, "key": { "a": 1 }, "name": "a_1", "unique": true, "background": true }
+        ));
+        assert!(Index::new("a").matches_existing(
+            &doc! { "v": 2, "key": { "a": 1 }, "name": "a_1", "background": true }
+        ));
+    }
+
+    #[test]
+    fn matches_existing_unique_if_present() {
+        let index = Index::new_unique_if_present("email", BsonTypeName::String);
+        let existing = doc! {
+            "v": 2,
+            "key": { "email": 1 },
+            "name": "email_1",
+            "unique": true,
+            "partialFilterExpression": { "email": { "$type": "string" } },
+        };
+        assert!(index.matches_existing(&existing));
+
+        let sparse = doc! { "v": 2, "key": { "email": 1 }, "name": "email_1", "unique": true, "sparse": true };
+        assert!(!index.matches_existing(&sparse));
+    }
+
+    #[test]
+    fn index_model_conversion() {
+        let model = IndexModel::builder()
+            .keys(doc! { "bio": "text", "country": -1 })
+            .options(
+                mongodb::options::IndexOptions::builder()
+                    .name("search".to_owned())
+                    .expire_after(std::time::Duration::from_secs(60))
+                    .weights(doc! { "bio": 3 })
+                    .default_language("french".to_owned())
+                    .build(),
+            )
+            .build();
+        let index = Index::try_from(model).unwrap();
+        assert_eq!(index.keys(), doc! { "bio": "text", "country": -1 });
+        assert!(matches!(
+            index.options(),
+            [
+                IndexOption::ExpireAfterSeconds(60),
+                IndexOption::Name(name),
+                IndexOption::Custom { name: language, .. },
+                IndexOption::Weights(weights),
+            ] if name == "search" && language == "default_language" && weights == &[("bio".to_owned(), 3)]
+        ));
+
+        let model = IndexModel::try_from(index.with_option(IndexOption::Custom {
+            name: "unknown".to_owned(),
+            value: true.into(),
+        }))
+        .unwrap();
+        assert_eq!(model.keys, doc! { "bio": "text", "country": -1 });
+        let options = model.options.unwrap();
+        assert_eq!(options.name.as_deref(), Some("search"));
+        assert_eq!(options.default_language.as_deref(), Some("french"));
+        assert_eq!(
+            options.expire_after,
+            Some(std::time::Duration::from_secs(60))
+        );
+
+        let err = Index::try_from(
+            IndexModel::builder()
+                .keys(doc! { "location": "2dsphere" })
+                .build(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("unsupported index key type"), "{}", err);
+
+        assert!(IndexModel::try_from(Index::default()).is_err());
+    }
 }
@@ -7,7 +7,7 @@
 //!
 //! - A stronger API leveraging Rust type system
 //! - Data structure models are defined using the well-known [`serde`](https://github.com/serde-rs/serde) serialization framework
-//! - Index support on top of the `Database::run_command` (index management is currently not implemented in the underlying driver)
+//! - Index support on top of the `Database::run_command`, or of the driver's index API with the `driver-index-management` feature
 //! - Indexes synchronization
 //! - Additional compile-time checks for queries using macros and type associated to mongo operators (eg: `And` instead of "$and")
 //!
@@ -89,16 +89,35 @@
 #[cfg(test)]
 extern crate pretty_assertions;
 
+mod change_stream;
+mod cursor;
+mod helpers;
 mod index;
 mod macros;
+mod memory;
 mod repository;
+mod schema;
 
 pub mod operator;
+pub mod query;
 
-pub use index::{sync_indexes, Index, IndexOption, Indexes, SortOrder};
+pub use change_stream::{ModelChange, ModelChangeStream};
+pub use cursor::{IdModelCursor, ModelCursor, PeekableModelCursor};
+pub use helpers::{bson_now_millis, bson_now_truncated, new_object_id, now};
+pub use index::{
+    check_command_reply, check_index_fields, provision, sync_indexes, sync_indexes_and_wait,
+    sync_indexes_with_collection_name, sync_indexes_with_selection_criteria, sync_sharding, Index,
+    IndexInfo, IndexOption, Indexes, ProvisionReport, SortOrder,
+};
+pub use memory::MemoryRepository;
 pub use repository::{
-    BulkUpdate, BulkUpdateResult, BulkUpdateUpsertResult, CollectionExt, Repository,
+    is_transient_read_error, retryable_writes_enabled, AggregateExplainSummary, BulkUpdate,
+    BulkUpdateResult, BulkUpdateUpsertResult, CollectionExt, CollectionStats, ConfirmDeleteAll,
+    CopyErrorPolicy, ExplainSummary, KeysetCursor, KeysetPage, Page, PartialInsertResult,
+    Repository, RepositoryBackend, RetryPolicy, ValidationReport, EXPIRE_AT_FIELD,
+    TRANSACTION_MAX_RETRY_TIME,
 };
+pub use schema::BsonSchema;
 
 // Re-export mongodb
 pub use mongodb as mongo;
@@ -112,6 +131,18 @@ pub trait Model: serde::ser::Serialize + serde::de::DeserializeOwned + Unpin + S
     type CollConf: CollectionConfig;
 }
 
+/// Give access to the `_id` of a `Model`.
+///
+/// Required by repository operations working with whole documents keyed by their ids
+/// such as `Repository::save_many` and `Repository::insert_one_and_get`.
+pub trait WithId {
+    /// Returns the `_id` of this document, or `None` if it wasn't assigned yet.
+    fn id(&self) -> Option<mongodb::bson::oid::ObjectId>;
+
+    /// Assign the `_id` generated on insertion.
+    fn set_id(&mut self, id: mongodb::bson::oid::ObjectId);
+}
+
 /// Define collection name, configuration and associated indexes.
 pub trait CollectionConfig {
     /// Collection name to use when creating a `mongodb::Collection` instance.
@@ -125,12 +156,74 @@ pub trait CollectionConfig {
         None
     }
 
+    /// `mongodb::options::CreateCollectionOptions` used when explicitly creating the collection with
+    /// `provision` or `Repository::create_collection`, such as a validator or a default collation.
+    ///
+    /// Collections implicitly created by a first write ignore these options.
+    ///
+    /// This method has a default implementation returning `None` (server defaults).
+    fn create_collection_options() -> Option<mongodb::options::CreateCollectionOptions> {
+        None
+    }
+
+    /// Sort applied by MongODM find helpers (such as `Repository::find_with_defaults`) when none is provided.
+    ///
+    /// This ensures a consistent ordering across queries, which is required for stable pagination.
+    /// Fields used here should ideally be covered by an index declared in `indexes`.
+    ///
+    /// This method has a default implementation returning `None` (natural order).
+    fn default_sort() -> Option<mongodb::bson::Document> {
+        None
+    }
+
+    /// Projection applied by MongODM find helpers (such as `Repository::find_with_defaults`) when none is provided.
+    ///
+    /// Useful to avoid fetching heavy fields (large arrays, embedded blobs...) rarely needed in list views,
+    /// e.g. `doc! { "attachments": 0 }`.
+    /// Excluded fields will be missing from the returned documents: they must be `Option` or `#[serde(default)]`
+    /// in the model, otherwise deserialization fails.
+    ///
+    /// This method has a default implementation returning `None` (all fields are returned).
+    fn default_projection() -> Option<mongodb::bson::Document> {
+        None
+    }
+
     /// Configure how indexes should be created and synchronized for the associated collection.
     ///
     /// This method has a default implementation returning no index (only special `_id` index will be present).
     fn indexes() -> Indexes {
         Indexes::default()
     }
+
+    /// Shard key of the associated collection, used by `sync_sharding`.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/core/sharding-shard-key/)
+    ///
+    /// Either ranged (eg: `doc! { "tenant": 1, "user_id": 1 }`) or hashed (eg: `doc! { "user_id": "hashed" }`).
+    ///
+    /// This method has a default implementation returning `None` (collection is not sharded).
+    fn shard_key() -> Option<mongodb::bson::Document> {
+        None
+    }
+
+    /// Queryable encryption schema of the associated collection, used by `Repository::create_collection`.
+    ///
+    /// [Mongo manual](https://www.mongodb.com/docs/manual/core/queryable-encryption/fundamentals/encrypt-and-query/)
+    ///
+    /// Encryption itself is performed by the driver and requires a client configured with auto-encryption
+    /// (`Client::encrypted_builder`): a key vault collection holding the data encryption keys referenced
+    /// by `keyId`, and the KMS providers protecting these keys must be set up beforehand. The same schema
+    /// should be registered in the `encrypted_fields_map` of the client, keyed by `"<db>.<collection>"`,
+    /// so that queries are encrypted even before the collection is created.
+    ///
+    /// Only available with the `in-use-encryption` cargo feature, which enables the driver's encryption
+    /// support and requires `libmongocrypt`.
+    ///
+    /// This method has a default implementation returning `None` (no encrypted field).
+    #[cfg(feature = "in-use-encryption")]
+    fn encrypted_fields() -> Option<mongodb::bson::Document> {
+        None
+    }
 }
 
 /// Utilities methods to get a `Repository`. Implemented for `mongodb::Database`.
@@ -225,9 +318,14 @@ pub mod prelude {
     };
     #[doc(no_inline)]
     pub use crate::{
-        f, field, operator::*, pipeline, sync_indexes, BulkUpdate, BulkUpdateResult,
-        BulkUpdateUpsertResult, CollectionConfig, CollectionExt as _, Index, IndexOption, Indexes,
-        Model, Repository, SortOrder, ToRepository as _,
+        add_fields, bson_now_millis, bson_now_truncated, f, field, filter, index, new_object_id,
+        now, operator::*, pipeline, project_exclude_id, query::*, sync_indexes,
+        sync_indexes_and_wait, sync_indexes_with_collection_name,
+        sync_indexes_with_selection_criteria, BsonSchema, BulkUpdate, BulkUpdateResult,
+        BulkUpdateUpsertResult, CollectionConfig, CollectionExt as _, Index, IndexInfo,
+        IndexOption, Indexes, KeysetCursor, KeysetPage, MemoryRepository, Model, ModelChange,
+        ModelChangeStream, ModelCursor, Page, Repository, RepositoryBackend, SortOrder,
+        ToRepository as _, WithId,
     };
     #[doc(no_inline)]
     pub use futures_util::future::{BoxFuture, FutureExt};
@@ -2,6 +2,9 @@
 ///
 /// Note that it sadly won't work with `#[serde(rename = "...")]` and `#[serde(rename_all = "...")]`.
 ///
+/// Serde attributes are invisible to the check: a path to a `#[serde(skip)]` (or `skip_serializing`) field
+/// compiles fine, but matches nothing since the field is never stored in the document.
+///
 /// # Example
 ///
 /// ```
@@ -82,6 +85,56 @@
 /// );
 /// ```
 ///
+/// Flattened structs
+///
+/// Fields of a `#[serde(flatten)]` struct are serialized at the level of the containing struct.
+/// Mark the flattened field with `flatten` so that it is checked but omitted from the path.
+/// As with renaming, the presence of the attribute itself can't be checked.
+/// ```
+/// use mongodm::mongo::bson::doc;
+/// use mongodm::field;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     #[serde(flatten)]
+///     meta: Meta,
+///     address: Address,
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Meta {
+///     created_by: String,
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Address {
+///     #[serde(flatten)]
+///     location: Location,
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Location {
+///     city: String,
+/// }
+///
+/// assert_eq!(
+///     doc! { field!((flatten meta in User).(created_by in Meta)): "admin" },
+///     doc! { "created_by": "admin" },
+/// );
+///
+/// assert_eq!(
+///     doc! { field!((address in User).(flatten location in Address).(city in Location)): "Paris" },
+///     doc! { "address.city": "Paris" },
+/// );
+///
+/// assert_eq!(
+///     doc! { field!(@(flatten meta in User).(created_by in Meta)): 1 },
+///     doc! { "$created_by": 1 },
+/// );
+/// ```
+///
 /// If the field doesn't exist, compilation will fail.
 ///
 /// ```compile_fail
@@ -166,6 +219,15 @@ macro_rules! field {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! field_string_helper {
+    ( ( flatten $field:ident in $type:path ) $( . $rest:tt )+ ) => {
+        $crate::field_string_helper!($($rest).+)
+    };
+    ( @ ( flatten $field:ident in $type:path ) $( . $rest:tt )+ ) => {
+        concat!( "$", $crate::field_string_helper!($($rest).+) )
+    };
+    ( @ @ ( flatten $field:ident in $type:path ) $( . $rest:tt )+ ) => {
+        concat!( "$$", $crate::field_string_helper!($($rest).+) )
+    };
     ( $field:ident in $type:path ) => {
         stringify!($field)
     };
@@ -192,6 +254,19 @@ macro_rules! field_string_helper {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! field_check_helper {
+    // A flattened field is checked like any other field, it's only omitted from the path
+    ( ( flatten $field:ident in $type:path ) . $($rest:tt)+ ) => {
+        $crate::field_check_helper!(( $field in $type ) . $($rest)+ )
+    };
+    ( @ ( flatten $field:ident in $type:path ) . $($rest:tt)+ ) => {
+        $crate::field_check_helper!(( $field in $type ) . $($rest)+ )
+    };
+    ( @ @ ( flatten $field:ident in $type:path ) . $($rest:tt)+ ) => {
+        $crate::field_check_helper!(( $field in $type ) . $($rest)+ )
+    };
+    ( ( $field:ident in $type:path ) . ( flatten $field2:ident in $type2:path ) . $($rest:tt)+ ) => {
+        $crate::field_check_helper!(( $field in $type ) . ( $field2 in $type2 ) . $($rest)+ )
+    };
     ( $field:ident in $type:path ) => {
         #[allow(unknown_lints, unneeded_field_pattern)]
         const _: fn() = || {
@@ -291,6 +366,156 @@ macro_rules! f {
     }};
 }
 
+/// Build a `ModelFilter` on a model, statically checking that each key is a field of the model.
+///
+/// Keys are field names of the model and values are anything accepted as a `doc!` value.
+/// Values that are not a single token tree (eg: `-1`) must be wrapped in parentheses.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+/// # use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     age: i32,
+/// }
+///
+/// let filter = filter!(User { name: "David", age: { GreaterThan: 30 } });
+///
+/// assert_eq!(
+///     filter.into_document(),
+///     doc! { "name": "David", "age": { "$gt": 30 } },
+/// );
+/// ```
+///
+/// If the field doesn't exist, compilation will fail.
+///
+/// ```compile_fail
+/// # use mongodm::prelude::*;
+/// struct User {
+///     name: String,
+/// }
+///
+/// // Doesn't compile because `age` isn't a member of `User`
+/// let filter = filter!(User { age: 30 });
+/// ```
+///
+/// As will using the filter with a repository of another model.
+///
+/// ```compile_fail
+/// # use mongodm::prelude::*;
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct User {
+/// #     name: String,
+/// # }
+/// # impl Model for User {
+/// #     type CollConf = UserCollConf;
+/// # }
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf {
+/// #     fn collection_name() -> &'static str { "user" }
+/// # }
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Session {
+/// #     name: String,
+/// # }
+/// # impl Model for Session {
+/// #     type CollConf = SessionCollConf;
+/// # }
+/// # struct SessionCollConf;
+/// # impl CollectionConfig for SessionCollConf {
+/// #     fn collection_name() -> &'static str { "session" }
+/// # }
+/// # async fn demo(db: mongodb::Database) {
+/// // Doesn't compile because the filter is on `User` and the repository on `Session`
+/// db.repository::<Session>()
+///     .find_one_filtered(filter!(User { name: "David" }))
+///     .await;
+/// # }
+/// ```
+#[macro_export]
+macro_rules! filter {
+    ( $model:path { $( $field:ident : $value:tt ),* $(,)? } ) => {{
+        $( $crate::field_check_helper!($field in $model); )*
+        $crate::query::ModelFilter::<$model>::new(
+            $crate::mongo::bson::doc! { $( stringify!($field): $value ),* }
+        )
+    }};
+}
+
+/// Build an `Index` on fields of a model, statically checking that each key is a field of the model.
+///
+/// Each key is a field name, optionally followed by its order: `Ascending` (the default), `Descending`
+/// or `Text` for a text index key. Options can then be added to the returned `Index` as usual.
+/// As with `field!`, this won't work with `#[serde(rename = "...")]`; indexes on fields of embedded documents
+/// are built with `Index::new(field!(...))`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     username: String,
+///     last_seen: i64,
+///     bio: String,
+/// }
+///
+/// assert_eq!(
+///     index!(User { username, last_seen: Descending })
+///         .with_option(IndexOption::Unique)
+///         .into_document(),
+///     Index::new("username")
+///         .with_key_with_direction("last_seen", SortOrder::Descending)
+///         .with_option(IndexOption::Unique)
+///         .into_document(),
+/// );
+///
+/// assert_eq!(
+///     index!(User { bio: Text }).into_document(),
+///     doc! { "key": { "bio": "text" }, "name": "bio_text" },
+/// );
+/// ```
+///
+/// If the field doesn't exist, compilation will fail.
+///
+/// ```compile_fail
+/// # use mongodm::index;
+/// struct User {
+///     username: String,
+/// }
+///
+/// // Doesn't compile because `usrname` isn't a member of `User`
+/// let index = index!(User { usrname });
+/// ```
+#[macro_export]
+macro_rules! index {
+    ( $model:path { $( $field:ident $( : $order:ident )? ),+ $(,)? } ) => {{
+        $( $crate::field_check_helper!($field in $model); )+
+        let mut index = $crate::Index::default();
+        $( $crate::index_key_helper!(index, $field $( , $order )?); )+
+        index
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! index_key_helper {
+    ( $index:ident, $field:ident ) => {
+        $index.add_key_with_direction(stringify!($field), $crate::SortOrder::Ascending)
+    };
+    ( $index:ident, $field:ident, Text ) => {
+        $index.add_key_with_text(stringify!($field))
+    };
+    ( $index:ident, $field:ident, $order:ident ) => {
+        $index.add_key_with_direction(stringify!($field), $crate::SortOrder::$order)
+    };
+}
+
 /// Helper to build aggregation pipelines.
 /// Return a Vec<Document> as expected by the aggregate function.
 ///
@@ -345,6 +570,66 @@ macro_rules! f {
 /// assert_eq!(a, b);
 /// assert_eq!(a, c);
 /// ```
+///
+/// # Running a pipeline
+///
+/// The generated `Vec<Document>` is accepted as is by `Repository::aggregate_as` (results deserialized
+/// into any type), `Repository::aggregate_models` (results of the shape of the model) and
+/// `Repository::aggregate_one` (first result only), each taking optional `AggregateOptions`.
+///
+/// ```no_run
+/// use futures_util::TryStreamExt;
+/// use mongodm::prelude::*;
+/// use serde::{Deserialize, Serialize};
+///
+/// struct OrderCollConf;
+///
+/// impl CollectionConfig for OrderCollConf {
+///     fn collection_name() -> &'static str {
+///         "order"
+///     }
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Order {
+///     customer: String,
+///     amount: i64,
+/// }
+///
+/// impl Model for Order {
+///     type CollConf = OrderCollConf;
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct CustomerTotal {
+///     #[serde(rename = "_id")]
+///     customer: String,
+///     total: i64,
+/// }
+///
+/// # async fn demo() -> Result<(), MongoError> {
+/// let client = MongoClient::with_uri_str("mongodb://localhost:27017").await?;
+/// let repository = client.database("shop").repository::<Order>();
+///
+/// let best_customer: Option<CustomerTotal> = repository
+///     .aggregate_one(
+///         pipeline! [
+///             Group: { "_id": f!(@customer in Order), "total": { Sum: f!(@amount in Order) } },
+///             Sort: { "total": -1 },
+///             Limit: 1,
+///         ],
+///         MongoAggregateOptions::builder().allow_disk_use(true).build(),
+///     )
+///     .await?;
+///
+/// let large_orders: Vec<Order> = repository
+///     .aggregate_models(pipeline! [ Match: { f!(amount in Order): { GreaterThan: 1000 } } ], None)
+///     .await?
+///     .try_collect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
 #[macro_export]
 macro_rules! pipeline {
     ($($tt:tt)*)=> {{
@@ -389,3 +674,296 @@ macro_rules! pipeline_helper {
         $crate::pipeline_helper!($vec $($rest)*);
     }};
 }
+
+/// Build an `$addFields` stage computing new fields from expressions referencing fields of a model.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/aggregation/addFields/)
+///
+/// Each new field maps to an aggregation expression written with the `doc!` syntax, where `@field`
+/// is a reference to a field of the model (`"$field"`), statically checked as with `field!`.
+/// Names of the new fields are not checked: they are typically absent from the model.
+/// Other values are regular expressions, such as `f!(@(address in User).(city in Address))`
+/// for references to fields of embedded documents.
+///
+/// `$set` is an alias of `$addFields`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     first: String,
+///     last: String,
+///     scores: Vec<i32>,
+/// }
+///
+/// let stage = add_fields!(User {
+///     full_name: { Concat: [@first, " ", @last] },
+///     best_score: { Max: @scores },
+///     adult: true,
+/// });
+///
+/// assert_eq!(
+///     stage,
+///     doc! {
+///         "$addFields": {
+///             "full_name": { "$concat": ["$first", " ", "$last"] },
+///             "best_score": { "$max": "$scores" },
+///             "adult": true,
+///         }
+///     }
+/// );
+///
+/// // To be used in a pipeline
+/// let pipeline = pipeline![
+///     stage,
+///     Sort: { "full_name": 1 },
+/// ];
+/// ```
+///
+/// If a referenced field doesn't exist, compilation will fail.
+///
+/// ```compile_fail
+/// # use mongodm::prelude::*;
+/// struct User {
+///     first: String,
+/// }
+///
+/// // Doesn't compile because `last` isn't a member of `User`
+/// let stage = add_fields!(User { full_name: { Concat: [@first, " ", @last] } });
+/// ```
+#[macro_export]
+macro_rules! add_fields {
+    ( $model:path { $( $rest:tt )* } ) => {{
+        let mut fields = $crate::mongo::bson::Document::new();
+        $crate::add_fields_helper!(@fields $model; fields; $( $rest )*);
+        $crate::mongo::bson::doc! { $crate::operator::AddFields: fields }
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! add_fields_helper {
+    // New fields, whose names are not checked
+    ( @fields $model:path; $doc:ident; ) => {};
+    ( @fields $model:path; $doc:ident; $new:ident : @ $field:ident $( , $( $rest:tt )* )? ) => {
+        $doc.insert(stringify!($new), $crate::add_fields_helper!(@ref $model; $field));
+        $crate::add_fields_helper!(@fields $model; $doc; $( $( $rest )* )?);
+    };
+    ( @fields $model:path; $doc:ident; $new:ident : $value:tt $( , $( $rest:tt )* )? ) => {
+        $doc.insert(stringify!($new), $crate::add_fields_helper!(@value $model; $value));
+        $crate::add_fields_helper!(@fields $model; $doc; $( $( $rest )* )?);
+    };
+    ( @fields $model:path; $doc:ident; $new:ident : $value:expr $( , $( $rest:tt )* )? ) => {
+        $doc.insert(stringify!($new), $crate::mongo::bson::Bson::from($value));
+        $crate::add_fields_helper!(@fields $model; $doc; $( $( $rest )* )?);
+    };
+
+    // Entries of an expression document, keyed by operators or strings
+    ( @entries $model:path; $doc:ident; ) => {};
+    ( @entries $model:path; $doc:ident; $key:tt : @ $field:ident $( , $( $rest:tt )* )? ) => {
+        $doc.insert($key, $crate::add_fields_helper!(@ref $model; $field));
+        $crate::add_fields_helper!(@entries $model; $doc; $( $( $rest )* )?);
+    };
+    ( @entries $model:path; $doc:ident; $key:tt : $value:tt $( , $( $rest:tt )* )? ) => {
+        $doc.insert($key, $crate::add_fields_helper!(@value $model; $value));
+        $crate::add_fields_helper!(@entries $model; $doc; $( $( $rest )* )?);
+    };
+    ( @entries $model:path; $doc:ident; $key:tt : $value:expr $( , $( $rest:tt )* )? ) => {
+        $doc.insert($key, $crate::mongo::bson::Bson::from($value));
+        $crate::add_fields_helper!(@entries $model; $doc; $( $( $rest )* )?);
+    };
+
+    // Elements of an expression array
+    ( @elements $model:path; $vec:ident; ) => {};
+    ( @elements $model:path; $vec:ident; @ $field:ident $( , $( $rest:tt )* )? ) => {
+        $vec.push($crate::add_fields_helper!(@ref $model; $field));
+        $crate::add_fields_helper!(@elements $model; $vec; $( $( $rest )* )?);
+    };
+    ( @elements $model:path; $vec:ident; $value:tt $( , $( $rest:tt )* )? ) => {
+        $vec.push($crate::add_fields_helper!(@value $model; $value));
+        $crate::add_fields_helper!(@elements $model; $vec; $( $( $rest )* )?);
+    };
+    ( @elements $model:path; $vec:ident; $value:expr $( , $( $rest:tt )* )? ) => {
+        $vec.push($crate::mongo::bson::Bson::from($value));
+        $crate::add_fields_helper!(@elements $model; $vec; $( $( $rest )* )?);
+    };
+
+    ( @ref $model:path; $field:ident ) => {
+        $crate::mongo::bson::Bson::String($crate::field!(@ $field in $model).to_owned())
+    };
+    ( @value $model:path; { $( $inner:tt )* } ) => {{
+        #[allow(unused_mut)]
+        let mut doc = $crate::mongo::bson::Document::new();
+        $crate::add_fields_helper!(@entries $model; doc; $( $inner )*);
+        $crate::mongo::bson::Bson::Document(doc)
+    }};
+    ( @value $model:path; [ $( $inner:tt )* ] ) => {{
+        #[allow(unused_mut)]
+        let mut vec = ::std::vec::Vec::<$crate::mongo::bson::Bson>::new();
+        $crate::add_fields_helper!(@elements $model; vec; $( $inner )*);
+        $crate::mongo::bson::Bson::Array(vec)
+    }};
+    ( @value $model:path; $value:expr ) => {
+        $crate::mongo::bson::Bson::from($value)
+    };
+}
+
+/// Build a projection document including the given fields of a model and excluding `_id`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/method/db.collection.find/#projection)
+///
+/// `_id` is returned by the server unless explicitly excluded: this is harmless for structs ignoring
+/// unknown fields (serde's default), but fails deserialization of projection structs declared with
+/// `#[serde(deny_unknown_fields)]`. Fields are statically checked as with `field!`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     name: String,
+///     age: i32,
+///     bio: String,
+/// }
+///
+/// assert_eq!(
+///     project_exclude_id!(User { name, age }),
+///     doc! { "_id": 0, "name": 1, "age": 1 },
+/// );
+/// ```
+///
+/// To be used with `Repository::find_as`:
+///
+/// ```no_run
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct User {
+/// #     name: String,
+/// #     bio: String,
+/// # }
+/// # impl Model for User {
+/// #     type CollConf = UserCollConf;
+/// # }
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf {
+/// #     fn collection_name() -> &'static str { "user" }
+/// # }
+/// use mongodm::prelude::*;
+///
+/// #[derive(Deserialize)]
+/// #[serde(deny_unknown_fields)]
+/// struct UserName {
+///     name: String,
+/// }
+///
+/// # async fn demo(db: mongodb::Database) {
+/// let names = db
+///     .repository::<User>()
+///     .find_as::<UserName>(doc! {}, project_exclude_id!(User { name }), None)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+///
+/// If a field doesn't exist, compilation will fail.
+///
+/// ```compile_fail
+/// # use mongodm::prelude::*;
+/// struct User {
+///     name: String,
+/// }
+///
+/// // Doesn't compile because `age` isn't a member of `User`
+/// let projection = project_exclude_id!(User { name, age });
+/// ```
+#[macro_export]
+macro_rules! project_exclude_id {
+    ( $model:path { $( $field:ident ),* $(,)? } ) => {{
+        #[allow(unused_mut)]
+        let mut projection = $crate::mongo::bson::doc! { "_id": 0 };
+        $( projection.insert($crate::field!($field in $model), 1); )*
+        projection
+    }};
+}
+
+/// Assert that the indexes declared by the `CollectionConfig` of a model only reference fields
+/// present in the serialized `sample`, panicking with the offending keys otherwise.
+///
+/// This is a runtime check, to be wired into a unit test with a fully populated sample: see
+/// `check_index_fields`. Unlike `field!`, it goes through serialization, so serde renames are honored.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+/// use mongodm::assert_indexes_valid;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// #[serde(rename_all = "camelCase")]
+/// struct User {
+///     user_name: String,
+///     last_seen: i64,
+/// }
+///
+/// impl Model for User {
+///     type CollConf = UserCollConf;
+/// }
+///
+/// struct UserCollConf;
+///
+/// impl CollectionConfig for UserCollConf {
+///     fn collection_name() -> &'static str {
+///         "user"
+///     }
+///
+///     fn indexes() -> Indexes {
+///         Indexes::new()
+///             .with(Index::new("userName").with_option(IndexOption::Unique))
+///             .with(Index::new("lastSeen"))
+///     }
+/// }
+///
+/// // In a `#[test]` function
+/// assert_indexes_valid!(User { user_name: String::from("David"), last_seen: 0 });
+/// ```
+///
+/// An index on a field that was renamed fails the assertion.
+///
+/// ```should_panic
+/// # use mongodm::prelude::*;
+/// # use mongodm::assert_indexes_valid;
+/// # use serde::{Serialize, Deserialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     user_name: String,
+/// }
+/// # impl Model for User {
+/// #     type CollConf = UserCollConf;
+/// # }
+/// # struct UserCollConf;
+///
+/// impl CollectionConfig for UserCollConf {
+/// #    fn collection_name() -> &'static str {
+/// #        "user"
+/// #    }
+///     fn indexes() -> Indexes {
+///         // Previously `name`
+///         Indexes::new().with(Index::new("name"))
+///     }
+/// }
+///
+/// // Panics: index "name_1" references "name"
+/// assert_indexes_valid!(User { user_name: String::from("David") });
+/// ```
+#[macro_export]
+macro_rules! assert_indexes_valid {
+    ( $sample:expr $(,)? ) => {
+        if let Err(e) = $crate::check_index_fields(&$sample) {
+            panic!("invalid indexes: {}", e);
+        }
+    };
+}
@@ -0,0 +1,590 @@
+//! In-memory implementation of `RepositoryBackend` meant for unit tests.
+
+use crate::cursor::h_from_document;
+use crate::repository::{h_get_path, h_to_document, h_validate_update};
+use crate::{CollectionConfig, Model, RepositoryBackend};
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, Document};
+use mongodb::error::Result;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+/// In-memory stand-in for a `Repository`, storing documents in a `Vec`.
+///
+/// This type can safely be copied and passed around because `std::sync::Arc` is used internally.
+/// All clones share the same storage.
+///
+/// Only a subset of the mongo query language is understood:
+///
+/// - equality on (possibly dotted) field paths, including matching an element of an array
+/// - `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin` and `$exists` field operators
+/// - `$and`, `$or` and `$nor` logical operators
+/// - `$set`, `$unset` and `$inc` update operators
+/// - ascending and descending sort from `CollectionConfig::default_sort`
+/// - exclusion and top-level inclusion projections from `CollectionConfig::default_projection`
+///
+/// Any other operator is reported as an error instead of being silently ignored.
+/// Ordering comparisons are only defined between numbers, strings, dates, object ids and booleans.
+///
+/// # Example
+///
+/// ```
+/// # use serde::{Serialize, Deserialize};
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf {
+/// #     fn collection_name() -> &'static str { "user" }
+/// # }
+/// use mongodm::prelude::*;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     age: i32,
+/// }
+///
+/// impl Model for User {
+///     type CollConf = UserCollConf;
+/// }
+///
+/// // Business logic written against `RepositoryBackend` rather than `Repository`
+/// async fn count_adults(repository: &impl RepositoryBackend<User>) -> Result<u64, MongoError> {
+///     repository.count(doc! { f!(age in User): { GreaterThanEqual: 18 } }).await
+/// }
+///
+/// # async fn demo() {
+/// let repository = MemoryRepository::<User>::new();
+/// repository.insert_model(&User { name: String::from("David"), age: 35 }).await.unwrap();
+/// repository.insert_model(&User { name: String::from("Stacey"), age: 12 }).await.unwrap();
+///
+/// assert_eq!(count_adults(&repository).await.unwrap(), 1);
+/// # }
+/// # let rt = tokio::runtime::Runtime::new().unwrap();
+/// # rt.block_on(demo());
+/// ```
+#[derive(Debug)]
+pub struct MemoryRepository<M: Model> {
+    docs: Arc<Mutex<Vec<Document>>>,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M: Model> Clone for MemoryRepository<M> {
+    fn clone(&self) -> Self {
+        Self {
+            docs: Arc::clone(&self.docs),
+            _model: PhantomData,
+        }
+    }
+}
+
+impl<M: Model> Default for MemoryRepository<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Model> MemoryRepository<M> {
+    /// Create a new empty in-memory repository.
+    pub fn new() -> Self {
+        Self {
+            docs: Arc::new(Mutex::new(Vec::new())),
+            _model: PhantomData,
+        }
+    }
+
+    /// Returns a copy of all stored documents.
+    pub fn documents(&self) -> Vec<Document> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Document>> {
+        // Storage is always left in a consistent state, a poisoned lock can be recovered.
+        self.docs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn filtered(&self, filter: &Document) -> Result<Vec<Document>> {
+        let mut ret = Vec::new();
+        for doc in self.lock().iter() {
+            if matches_filter(doc, filter)? {
+                ret.push(doc.clone());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[async_trait]
+impl<M: Model> RepositoryBackend<M> for MemoryRepository<M> {
+    async fn insert_model(&self, model: &M) -> Result<Bson> {
+        let mut doc = h_to_document(model)?;
+        let id = match doc.get("_id") {
+            Some(id) => id.clone(),
+            None => {
+                let id = Bson::ObjectId(ObjectId::new());
+                let mut with_id = Document::new();
+                with_id.insert("_id", id.clone());
+                with_id.extend(doc);
+                doc = with_id;
+                id
+            }
+        };
+
+        let mut docs = self.lock();
+        if docs.iter().any(|d| d.get("_id") == Some(&id)) {
+            return Err(std::io::Error::other(format!("duplicate key: _id {}", id)).into());
+        }
+        docs.push(doc);
+
+        Ok(id)
+    }
+
+    async fn find_one_model(&self, filter: Document) -> Result<Option<M>> {
+        match self.filtered(&filter)?.into_iter().next() {
+            Some(doc) => Ok(Some(h_from_document(doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_models(&self, filter: Document) -> Result<Vec<M>> {
+        let mut docs = self.filtered(&filter)?;
+        if let Some(sort) = M::CollConf::default_sort() {
+            sort_documents(&mut docs, &sort);
+        }
+        let projection = M::CollConf::default_projection();
+
+        let mut ret = Vec::new();
+        for doc in docs {
+            let doc = match &projection {
+                Some(projection) => project_document(doc, projection)?,
+                None => doc,
+            };
+            ret.push(h_from_document(doc)?);
+        }
+        Ok(ret)
+    }
+
+    async fn count(&self, filter: Document) -> Result<u64> {
+        Ok(self.filtered(&filter)?.len() as u64)
+    }
+
+    async fn update_models(&self, filter: Document, update: Document) -> Result<u64> {
+        h_validate_update(&update)?;
+        let mut docs = self.lock();
+        let mut nb_modified = 0;
+        for doc in docs.iter_mut() {
+            if matches_filter(doc, &filter)? {
+                let mut updated = doc.clone();
+                apply_update(&mut updated, &update)?;
+                if updated != *doc {
+                    *doc = updated;
+                    nb_modified += 1;
+                }
+            }
+        }
+        Ok(nb_modified)
+    }
+
+    async fn delete_models(&self, filter: Document) -> Result<u64> {
+        let mut docs = self.lock();
+        let mut kept = Vec::with_capacity(docs.len());
+        let mut nb_deleted = 0;
+        for doc in docs.drain(..) {
+            if matches_filter(&doc, &filter)? {
+                nb_deleted += 1;
+            } else {
+                kept.push(doc);
+            }
+        }
+        *docs = kept;
+        Ok(nb_deleted)
+    }
+}
+
+fn unsupported(operator: &str) -> mongodb::error::Error {
+    std::io::Error::other(format!(
+        "operator '{}' is not supported by MemoryRepository",
+        operator
+    ))
+    .into()
+}
+
+fn matches_filter(doc: &Document, filter: &Document) -> Result<bool> {
+    for (key, cond) in filter {
+        let matched = match key.as_str() {
+            "$and" => sub_filters_matches(doc, key, cond)?.into_iter().all(|m| m),
+            "$or" => sub_filters_matches(doc, key, cond)?.into_iter().any(|m| m),
+            "$nor" => !sub_filters_matches(doc, key, cond)?.into_iter().any(|m| m),
+            op if op.starts_with('$') => return Err(unsupported(op)),
+            path => matches_condition(h_get_path(doc, path), cond)?,
+        };
+
+        if !matched {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn sub_filters_matches(doc: &Document, operator: &str, value: &Bson) -> Result<Vec<bool>> {
+    let invalid = || std::io::Error::other(format!("'{}' expects an array of documents", operator));
+    let array = value.as_array().ok_or_else(invalid)?;
+    array
+        .iter()
+        .map(|v| matches_filter(doc, v.as_document().ok_or_else(invalid)?))
+        .collect()
+}
+
+fn is_operator_doc(doc: &Document) -> bool {
+    !doc.is_empty() && doc.keys().all(|k| k.starts_with('$'))
+}
+
+fn matches_condition(value: Option<&Bson>, cond: &Bson) -> Result<bool> {
+    match cond {
+        Bson::Document(ops) if is_operator_doc(ops) => {
+            for (op, arg) in ops {
+                let matched = match op.as_str() {
+                    "$eq" => is_equal(value, arg),
+                    "$ne" => !is_equal(value, arg),
+                    "$gt" => compare_any(value, arg, |o| o == Ordering::Greater),
+                    "$gte" => compare_any(value, arg, |o| o != Ordering::Less),
+                    "$lt" => compare_any(value, arg, |o| o == Ordering::Less),
+                    "$lte" => compare_any(value, arg, |o| o != Ordering::Greater),
+                    "$in" => in_array(value, op, arg)?,
+                    "$nin" => !in_array(value, op, arg)?,
+                    "$exists" => value.is_some() == is_included(arg),
+                    _ => return Err(unsupported(op)),
+                };
+
+                if !matched {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        _ => Ok(is_equal(value, cond)),
+    }
+}
+
+fn is_equal(value: Option<&Bson>, expected: &Bson) -> bool {
+    match value {
+        None => matches!(expected, Bson::Null),
+        Some(Bson::Array(values)) if !matches!(expected, Bson::Array(_)) => {
+            values.iter().any(|v| bson_eq(v, expected))
+        }
+        Some(value) => bson_eq(value, expected),
+    }
+}
+
+fn bson_eq(a: &Bson, b: &Bson) -> bool {
+    match bson_cmp(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+fn in_array(value: Option<&Bson>, operator: &str, arg: &Bson) -> Result<bool> {
+    let candidates = arg
+        .as_array()
+        .ok_or_else(|| std::io::Error::other(format!("'{}' expects an array", operator)))?;
+    Ok(candidates.iter().any(|c| is_equal(value, c)))
+}
+
+fn compare_any(value: Option<&Bson>, arg: &Bson, pred: impl Fn(Ordering) -> bool) -> bool {
+    match value {
+        Some(Bson::Array(values)) => values
+            .iter()
+            .any(|v| bson_cmp(v, arg).map(&pred).unwrap_or(false)),
+        Some(value) => bson_cmp(value, arg).map(pred).unwrap_or(false),
+        None => false,
+    }
+}
+
+fn bson_cmp(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Some(a.cmp(b)),
+        (Bson::Int64(a), Bson::Int64(b)) => Some(a.cmp(b)),
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        _ => as_f64(a)?.partial_cmp(&as_f64(b)?),
+    }
+}
+
+fn sort_documents(docs: &mut [Document], sort: &Document) {
+    docs.sort_by(|a, b| {
+        for (path, direction) in sort {
+            // Missing fields are sorted first, like null values
+            let ordering = match (h_get_path(a, path), h_get_path(b, path)) {
+                (Some(a), Some(b)) => bson_cmp(a, b).unwrap_or(Ordering::Equal),
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+            let ordering = match bson_cmp(direction, &Bson::Int32(0)) {
+                Some(Ordering::Less) => ordering.reverse(),
+                _ => ordering,
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+/// Whether a projection value or an `$exists` argument is truthy: anything but `false` and numeric zeros.
+fn is_included(value: &Bson) -> bool {
+    !matches!(value, Bson::Boolean(false))
+        && bson_cmp(value, &Bson::Int32(0)) != Some(Ordering::Equal)
+}
+
+fn project_document(mut doc: Document, projection: &Document) -> Result<Document> {
+    // `_id` is included by default: including it only selects an inclusion projection when it is alone
+    let inclusion = projection
+        .iter()
+        .any(|(path, value)| (path != "_id" || projection.len() == 1) && is_included(value));
+
+    if !inclusion {
+        for (path, value) in projection {
+            if !is_included(value) {
+                unset_path(&mut doc, path);
+            }
+        }
+        return Ok(doc);
+    }
+
+    let mut projected = Document::new();
+    if projection.get("_id").is_none_or(is_included) {
+        if let Some(id) = doc.get("_id") {
+            projected.insert("_id", id.clone());
+        }
+    }
+    for (path, value) in projection {
+        if path.contains('.') {
+            return Err(unsupported("dotted inclusion projection"));
+        }
+        if path != "_id" && is_included(value) {
+            if let Some(value) = doc.remove(path) {
+                projected.insert(path, value);
+            }
+        }
+    }
+    Ok(projected)
+}
+
+fn apply_update(doc: &mut Document, update: &Document) -> Result<()> {
+    for (op, fields) in update {
+        let fields = fields
+            .as_document()
+            .ok_or_else(|| std::io::Error::other(format!("'{}' expects a document", op)))?;
+        for (path, value) in fields {
+            match op.as_str() {
+                "$set" => set_path(doc, path, value.clone())?,
+                "$unset" => unset_path(doc, path),
+                "$inc" => {
+                    let current = h_get_path(doc, path).cloned().unwrap_or(Bson::Int32(0));
+                    let incremented = increment(&current, value).ok_or_else(|| {
+                        std::io::Error::other(format!("cannot apply '$inc' to field '{}'", path))
+                    })?;
+                    set_path(doc, path, incremented)?;
+                }
+                _ => return Err(unsupported(op)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `by` to `current` like the server does: an `Int32` overflow is promoted to `Int64`, any
+/// `Double` operand yields a `Double`, and `None` is returned on `Int64` overflow or non-numeric
+/// operands.
+fn increment(current: &Bson, by: &Bson) -> Option<Bson> {
+    match (current, by) {
+        (Bson::Int32(a), Bson::Int32(b)) => Some(
+            a.checked_add(*b)
+                .map(Bson::Int32)
+                .unwrap_or(Bson::Int64(i64::from(*a) + i64::from(*b))),
+        ),
+        (Bson::Int32(a), Bson::Int64(b)) => i64::from(*a).checked_add(*b).map(Bson::Int64),
+        (Bson::Int64(a), Bson::Int32(b)) => a.checked_add(i64::from(*b)).map(Bson::Int64),
+        (Bson::Int64(a), Bson::Int64(b)) => a.checked_add(*b).map(Bson::Int64),
+        (Bson::Double(_), _) | (_, Bson::Double(_)) => {
+            Some(Bson::Double(as_f64(current)? + as_f64(by)?))
+        }
+        _ => None,
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(v) => Some(f64::from(*v)),
+        Bson::Int64(v) => Some(*v as f64),
+        Bson::Double(v) => Some(*v),
+        _ => None,
+    }
+}
+
+fn set_path(doc: &mut Document, path: &str, value: Bson) -> Result<()> {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if !doc.contains_key(head) {
+                doc.insert(head, Document::new());
+            }
+            match doc.get_mut(head) {
+                Some(Bson::Document(sub)) => set_path(sub, rest, value),
+                _ => Err(
+                    std::io::Error::other(format!("cannot set '{}' on a non-document", path))
+                        .into(),
+                ),
+            }
+        }
+        None => {
+            doc.insert(path, value);
+            Ok(())
+        }
+    }
+}
+
+fn unset_path(doc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(sub)) = doc.get_mut(head) {
+                unset_path(sub, rest);
+            }
+        }
+        None => {
+            doc.remove(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn filter_matching() {
+        let doc = doc! {
+            "name": "David",
+            "age": 35,
+            "tags": ["admin", "staff"],
+            "address": { "city": "Montreal" },
+        };
+
+        assert!(matches_filter(&doc, &doc! {}).unwrap());
+        assert!(matches_filter(&doc, &doc! { "name": "David", "age": 35i64 }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "tags": "admin" }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "address.city": "Montreal" }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "age": { "$gt": 30, "$lte": 35.0 } }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "name": { "$in": ["Stacey", "David"] } }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "missing": { "$exists": false } }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "missing": { "$exists": 0 } }).unwrap());
+        assert!(matches_filter(&doc, &doc! { "name": { "$exists": 1 } }).unwrap());
+        assert!(matches_filter(
+            &doc,
+            &doc! { "$or": [{ "name": "Stacey" }, { "age": { "$lt": 40 } }] }
+        )
+        .unwrap());
+
+        assert!(!matches_filter(&doc, &doc! { "name": "Stacey" }).unwrap());
+        assert!(!matches_filter(&doc, &doc! { "age": { "$gt": 35 } }).unwrap());
+        assert!(!matches_filter(&doc, &doc! { "name": { "$nin": ["David"] } }).unwrap());
+        assert!(!matches_filter(&doc, &doc! { "$nor": [{ "name": "David" }] }).unwrap());
+        assert!(!matches_filter(&doc, &doc! { "name": { "$exists": 0.0 } }).unwrap());
+        assert!(!matches_filter(&doc, &doc! { "missing": { "$exists": 1i64 } }).unwrap());
+
+        assert!(matches_filter(&doc, &doc! { "name": { "$regex": "^D" } }).is_err());
+    }
+
+    #[test]
+    fn sort() {
+        let mut docs = vec![
+            doc! { "name": "a", "age": 30 },
+            doc! { "name": "b", "age": 20 },
+            doc! { "name": "c" },
+            doc! { "name": "d", "age": 30 },
+        ];
+        sort_documents(&mut docs, &doc! { "age": -1, "name": 1 });
+
+        let names: Vec<_> = docs.iter().map(|d| d.get_str("name").unwrap()).collect();
+        assert_eq!(names, vec!["a", "d", "b", "c"]);
+    }
+
+    #[test]
+    fn projection() {
+        let doc = doc! { "_id": 1, "name": "David", "history": [1, 2, 3], "address": { "city": "Montreal", "zip": "H0H" } };
+
+        assert_eq!(
+            project_document(doc.clone(), &doc! { "history": 0, "address.zip": false }).unwrap(),
+            doc! { "_id": 1, "name": "David", "address": { "city": "Montreal" } }
+        );
+        assert_eq!(
+            project_document(doc.clone(), &doc! { "name": 1 }).unwrap(),
+            doc! { "_id": 1, "name": "David" }
+        );
+        assert_eq!(
+            project_document(doc.clone(), &doc! { "_id": 1 }).unwrap(),
+            doc! { "_id": 1 }
+        );
+        assert_eq!(
+            project_document(doc.clone(), &doc! { "_id": true, "history": 0 }).unwrap(),
+            doc! { "_id": 1, "name": "David", "address": { "city": "Montreal", "zip": "H0H" } }
+        );
+        assert_eq!(
+            project_document(doc.clone(), &doc! { "_id": 0, "name": true }).unwrap(),
+            doc! { "name": "David" }
+        );
+        assert!(project_document(doc, &doc! { "address.city": 1 }).is_err());
+    }
+
+    #[test]
+    fn update_operators() {
+        let mut doc = doc! { "name": "David", "age": 35, "info": "a" };
+        apply_update(
+            &mut doc,
+            &doc! {
+                "$set": { "name": "Dane", "address.city": "Montreal" },
+                "$unset": { "info": "" },
+                "$inc": { "age": 1, "visits": 2 },
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            doc,
+            doc! { "name": "Dane", "age": 36, "address": { "city": "Montreal" }, "visits": 2 }
+        );
+    }
+
+    #[test]
+    fn inc_promotes_int32_overflow() {
+        let mut doc = doc! { "n": i32::MAX };
+        apply_update(&mut doc, &doc! { "$inc": { "n": 1 } }).unwrap();
+        assert_eq!(doc, doc! { "n": i64::from(i32::MAX) + 1 });
+    }
+
+    #[test]
+    fn inc_rejects_int64_overflow() {
+        let mut doc = doc! { "n": i64::MAX };
+        assert!(apply_update(&mut doc, &doc! { "$inc": { "n": 1 } }).is_err());
+        assert!(apply_update(&mut doc, &doc! { "$inc": { "n": 1i64 } }).is_err());
+        assert_eq!(doc, doc! { "n": i64::MAX });
+    }
+
+    #[test]
+    fn inc_mixed_int_and_double() {
+        let mut doc = doc! { "a": 1, "b": 2i64, "c": 1.5 };
+        apply_update(&mut doc, &doc! { "$inc": { "a": 0.5, "b": 0.25, "c": 2 } }).unwrap();
+        assert_eq!(doc, doc! { "a": 1.5, "b": 2.25, "c": 3.5 });
+    }
+
+    #[test]
+    fn inc_rejects_non_numeric() {
+        let mut doc = doc! { "name": "David" };
+        assert!(apply_update(&mut doc, &doc! { "$inc": { "name": 1 } }).is_err());
+    }
+}
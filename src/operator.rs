@@ -39,6 +39,39 @@
 //! // Generated document are identicals
 //! assert_eq!(a, b);
 //! ```
+//!
+//! Aggregation stages and expressions are available as well, such as window functions:
+//!
+//! ```
+//! use mongodm::mongo::bson::doc;
+//! use mongodm::operator::*;
+//!
+//! // Rank sales by quantity for each state
+//! let pipeline = vec![doc! {
+//!     SetWindowFields: {
+//!         "partitionBy": "$state",
+//!         "sortBy": { "quantity": -1 },
+//!         "output": {
+//!             "rank": { Rank: {} },
+//!             "previousQuantity": { Shift: { "output": "$quantity", "by": -1 } },
+//!         }
+//!     }
+//! }];
+//!
+//! assert_eq!(
+//!     pipeline,
+//!     vec![doc! {
+//!         "$setWindowFields": {
+//!             "partitionBy": "$state",
+//!             "sortBy": { "quantity": -1 },
+//!             "output": {
+//!                 "rank": { "$rank": {} },
+//!                 "previousQuantity": { "$shift": { "output": "$quantity", "by": -1 } },
+//!             }
+//!         }
+//!     }],
+//! );
+//! ```
 
 macro_rules! declare_operator {
     ($ty:ident => $mongo_operator:literal) => {
@@ -148,6 +181,16 @@ declare_operator! { "Geospatial" ["https://docs.mongodb.com/manual/reference/ope
     NearSphere => "$nearSphere",
 }
 
+declare_operator! { "Geometry specifiers" ["https://docs.mongodb.com/manual/reference/operator/query/#geometry-specifiers"]:
+    GeoBox => "$box",
+    Center => "$center",
+    CenterSphere => "$centerSphere",
+    Geometry => "$geometry",
+    MaxDistance => "$maxDistance",
+    MinDistance => "$minDistance",
+    Polygon => "$polygon",
+}
+
 declare_operator! { "Array (query)" ["https://docs.mongodb.com/manual/reference/operator/query/#array"]:
     All => "$all",
     ElemMatch => "$elemMatch",
@@ -418,6 +461,21 @@ declare_operator! { "Accumulators ($group)" ["https://docs.mongodb.com/manual/re
     Sum => "$sum",
 }
 
+declare_operator! { "Window functions" ["https://docs.mongodb.com/manual/reference/operator/aggregation/setWindowFields/"]:
+    SetWindowFields => "$setWindowFields",
+    Rank => "$rank",
+    DenseRank => "$denseRank",
+    DocumentNumber => "$documentNumber",
+    Shift => "$shift",
+    Derivative => "$derivative",
+    Integral => "$integral",
+    ExpMovingAvg => "$expMovingAvg",
+    CovariancePop => "$covariancePop",
+    CovarianceSamp => "$covarianceSamp",
+    Locf => "$locf",
+    LinearFill => "$linearFill",
+}
+
 declare_operator! { "Variable Expression Operators" ["https://docs.mongodb.com/manual/reference/operator/aggregation/#variable-expression-operators"]:
     Let => "$let",
 }
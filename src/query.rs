@@ -0,0 +1,1323 @@
+//! Helpers to build common query documents.
+//!
+//! These builders produce the same documents you would write by hand with `doc!`,
+//! but take care of the operator nesting for you.
+
+use crate::operator::*;
+use crate::{CollectionConfig, Model};
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::UpdateOptions;
+use std::marker::PhantomData;
+
+/// Build a `$text` search query.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/text/)
+///
+/// A text index must exist on the collection (see `Index::new_with_text`).
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// let filter = text_search("coffee shop")
+///     .language("en")
+///     .case_sensitive(false)
+///     .into_document();
+///
+/// assert_eq!(
+///     filter,
+///     doc! {
+///         "$text": {
+///             "$search": "coffee shop",
+///             "$language": "en",
+///             "$caseSensitive": false,
+///         }
+///     }
+/// );
+///
+/// // Sort results by relevance
+/// let options = MongoFindOptions::builder()
+///     .projection(doc! { "score": text_score() })
+///     .sort(doc! { "score": text_score() })
+///     .build();
+///
+/// assert_eq!(
+///     options.sort,
+///     Some(doc! { "score": { "$meta": "textScore" } }),
+/// );
+/// ```
+pub fn text_search(search: impl Into<String>) -> TextSearch {
+    TextSearch {
+        search: search.into(),
+        language: None,
+        case_sensitive: None,
+        diacritic_sensitive: None,
+    }
+}
+
+/// `$meta: "textScore"` expression, to be used in projections and sorts of text search queries.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/aggregation/meta/)
+pub fn text_score() -> Document {
+    doc! { Meta: "textScore" }
+}
+
+/// Builder for a `$text` search query. Created with `text_search`.
+#[derive(Debug, Clone)]
+pub struct TextSearch {
+    search: String,
+    language: Option<String>,
+    case_sensitive: Option<bool>,
+    diacritic_sensitive: Option<bool>,
+}
+
+impl TextSearch {
+    /// Language determining the list of stop words and the rules for the stemmer and tokenizer.
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    /// Enable or disable case sensitive search.
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = Some(case_sensitive);
+        self
+    }
+
+    /// Enable or disable diacritic sensitive search.
+    pub fn diacritic_sensitive(mut self, diacritic_sensitive: bool) -> Self {
+        self.diacritic_sensitive = Some(diacritic_sensitive);
+        self
+    }
+
+    /// Convert this structure into a `Document` usable as a query filter.
+    pub fn into_document(self) -> Document {
+        let mut text = doc! { "$search": self.search };
+        if let Some(language) = self.language {
+            text.insert("$language", language);
+        }
+        if let Some(case_sensitive) = self.case_sensitive {
+            text.insert("$caseSensitive", case_sensitive);
+        }
+        if let Some(diacritic_sensitive) = self.diacritic_sensitive {
+            text.insert("$diacriticSensitive", diacritic_sensitive);
+        }
+        doc! { Text: text }
+    }
+}
+
+impl From<TextSearch> for Document {
+    fn from(t: TextSearch) -> Document {
+        t.into_document()
+    }
+}
+
+impl From<TextSearch> for Bson {
+    fn from(t: TextSearch) -> Bson {
+        Bson::Document(t.into_document())
+    }
+}
+
+/// Mean radius of the earth, in meters, as used by mongo to convert distances to radians.
+pub const EARTH_RADIUS_METERS: f64 = 6_378_100.0;
+
+/// Longitude in degrees, from -180 to 180.
+///
+/// Having a dedicated type for each coordinate prevents swapping longitude and latitude,
+/// GeoJSON expecting the longitude first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Longitude(pub f64);
+
+/// Latitude in degrees, from -90 to 90.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latitude(pub f64);
+
+fn coordinates(point: (Longitude, Latitude)) -> Bson {
+    let (Longitude(lng), Latitude(lat)) = point;
+    Bson::Array(vec![lng.into(), lat.into()])
+}
+
+/// Build a `$near` query matching documents from the nearest to the farthest of a GeoJSON point.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/near/)
+///
+/// Requires a `2dsphere` index on the queried field.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// assert_eq!(
+///     doc! { "location": near(Longitude(-73.9667), Latitude(40.78), 500.0) },
+///     doc! {
+///         "location": {
+///             "$near": {
+///                 "$geometry": { "type": "Point", "coordinates": [-73.9667, 40.78] },
+///                 "$maxDistance": 500.0,
+///             }
+///         }
+///     }
+/// );
+/// ```
+pub fn near(lng: Longitude, lat: Latitude, max_distance_meters: f64) -> Document {
+    doc! {
+        Near: {
+            Geometry: { "type": "Point", "coordinates": coordinates((lng, lat)) },
+            MaxDistance: max_distance_meters,
+        }
+    }
+}
+
+/// Build a `$geoWithin` query matching legacy coordinate pairs within a box.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/box/)
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// assert_eq!(
+///     doc! { "location": geo_within_box((Longitude(0.0), Latitude(0.0)), (Longitude(100.0), Latitude(80.0))) },
+///     doc! { "location": { "$geoWithin": { "$box": [[0.0, 0.0], [100.0, 80.0]] } } }
+/// );
+/// ```
+pub fn geo_within_box(
+    bottom_left: (Longitude, Latitude),
+    upper_right: (Longitude, Latitude),
+) -> Document {
+    doc! { GeoWithin: { GeoBox: [coordinates(bottom_left), coordinates(upper_right)] } }
+}
+
+/// Build a `$geoWithin` query matching points within a circle on a sphere.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/centerSphere/)
+///
+/// The radius is given in meters and converted to radians using `EARTH_RADIUS_METERS`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// assert_eq!(
+///     doc! { "location": geo_within_center_sphere((Longitude(-88.0), Latitude(30.0)), 6_378_100.0) },
+///     doc! { "location": { "$geoWithin": { "$centerSphere": [[-88.0, 30.0], 1.0] } } }
+/// );
+/// ```
+pub fn geo_within_center_sphere(center: (Longitude, Latitude), radius_meters: f64) -> Document {
+    doc! {
+        GeoWithin: {
+            CenterSphere: [coordinates(center), radius_meters / EARTH_RADIUS_METERS]
+        }
+    }
+}
+
+fn expr_cmp(operator: impl Into<String>, a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    doc! { Expr: { operator.into(): [a.into(), b.into()] } }
+}
+
+/// Build an `$expr` query matching documents where `a` equals `b`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/expr/)
+///
+/// Unlike regular query operators, `$expr` allows to compare two fields of the same document.
+/// Field references are dollar-prefixed, conveniently obtained with `field!(@... in ...)`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct Budget {
+///     spent: i64,
+///     budget: i64,
+/// }
+///
+/// assert_eq!(
+///     expr_gt(f!(@spent in Budget), f!(@budget in Budget)),
+///     doc! { "$expr": { "$gt": ["$spent", "$budget"] } },
+/// );
+///
+/// // Literal values can be used as well
+/// assert_eq!(
+///     expr_lte(f!(@spent in Budget), 100),
+///     doc! { "$expr": { "$lte": ["$spent", 100] } },
+/// );
+/// ```
+pub fn expr_eq(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(Equal, a, b)
+}
+
+/// Build an `$expr` query matching documents where `a` is not equal to `b`. See `expr_eq`.
+pub fn expr_ne(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(NotEqual, a, b)
+}
+
+/// Build an `$expr` query matching documents where `a` is greater than `b`. See `expr_eq`.
+pub fn expr_gt(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(GreaterThan, a, b)
+}
+
+/// Build an `$expr` query matching documents where `a` is greater than or equal to `b`. See `expr_eq`.
+pub fn expr_gte(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(GreaterThanEqual, a, b)
+}
+
+/// Build an `$expr` query matching documents where `a` is lesser than `b`. See `expr_eq`.
+pub fn expr_lt(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(LesserThan, a, b)
+}
+
+/// Build an `$expr` query matching documents where `a` is lesser than or equal to `b`. See `expr_eq`.
+pub fn expr_lte(a: impl Into<Bson>, b: impl Into<Bson>) -> Document {
+    expr_cmp(LesserThanEqual, a, b)
+}
+
+/// Build an `$or` query matching documents satisfying any of the conditions.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/or/)
+///
+/// Same as `doc! { Or: [...] }`, but accepting conditions built at runtime (for equality
+/// against several values of a single field, prefer `In`).
+///
+/// Mongo rejects an empty `$or` array: when `conditions` is empty, a query matching no document
+/// (`{ "$expr": false }`) is returned instead, consistently with "any of nothing".
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     name: String,
+///     age: i64,
+/// }
+///
+/// let conditions = vec![
+///     doc! { f!(age in User): { LesserThan: 18 } },
+///     doc! { f!(name in User): { Regex: "^admin" } },
+/// ];
+/// assert_eq!(
+///     any_of(conditions),
+///     doc! { "$or": [ { "age": { "$lt": 18 } }, { "name": { "$regex": "^admin" } } ] },
+/// );
+///
+/// assert_eq!(any_of(Vec::new()), doc! { "$expr": false });
+/// ```
+pub fn any_of(conditions: impl IntoIterator<Item = Document>) -> Document {
+    let conditions: Vec<Document> = conditions.into_iter().collect();
+    if conditions.is_empty() {
+        return doc! { Expr: false };
+    }
+    doc! { Or: conditions }
+}
+
+/// Build an `$and` query matching documents satisfying all of the conditions. See `any_of`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/and/)
+///
+/// Mongo rejects an empty `$and` array: when `conditions` is empty, an empty query matching
+/// all documents is returned instead, consistently with "all of nothing".
+pub fn all_of(conditions: impl IntoIterator<Item = Document>) -> Document {
+    let conditions: Vec<Document> = conditions.into_iter().collect();
+    if conditions.is_empty() {
+        return Document::new();
+    }
+    doc! { And: conditions }
+}
+
+/// Build a condition matching dates in the half-open interval `[from, to)`.
+///
+/// `from` is inclusive and `to` is exclusive, which allows to chain consecutive ranges
+/// (e.g. one per day) without missing or counting twice the documents on the boundaries.
+///
+/// Any type convertible into `bson::DateTime` is accepted: `std::time::SystemTime`, and
+/// `chrono::DateTime` or `time::OffsetDateTime` when the matching `bson` features
+/// (`chrono-0_4`, `time-0_3`) are enabled. Bson dates are stored in UTC with millisecond precision.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct Event {
+///     created_at: BsonDateTime,
+/// }
+///
+/// let start = BsonDateTime::from_millis(1_600_000_000_000);
+/// let end = BsonDateTime::from_millis(1_600_086_400_000);
+///
+/// assert_eq!(
+///     doc! { f!(created_at in Event): date_range(start, end) },
+///     doc! { "created_at": { "$gte": start, "$lt": end } },
+/// );
+/// ```
+pub fn date_range(from: impl Into<DateTime>, to: impl Into<DateTime>) -> Document {
+    doc! { GreaterThanEqual: from.into(), LesserThan: to.into() }
+}
+
+/// Build a condition matching dates strictly before `dt`. See `date_range`.
+pub fn before(dt: impl Into<DateTime>) -> Document {
+    doc! { LesserThan: dt.into() }
+}
+
+/// Build a condition matching dates at or after `dt`. See `date_range`.
+pub fn after(dt: impl Into<DateTime>) -> Document {
+    doc! { GreaterThanEqual: dt.into() }
+}
+
+/// BSON type alias, to be used with the `Type` query operator.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/type/)
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// assert_eq!(
+///     doc! { "age": { Type: BsonTypeName::Int } },
+///     doc! { "age": { "$type": "int" } },
+/// );
+///
+/// assert_eq!(
+///     doc! { "age": { Type: BsonTypeName::any_of(&[BsonTypeName::Int, BsonTypeName::Long]) } },
+///     doc! { "age": { "$type": ["int", "long"] } },
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BsonTypeName {
+    /// 64-bit floating point
+    Double,
+    /// UTF-8 string
+    String,
+    /// Embedded document
+    Object,
+    /// Array
+    Array,
+    /// Binary data
+    BinData,
+    /// ObjectId
+    ObjectId,
+    /// Boolean
+    Bool,
+    /// UTC datetime
+    Date,
+    /// Null
+    Null,
+    /// Regular expression
+    Regex,
+    /// JavaScript code
+    JavaScript,
+    /// 32-bit integer
+    Int,
+    /// Internal timestamp
+    Timestamp,
+    /// 64-bit integer
+    Long,
+    /// 128-bit decimal
+    Decimal,
+    /// Min key
+    MinKey,
+    /// Max key
+    MaxKey,
+    /// Any numeric type (`double`, `int`, `long` or `decimal`)
+    Number,
+}
+
+impl BsonTypeName {
+    /// Alias of this type as expected by `$type`.
+    pub fn alias(self) -> &'static str {
+        match self {
+            BsonTypeName::Double => "double",
+            BsonTypeName::String => "string",
+            BsonTypeName::Object => "object",
+            BsonTypeName::Array => "array",
+            BsonTypeName::BinData => "binData",
+            BsonTypeName::ObjectId => "objectId",
+            BsonTypeName::Bool => "bool",
+            BsonTypeName::Date => "date",
+            BsonTypeName::Null => "null",
+            BsonTypeName::Regex => "regex",
+            BsonTypeName::JavaScript => "javascript",
+            BsonTypeName::Int => "int",
+            BsonTypeName::Timestamp => "timestamp",
+            BsonTypeName::Long => "long",
+            BsonTypeName::Decimal => "decimal",
+            BsonTypeName::MinKey => "minKey",
+            BsonTypeName::MaxKey => "maxKey",
+            BsonTypeName::Number => "number",
+        }
+    }
+
+    /// Match any of the given types.
+    pub fn any_of(types: &[BsonTypeName]) -> Bson {
+        Bson::Array(types.iter().map(|t| Bson::from(*t)).collect())
+    }
+}
+
+impl From<BsonTypeName> for Bson {
+    fn from(t: BsonTypeName) -> Bson {
+        Bson::String(t.alias().to_owned())
+    }
+}
+
+/// Build a `$push` update appending multiple `values` to the array `field`, optionally keeping it sorted and capped.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/update/push/)
+///
+/// `$sort` and `$slice` are applied to the whole array after the values are pushed, in that order.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// // Keep the 10 most recent activities, newest first
+/// let update = push_each("activities", vec![doc! { "kind": "login", "at": 1700 }])
+///     .sort(doc! { "at": -1 })
+///     .slice(10)
+///     .into_document();
+///
+/// assert_eq!(
+///     update,
+///     doc! {
+///         "$push": {
+///             "activities": {
+///                 "$each": [{ "kind": "login", "at": 1700 }],
+///                 "$sort": { "at": -1 },
+///                 "$slice": 10,
+///             }
+///         }
+///     }
+/// );
+/// ```
+pub fn push_each<I, V>(field: impl Into<String>, values: I) -> PushEach
+where
+    I: IntoIterator<Item = V>,
+    V: Into<Bson>,
+{
+    PushEach {
+        field: field.into(),
+        values: values.into_iter().map(Into::into).collect(),
+        position: None,
+        sort: None,
+        slice: None,
+    }
+}
+
+/// Builder for a `$push` update with `$each`. Created with `push_each`.
+#[derive(Debug, Clone)]
+pub struct PushEach {
+    field: String,
+    values: Vec<Bson>,
+    position: Option<i32>,
+    sort: Option<Bson>,
+    slice: Option<i32>,
+}
+
+impl PushEach {
+    /// Insert the values at the given index rather than at the end of the array.
+    /// A negative index counts from the end of the array.
+    pub fn position(mut self, position: i32) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Sort the array after pushing: either `1` / `-1` for arrays of scalars, or a sort document
+    /// on fields of the elements for arrays of documents.
+    pub fn sort(mut self, sort: impl Into<Bson>) -> Self {
+        self.sort = Some(sort.into());
+        self
+    }
+
+    /// Cap the array after pushing and sorting.
+    ///
+    /// A positive `n` keeps the first `n` elements, a negative `n` keeps the last `-n` elements,
+    /// and `0` empties the array.
+    pub fn slice(mut self, n: i32) -> Self {
+        self.slice = Some(n);
+        self
+    }
+
+    /// Convert this structure into a `Document` usable as an update.
+    pub fn into_document(self) -> Document {
+        let mut each = doc! { Each: self.values };
+        if let Some(position) = self.position {
+            each.insert(Position, position);
+        }
+        if let Some(sort) = self.sort {
+            each.insert(Sort, sort);
+        }
+        if let Some(slice) = self.slice {
+            each.insert(Slice, slice);
+        }
+        doc! { Push: { self.field: each } }
+    }
+}
+
+impl From<PushEach> for Document {
+    fn from(p: PushEach) -> Document {
+        p.into_document()
+    }
+}
+
+impl From<PushEach> for Bson {
+    fn from(p: PushEach) -> Bson {
+        Bson::Document(p.into_document())
+    }
+}
+
+/// Build an update of the elements of the array `array_field` matching `conditions`, along with the
+/// `UpdateOptions` carrying the corresponding `arrayFilters`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/update/positional-filtered/)
+///
+/// Field paths of both `conditions` and `update` are relative to an array element: they are prefixed
+/// with `identifier` and `array_field.$[identifier]` respectively. For arrays of scalars, conditions
+/// on the element itself are written as operators (eg: `{ GreaterThanEqual: 100 }`) and the element
+/// itself is updated using an empty path.
+/// `identifier` must start with a lowercase letter and contain only alphanumeric characters.
+///
+/// The options can be used with `update_one`, `update_many` or in a `BulkUpdate`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// let (update, options) = update_array_elements(
+///     "items",
+///     "elem",
+///     doc! { "sku": "X" },
+///     doc! { Set: { "price": 10 } },
+/// );
+///
+/// assert_eq!(update, doc! { "$set": { "items.$[elem].price": 10 } });
+/// assert_eq!(options.array_filters, Some(vec![doc! { "elem.sku": "X" }]));
+///
+/// // Array of scalars
+/// let (update, options) = update_array_elements(
+///     "scores",
+///     "score",
+///     doc! { GreaterThan: 100 },
+///     doc! { Set: { "": 100 } },
+/// );
+///
+/// assert_eq!(update, doc! { "$set": { "scores.$[score]": 100 } });
+/// assert_eq!(options.array_filters, Some(vec![doc! { "score": { "$gt": 100 } }]));
+/// ```
+///
+/// ```no_run
+/// # use mongodm::prelude::*;
+/// # async fn demo(coll: MongoCollection<mongodm::bson::Document>) {
+/// let (update, options) = update_array_elements(
+///     "items",
+///     "elem",
+///     doc! { "sku": "X" },
+///     doc! { Set: { "price": 10 } },
+/// );
+/// coll.update_one(doc! { "_id": 1 }, update)
+///     .with_options(options)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+pub fn update_array_elements(
+    array_field: &str,
+    identifier: &str,
+    conditions: Document,
+    update: Document,
+) -> (Document, UpdateOptions) {
+    let element_path = format!("{}.$[{}]", array_field, identifier);
+
+    let mut array_update = Document::new();
+    for (operator, fields) in update {
+        let value = match fields {
+            Bson::Document(fields) => Bson::Document(
+                fields
+                    .into_iter()
+                    .map(|(path, value)| (h_join_path(&element_path, &path), value))
+                    .collect(),
+            ),
+            other => other,
+        };
+        array_update.insert(operator, value);
+    }
+
+    let filter = if conditions.keys().any(|key| key.starts_with('$')) {
+        doc! { identifier: conditions }
+    } else {
+        conditions
+            .into_iter()
+            .map(|(path, value)| (h_join_path(identifier, &path), value))
+            .collect()
+    };
+    let options = UpdateOptions::builder().array_filters(vec![filter]).build();
+
+    (array_update, options)
+}
+
+/// Build a projection keeping only the first element of the array `array_field` matching `condition`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/projection/elemMatch/)
+///
+/// Not to be confused with the `$elemMatch` query operator (`ElemMatch` in a filter), which selects
+/// documents having at least one matching element but returns whole arrays. The projection form doesn't
+/// filter documents: the array field is simply omitted from documents without matching element, so it must
+/// be an `Option` (or `#[serde(default)]`) in the target type. Fields of `condition` are relative to array
+/// elements, and are preferably built with `field!` against the element type.
+///
+/// Other fields are excluded unless added to the projection (`_id` excepted). To be used in
+/// `FindOptions::projection`, such as with `Repository::find_as`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct Order {
+///     items: Vec<Item>,
+/// }
+///
+/// struct Item {
+///     sku: String,
+///     quantity: i32,
+/// }
+///
+/// let projection = project_elem_match(
+///     f!(items in Order),
+///     doc! { f!(sku in Item): "X", f!(quantity in Item): { GreaterThan: 1 } },
+/// );
+/// assert_eq!(
+///     projection,
+///     doc! { "items": { "$elemMatch": { "sku": "X", "quantity": { "$gt": 1 } } } },
+/// );
+/// ```
+///
+/// ```no_run
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Order {
+/// #     customer: String,
+/// #     items: Vec<Item>,
+/// # }
+/// # impl Model for Order {
+/// #     type CollConf = OrderCollConf;
+/// # }
+/// # struct OrderCollConf;
+/// # impl CollectionConfig for OrderCollConf {
+/// #     fn collection_name() -> &'static str { "order" }
+/// # }
+/// use mongodm::prelude::*;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Item {
+///     sku: String,
+///     quantity: i32,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct OrderItem {
+///     // The single matching item, if any
+///     items: Option<Vec<Item>>,
+/// }
+///
+/// # async fn demo(db: mongodb::Database) {
+/// let orders = db
+///     .repository::<Order>()
+///     .find_as::<OrderItem>(
+///         // Query operator: orders containing an item X
+///         doc! { f!(items in Order): { ElemMatch: { f!(sku in Item): "X" } } },
+///         // Projection operator: only return this item
+///         project_elem_match(f!(items in Order), doc! { f!(sku in Item): "X" }),
+///         None,
+///     )
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+pub fn project_elem_match(array_field: impl Into<String>, condition: Document) -> Document {
+    let mut projection = Document::new();
+    projection.insert(array_field, doc! { ElemMatch: condition });
+    projection
+}
+
+fn h_join_path(prefix: &str, path: &str) -> String {
+    if path.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{}.{}", prefix, path)
+    }
+}
+
+/// Build a `$rename` update moving the value of field `from` to field `to`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/update/rename/)
+///
+/// Both paths are meant to be built with `field!`, possibly from two different types: typically
+/// the previous and the current version of a model during a schema migration, so that both ends
+/// are checked at compile time. Documents missing `from` are left as is, and an existing `to` is
+/// overwritten. `$rename` doesn't work on fields inside arrays.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct UserV1 {
+///     mail: String,
+/// }
+///
+/// struct User {
+///     email: String,
+/// }
+///
+/// let update = rename(f!(mail in UserV1), f!(email in User));
+/// assert_eq!(update, doc! { "$rename": { "mail": "email" } });
+///
+/// // Migrate all remaining documents
+/// let filter = doc! { f!(mail in UserV1): { Exists: true } };
+/// ```
+pub fn rename(from: impl Into<String>, to: impl Into<String>) -> Document {
+    let mut fields = Document::new();
+    fields.insert(from, to.into());
+    doc! { Rename: fields }
+}
+
+/// Build a `$merge` stage writing the results of an aggregation pipeline into `collection`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/aggregation/merge/)
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// let stage = merge_into("monthly_totals")
+///     .on("_id")
+///     .when_matched(WhenMatched::Replace)
+///     .when_not_matched(WhenNotMatched::Insert)
+///     .into_document();
+///
+/// assert_eq!(
+///     stage,
+///     doc! {
+///         "$merge": {
+///             "into": "monthly_totals",
+///             "on": "_id",
+///             "whenMatched": "replace",
+///             "whenNotMatched": "insert",
+///         }
+///     }
+/// );
+/// ```
+pub fn merge_into(collection: impl Into<String>) -> MergeStage {
+    MergeStage {
+        into: Bson::String(collection.into()),
+        on: None,
+        when_matched: None,
+        when_not_matched: None,
+    }
+}
+
+/// Build a `$out` stage replacing `collection` with the results of an aggregation pipeline.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/aggregation/out/)
+pub fn out_to(collection: impl Into<String>) -> Document {
+    doc! { Out: collection.into() }
+}
+
+/// Behavior of a `$merge` stage when a result document matches an existing document.
+#[derive(Debug, Clone)]
+pub enum WhenMatched {
+    /// Replace the existing document
+    Replace,
+    /// Keep the existing document
+    KeepExisting,
+    /// Merge the result document into the existing document
+    Merge,
+    /// Stop and fail the aggregation
+    Fail,
+    /// Update the existing document with an update pipeline
+    Pipeline(Vec<Document>),
+}
+
+impl From<WhenMatched> for Bson {
+    fn from(w: WhenMatched) -> Bson {
+        match w {
+            WhenMatched::Replace => "replace".into(),
+            WhenMatched::KeepExisting => "keepExisting".into(),
+            WhenMatched::Merge => "merge".into(),
+            WhenMatched::Fail => "fail".into(),
+            WhenMatched::Pipeline(pipeline) => pipeline.into(),
+        }
+    }
+}
+
+/// Behavior of a `$merge` stage when a result document doesn't match any existing document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhenNotMatched {
+    /// Insert the result document
+    Insert,
+    /// Discard the result document
+    Discard,
+    /// Stop and fail the aggregation
+    Fail,
+}
+
+impl From<WhenNotMatched> for Bson {
+    fn from(w: WhenNotMatched) -> Bson {
+        match w {
+            WhenNotMatched::Insert => "insert",
+            WhenNotMatched::Discard => "discard",
+            WhenNotMatched::Fail => "fail",
+        }
+        .into()
+    }
+}
+
+/// Builder for a `$merge` stage. Created with `merge_into`.
+#[derive(Debug, Clone)]
+pub struct MergeStage {
+    into: Bson,
+    on: Option<Bson>,
+    when_matched: Option<WhenMatched>,
+    when_not_matched: Option<WhenNotMatched>,
+}
+
+impl MergeStage {
+    /// Write into a collection of another database.
+    pub fn in_db(mut self, db: impl Into<String>) -> Self {
+        if let Bson::String(coll) = self.into {
+            self.into = Bson::Document(doc! { "db": db.into(), "coll": coll });
+        }
+        self
+    }
+
+    /// Field identifying the existing document matching a result document. Defaults to `_id`.
+    pub fn on(mut self, field: impl Into<String>) -> Self {
+        self.on = Some(Bson::String(field.into()));
+        self
+    }
+
+    /// Fields identifying the existing document matching a result document.
+    /// A unique index must exist on these fields.
+    pub fn on_fields<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.on = Some(Bson::Array(
+            fields.into_iter().map(|f| Bson::String(f.into())).collect(),
+        ));
+        self
+    }
+
+    /// Behavior when a result document matches an existing document. Defaults to `WhenMatched::Merge`.
+    pub fn when_matched(mut self, when_matched: WhenMatched) -> Self {
+        self.when_matched = Some(when_matched);
+        self
+    }
+
+    /// Behavior when a result document doesn't match any existing document. Defaults to `WhenNotMatched::Insert`.
+    pub fn when_not_matched(mut self, when_not_matched: WhenNotMatched) -> Self {
+        self.when_not_matched = Some(when_not_matched);
+        self
+    }
+
+    /// Convert this structure into a `Document` usable as a pipeline stage.
+    pub fn into_document(self) -> Document {
+        let mut merge = doc! { "into": self.into };
+        if let Some(on) = self.on {
+            merge.insert("on", on);
+        }
+        if let Some(when_matched) = self.when_matched {
+            merge.insert("whenMatched", when_matched);
+        }
+        if let Some(when_not_matched) = self.when_not_matched {
+            merge.insert("whenNotMatched", when_not_matched);
+        }
+        doc! { Merge: merge }
+    }
+}
+
+impl From<MergeStage> for Document {
+    fn from(m: MergeStage) -> Document {
+        m.into_document()
+    }
+}
+
+impl From<MergeStage> for Bson {
+    fn from(m: MergeStage) -> Bson {
+        Bson::Document(m.into_document())
+    }
+}
+
+/// Build a `$lookup` stage joining documents of the `Foreign` model into documents of the `Local` model.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/aggregation/lookup/)
+///
+/// Unlike the `Lookup` operator, the joined collection is taken from `Foreign::CollConf::collection_name`,
+/// so renaming the collection can't silently break the pipeline. `local_field` is a field of `Local`
+/// and `foreign_field` a field of `Foreign`, ideally obtained with `f!`. Joined documents are stored
+/// in an array named `as_field`.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+/// # use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     _id: ObjectId,
+///     name: String,
+/// }
+/// # impl Model for User {
+/// #     type CollConf = UserCollConf;
+/// # }
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf {
+/// #     fn collection_name() -> &'static str { "users" }
+/// # }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Session {
+///     user_id: ObjectId,
+/// }
+///
+/// impl Model for Session {
+///     type CollConf = SessionCollConf;
+/// }
+///
+/// struct SessionCollConf;
+///
+/// impl CollectionConfig for SessionCollConf {
+///     fn collection_name() -> &'static str {
+///         "sessions"
+///     }
+/// }
+///
+/// let a = pipeline! [
+///     Match: { f!(name in User): "John" },
+///     lookup::<User, Session>(f!(_id in User), f!(user_id in Session), "sessions"),
+/// ];
+///
+/// let b = vec![
+///     doc! { "$match": { "name": "John" } },
+///     doc! { "$lookup": {
+///         "from": "sessions",
+///         "as": "sessions",
+///         "localField": "_id",
+///         "foreignField": "user_id",
+///     } },
+/// ];
+///
+/// assert_eq!(a, b);
+/// ```
+pub fn lookup<Local: Model, Foreign: Model>(
+    local_field: &str,
+    foreign_field: &str,
+    as_field: &str,
+) -> Document {
+    Lookup {
+        From: Foreign::CollConf::collection_name(),
+        As: as_field,
+        LocalField: local_field,
+        ForeignField: foreign_field,
+    }
+    .into()
+}
+
+/// Convert each segment of a snake_case field path to camelCase.
+///
+/// Stopgap for collections using the camelCase convention (e.g. `#[serde(rename_all = "camelCase")]`),
+/// which `field!` doesn't support: `to_camel_path(f!(last_seen in User))` yields `"lastSeen"`.
+/// Leading `$` and `_` characters of each segment are preserved, so `_id` and `field!(@...)` paths are
+/// left untouched.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     _id: ObjectId,
+///     last_seen: i64,
+///     home_address: Address,
+/// }
+///
+/// struct Address {
+///     zip_code: String,
+/// }
+///
+/// assert_eq!(to_camel_path(f!(last_seen in User)), "lastSeen");
+/// assert_eq!(to_camel_path(f!(@last_seen in User)), "$lastSeen");
+/// assert_eq!(to_camel_path(f!(_id in User)), "_id");
+/// assert_eq!(
+///     to_camel_path(f!((home_address in User).(zip_code in Address))),
+///     "homeAddress.zipCode",
+/// );
+/// assert_eq!(
+///     doc! { to_camel_path(f!(last_seen in User)): { GreaterThan: 1000 } },
+///     doc! { "lastSeen": { "$gt": 1000 } },
+/// );
+/// ```
+pub fn to_camel_path(path: &str) -> String {
+    let mut camel = String::with_capacity(path.len());
+    for (i, segment) in path.split('.').enumerate() {
+        if i > 0 {
+            camel.push('.');
+        }
+
+        let body = segment.trim_start_matches(['$', '_']);
+        camel.push_str(&segment[..segment.len() - body.len()]);
+
+        let mut upper_next = false;
+        for c in body.chars() {
+            if c == '_' {
+                upper_next = true;
+            } else if upper_next {
+                camel.extend(c.to_uppercase());
+                upper_next = false;
+            } else {
+                camel.push(c);
+            }
+        }
+    }
+    camel
+}
+
+/// Builder for an upsert of a `M` document, distinguishing fields set on every write from fields only
+/// set when the document is created.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/update/setOnInsert/)
+///
+/// `build` returns the update combining `$set` and `$setOnInsert`, along with `UpdateOptions` enabling
+/// `upsert`, to be used with `update_one` or in a `BulkUpdate`. Setting a creation timestamp with `set`
+/// would overwrite it on every upsert: use `set_on_insert` instead.
+///
+/// A field can't be part of both operators: setting a field with one method removes it from the other one.
+/// Operators without field are omitted, as an empty `$set` is rejected by the server.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+///
+/// struct User {
+///     name: String,
+///     last_seen: BsonDateTime,
+///     created_at: BsonDateTime,
+/// }
+///
+/// let date = BsonDateTime::from_millis(1_600_000_000_000);
+/// let (update, options) = Upsert::<User>::new()
+///     .set(f!(last_seen in User), date)
+///     .set_on_insert(f!(created_at in User), date)
+///     .build();
+///
+/// assert_eq!(
+///     update,
+///     doc! {
+///         "$set": { "last_seen": date },
+///         "$setOnInsert": { "created_at": date },
+///     }
+/// );
+/// assert_eq!(options.upsert, Some(true));
+/// ```
+///
+/// ```no_run
+/// # use mongodm::prelude::*;
+/// # use serde::{Serialize, Deserialize};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct User {
+/// #     name: String,
+/// #     last_seen: BsonDateTime,
+/// #     created_at: BsonDateTime,
+/// # }
+/// # impl Model for User {
+/// #     type CollConf = UserCollConf;
+/// # }
+/// # struct UserCollConf;
+/// # impl CollectionConfig for UserCollConf {
+/// #     fn collection_name() -> &'static str { "user" }
+/// # }
+/// # async fn demo(db: mongodb::Database) {
+/// let (update, options) = Upsert::<User>::new()
+///     .set(f!(last_seen in User), now())
+///     .set_on_insert(f!(created_at in User), now())
+///     .build();
+/// db.repository::<User>()
+///     .update_one(doc! { f!(name in User): "David" }, update)
+///     .with_options(options)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Upsert<M> {
+    set: Document,
+    set_on_insert: Document,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M> Default for Upsert<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M> Upsert<M> {
+    /// Start an upsert setting no field.
+    pub fn new() -> Self {
+        Self {
+            set: Document::new(),
+            set_on_insert: Document::new(),
+            _model: PhantomData,
+        }
+    }
+
+    /// Set `field` to `value` whether the document is inserted or updated.
+    pub fn set(mut self, field: impl Into<String>, value: impl Into<Bson>) -> Self {
+        let field = field.into();
+        self.set_on_insert.remove(&field);
+        self.set.insert(field, value);
+        self
+    }
+
+    /// Set `field` to `value` only when the document is inserted.
+    pub fn set_on_insert(mut self, field: impl Into<String>, value: impl Into<Bson>) -> Self {
+        let field = field.into();
+        self.set.remove(&field);
+        self.set_on_insert.insert(field, value);
+        self
+    }
+
+    /// Returns the update document and the options enabling `upsert`.
+    pub fn build(self) -> (Document, UpdateOptions) {
+        let mut update = Document::new();
+        if !self.set.is_empty() {
+            update.insert(Set, self.set);
+        }
+        if !self.set_on_insert.is_empty() {
+            update.insert(SetOnInsert, self.set_on_insert);
+        }
+        (update, UpdateOptions::builder().upsert(true).build())
+    }
+}
+
+/// Query filter tied to the model `M`, usually built with the `filter!` macro.
+///
+/// Repository helpers accepting a `ModelFilter<M>` (such as `Repository::find_filtered`) reject at compile time
+/// filters written for another model.
+#[derive(Debug, Clone)]
+pub struct ModelFilter<M> {
+    doc: Document,
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M> ModelFilter<M> {
+    /// Tag an arbitrary document as a filter on `M`.
+    ///
+    /// Fields of `doc` are not checked: this is an escape hatch for filters that can't be expressed
+    /// with `filter!`, such as ones on nested fields or using top-level operators.
+    pub fn new(doc: Document) -> Self {
+        Self {
+            doc,
+            _model: PhantomData,
+        }
+    }
+
+    /// Returns the underlying filter document.
+    pub fn into_document(self) -> Document {
+        self.doc
+    }
+}
+
+impl<M> From<ModelFilter<M>> for Document {
+    fn from(f: ModelFilter<M>) -> Document {
+        f.into_document()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bson_type_aliases() {
+        let aliases = [
+            (BsonTypeName::Double, "double"),
+            (BsonTypeName::String, "string"),
+            (BsonTypeName::Object, "object"),
+            (BsonTypeName::Array, "array"),
+            (BsonTypeName::BinData, "binData"),
+            (BsonTypeName::ObjectId, "objectId"),
+            (BsonTypeName::Bool, "bool"),
+            (BsonTypeName::Date, "date"),
+            (BsonTypeName::Null, "null"),
+            (BsonTypeName::Regex, "regex"),
+            (BsonTypeName::JavaScript, "javascript"),
+            (BsonTypeName::Int, "int"),
+            (BsonTypeName::Timestamp, "timestamp"),
+            (BsonTypeName::Long, "long"),
+            (BsonTypeName::Decimal, "decimal"),
+            (BsonTypeName::MinKey, "minKey"),
+            (BsonTypeName::MaxKey, "maxKey"),
+            (BsonTypeName::Number, "number"),
+        ];
+        for (t, alias) in aliases {
+            assert_eq!(Bson::from(t), Bson::String(alias.to_owned()));
+        }
+    }
+
+    #[test]
+    fn upsert() {
+        struct User;
+
+        let (update, options) = Upsert::<User>::new()
+            .set("name", "David")
+            .set("age", 35)
+            .set_on_insert("created_at", 100)
+            .build();
+        assert_eq!(
+            update,
+            doc! {
+                "$set": { "name": "David", "age": 35 },
+                "$setOnInsert": { "created_at": 100 },
+            }
+        );
+        assert_eq!(options.upsert, Some(true));
+
+        // A field is only part of the last operator it was set with
+        let (update, _) = Upsert::<User>::new()
+            .set("created_at", 1)
+            .set_on_insert("created_at", 2)
+            .build();
+        assert_eq!(update, doc! { "$setOnInsert": { "created_at": 2 } });
+
+        let (update, _) = Upsert::<User>::new().set("name", "David").build();
+        assert_eq!(update, doc! { "$set": { "name": "David" } });
+    }
+
+    #[test]
+    fn any_of_all_of() {
+        let conditions = vec![doc! { "a": 1 }, doc! { "b": { "$gt": 2 } }];
+        assert_eq!(
+            any_of(conditions.clone()),
+            doc! { "$or": [ { "a": 1 }, { "b": { "$gt": 2 } } ] }
+        );
+        assert_eq!(
+            all_of(conditions),
+            doc! { "$and": [ { "a": 1 }, { "b": { "$gt": 2 } } ] }
+        );
+
+        assert_eq!(any_of(None), doc! { "$expr": false });
+        assert_eq!(all_of(None), doc! {});
+    }
+}
@@ -1,14 +1,30 @@
 //! Repositories are abstraction over a specific mongo collection for a given `Model`
 
-use crate::{CollectionConfig, Model};
+use crate::change_stream::ModelChangeStream;
+use crate::cursor::{h_from_document, ModelCursor};
+use crate::index::{
+    h_list_indexes, h_run_command, h_run_command_with_selection_criteria, h_selection_criteria,
+};
+use crate::query::ModelFilter;
+use crate::{CollectionConfig, Index, IndexInfo, Indexes, Model, WithId};
 use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use futures_util::TryStreamExt;
 use mongodb::bson::oid::ObjectId;
-use mongodb::bson::{doc, from_document, to_bson, Document};
-use mongodb::error::Result;
+use mongodb::bson::{doc, from_document, to_bson, Bson, DateTime as BsonDateTime, Document};
+use mongodb::error::{
+    IndexedWriteError, Result, WriteConcernError, TRANSIENT_TRANSACTION_ERROR,
+    UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
 use mongodb::options::*;
-use serde::Deserialize;
+use mongodb::ClientSession;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::ops::Deref;
+use std::time::{Duration, Instant};
 
 /// Represents an individual update operation for the `bulk_update` function.
 #[derive(Debug)]
@@ -27,6 +43,40 @@ pub struct BulkUpdateResult {
     pub nb_modified: u64,
     #[serde(default)]
     pub upserted: Vec<BulkUpdateUpsertResult>,
+    /// Errors of the updates that failed, `IndexedWriteError::index` referring to the position in the updates.
+    /// Updates are ordered: the updates following the first failed one were not attempted.
+    #[serde(rename = "writeErrors", default)]
+    pub write_errors: Vec<IndexedWriteError>,
+    /// Set when updates were applied but the write concern could not be satisfied, for instance
+    /// when `w_timeout` expired before enough members acknowledged the writes.
+    /// Updates are not rolled back in such case.
+    #[serde(rename = "writeConcernError")]
+    pub write_concern_error: Option<WriteConcernError>,
+}
+
+impl BulkUpdateResult {
+    /// Add the result of a command sent for the updates starting at index `offset`.
+    fn h_merge(&mut self, other: BulkUpdateResult, offset: u64) {
+        self.nb_affected += other.nb_affected;
+        self.nb_modified += other.nb_modified;
+        self.upserted.extend(
+            other
+                .upserted
+                .into_iter()
+                .map(|upserted| BulkUpdateUpsertResult {
+                    index: upserted.index + offset,
+                    id: upserted.id,
+                }),
+        );
+        self.write_errors
+            .extend(other.write_errors.into_iter().map(|mut error| {
+                error.index += offset as usize;
+                error
+            }));
+        if self.write_concern_error.is_none() {
+            self.write_concern_error = other.write_concern_error;
+        }
+    }
 }
 
 /// Individual update result of a `bulk_update` operation.
@@ -38,6 +88,334 @@ pub struct BulkUpdateUpsertResult {
     pub id: ObjectId,
 }
 
+/// Position in a collection sorted by a field then by `_id`, used for keyset pagination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeysetCursor {
+    /// Value of the sort field of the last returned document
+    pub value: Bson,
+    /// `_id` of the last returned document, used as a tiebreaker
+    pub id: Bson,
+}
+
+impl KeysetCursor {
+    /// Cursor positioned after `doc`, `sort_field` being a possibly dotted path.
+    fn h_after(doc: &Document, sort_field: &str) -> Self {
+        Self {
+            value: h_get_path(doc, sort_field).cloned().unwrap_or(Bson::Null),
+            id: doc.get("_id").cloned().unwrap_or(Bson::Null),
+        }
+    }
+}
+
+/// Page of documents returned by `Repository::paginate_after`.
+#[derive(Debug)]
+pub struct KeysetPage<M> {
+    pub items: Vec<M>,
+    /// Cursor to pass to `Repository::paginate_after` to get the next page.
+    /// `None` when the last page has been reached.
+    pub next: Option<KeysetCursor>,
+}
+
+/// Returns whether write operations of clients created with the given options are retried once on
+/// network errors and failovers.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/core/retryable-writes/)
+///
+/// Retryable writes are enabled by default, and can only be configured at the client level
+/// (`retryWrites` in the connection string or `ClientOptions::retry_writes`): the driver doesn't
+/// allow overriding it per database or collection, and doesn't expose the options of an existing client.
+/// Raw commands, such as the one run by `Repository::bulk_update`, are never retried.
+pub fn retryable_writes_enabled(options: &ClientOptions) -> bool {
+    options.retry_writes.unwrap_or(true)
+}
+
+/// Returns whether a failed read is worth retrying: network errors, server selection failures, and
+/// command errors reported during failovers or by nodes shutting down or recovering.
+///
+/// Other errors, such as deserialization errors or command errors like `Unauthorized`, would fail
+/// the same way again and are not considered transient.
+pub fn is_transient_read_error(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::ErrorKind;
+
+    // Same codes as the driver uses to retry reads once by itself
+    const RETRYABLE_READ_CODES: [i32; 13] = [
+        11600, 11602, 10107, 13435, 13436, 189, 91, 7, 6, 89, 9001, 134, 262,
+    ];
+
+    match error.kind.as_ref() {
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } => true,
+        ErrorKind::ServerSelection { .. } => true,
+        ErrorKind::Command(err) => RETRYABLE_READ_CODES.contains(&err.code),
+        _ => false,
+    }
+}
+
+/// Retry policy of `Repository::find_one_retrying`.
+///
+/// Failed attempts are retried up to `max_retries` times while `retry_if` returns `true` for the error,
+/// waiting `initial_backoff` before the first retry, then twice as long before each subsequent retry,
+/// up to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Maximum number of retries, not counting the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_backoff: Duration,
+    /// Whether an error is worth retrying
+    pub retry_if: fn(&mongodb::error::Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    /// 3 retries, starting with a 100ms backoff capped to 2s, on errors matching `is_transient_read_error`.
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_if: is_transient_read_error,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry, starting at 0.
+    fn h_backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Maximum time spent retrying a `Repository::transaction`.
+pub const TRANSACTION_MAX_RETRY_TIME: Duration = Duration::from_secs(120);
+
+/// Page of results returned by `Repository::aggregate_paginated`.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Total number of documents matching the query, across all pages
+    pub total: u64,
+    /// Zero-based index of this page
+    pub page: u64,
+    pub per_page: u64,
+}
+
+/// Statistics of a collection, as returned by `Repository::stats`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/command/collStats/)
+///
+/// Fields returned by `collStats` vary with the server version and the storage engine,
+/// hence all of them are optional. Sizes are in bytes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CollectionStats {
+    /// Number of documents
+    pub count: Option<u64>,
+    /// Total uncompressed size of the documents
+    pub size: Option<u64>,
+    /// Storage allocated for the documents
+    #[serde(rename = "storageSize")]
+    pub storage_size: Option<u64>,
+    /// Average size of a document
+    #[serde(rename = "avgObjSize")]
+    pub avg_obj_size: Option<f64>,
+    /// Number of indexes
+    #[serde(rename = "nindexes")]
+    pub n_indexes: Option<u64>,
+    /// Total size of all indexes
+    #[serde(rename = "totalIndexSize")]
+    pub total_index_size: Option<u64>,
+    /// All remaining fields returned by the server
+    #[serde(flatten)]
+    pub other: Document,
+}
+
+/// Field storing the expiration date of documents inserted with `Repository::insert_expiring`.
+pub const EXPIRE_AT_FIELD: &str = "expireAt";
+
+/// Result of a `validate` command, as returned by `Repository::validate`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/command/validate/)
+///
+/// The output of `validate` varies with the server version and the storage engine: only the most
+/// stable fields are typed, everything else is kept in `other`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationReport {
+    /// Whether the collection is valid
+    pub valid: bool,
+    /// Warnings found during validation
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// Errors found during validation
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /// Number of invalid documents
+    #[serde(rename = "nInvalidDocuments")]
+    pub n_invalid_documents: Option<i64>,
+    /// Validation details of each index, by index name
+    #[serde(rename = "indexDetails")]
+    pub index_details: Option<Document>,
+    /// All remaining fields returned by the server
+    #[serde(flatten)]
+    pub other: Document,
+}
+
+/// Main figures of a query plan, extracted from the output of `Repository::explain`.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/explain-results/)
+///
+/// The explain format varies with the server version, the query engine and the deployment: classic
+/// and slot-based (`queryPlan`) winning plans are supported, and the first shard is used for sharded
+/// collections. Figures that can't be found are left unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExplainSummary {
+    /// Root stage of the winning plan (eg: `COLLSCAN`, `FETCH`, `PROJECTION_COVERED`)
+    pub stage: Option<String>,
+    /// Name of the first index used by the winning plan
+    pub index_name: Option<String>,
+    /// Number of documents fetched from the collection
+    pub docs_examined: Option<u64>,
+    /// Number of index keys scanned
+    pub keys_examined: Option<u64>,
+    /// Number of returned documents
+    pub n_returned: Option<u64>,
+}
+
+impl ExplainSummary {
+    /// Extract the summary from an `explain` output.
+    pub fn from_explain(explain: &Document) -> Self {
+        let mut query_planner = explain.get_document("queryPlanner").ok();
+        let mut execution_stats = explain.get_document("executionStats").ok();
+
+        // Sharded collections: the plan of each shard is listed
+        let shard_plan = query_planner
+            .and_then(|planner| planner.get_document("winningPlan").ok())
+            .and_then(|plan| plan.get_array("shards").ok())
+            .and_then(|shards| shards.first())
+            .and_then(Bson::as_document);
+        if let Some(shard_plan) = shard_plan {
+            query_planner = Some(shard_plan);
+        }
+        let shard_stats = execution_stats
+            .and_then(|stats| stats.get_document("executionStages").ok())
+            .and_then(|stages| stages.get_array("shards").ok())
+            .and_then(|shards| shards.first())
+            .and_then(Bson::as_document);
+        if let Some(shard_stats) = shard_stats {
+            execution_stats = Some(shard_stats);
+        }
+
+        let plan = query_planner
+            .and_then(|planner| planner.get_document("winningPlan").ok())
+            .map(|plan| plan.get_document("queryPlan").unwrap_or(plan));
+
+        Self {
+            stage: plan
+                .and_then(|plan| plan.get_str("stage").ok())
+                .map(str::to_owned),
+            index_name: plan.and_then(h_find_index_name),
+            docs_examined: execution_stats.and_then(|s| h_get_u64(s, "totalDocsExamined")),
+            keys_examined: execution_stats.and_then(|s| h_get_u64(s, "totalKeysExamined")),
+            n_returned: execution_stats.and_then(|s| h_get_u64(s, "nReturned")),
+        }
+    }
+
+    /// Whether the query was answered using an index only, without fetching any document.
+    pub fn is_covered(&self) -> bool {
+        self.index_name.is_some() && self.docs_examined == Some(0)
+    }
+}
+
+/// Main figures of an aggregation plan, as returned by `Repository::explain_aggregate`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AggregateExplainSummary {
+    /// Names of the stages run by the aggregation framework, starting with `$cursor` (the query feeding
+    /// the pipeline). Empty when the whole pipeline was pushed down to the query layer.
+    pub stages: Vec<String>,
+    /// Summary of the query feeding the pipeline: `index_name` tells whether the leading `$match`
+    /// and `$sort` stages are supported by an index
+    pub query: ExplainSummary,
+}
+
+impl AggregateExplainSummary {
+    /// Extract the summary from the `explain` output of an aggregation.
+    pub fn from_explain(explain: &Document) -> Self {
+        // Sharded collections: the plan of each shard is listed
+        let explain = explain
+            .get_document("shards")
+            .ok()
+            .and_then(|shards| shards.values().next())
+            .and_then(Bson::as_document)
+            .unwrap_or(explain);
+
+        let stages: Vec<&Document> = explain
+            .get_array("stages")
+            .map(|stages| stages.iter().filter_map(Bson::as_document).collect())
+            .unwrap_or_default();
+        let query = stages
+            .first()
+            .and_then(|stage| stage.get_document("$cursor").ok())
+            .unwrap_or(explain);
+
+        Self {
+            stages: stages
+                .iter()
+                .filter_map(|stage| stage.keys().next().cloned())
+                .collect(),
+            query: ExplainSummary::from_explain(query),
+        }
+    }
+}
+
+fn h_find_index_name(stage: &Document) -> Option<String> {
+    if let Ok(name) = stage.get_str("indexName") {
+        return Some(name.to_owned());
+    }
+    if let Ok(input) = stage.get_document("inputStage") {
+        return h_find_index_name(input);
+    }
+    stage
+        .get_array("inputStages")
+        .ok()?
+        .iter()
+        .filter_map(Bson::as_document)
+        .find_map(h_find_index_name)
+}
+
+fn h_get_u64(doc: &Document, key: &str) -> Option<u64> {
+    match doc.get(key)? {
+        Bson::Int32(v) => u64::try_from(*v).ok(),
+        Bson::Int64(v) => u64::try_from(*v).ok(),
+        Bson::Double(v) if *v >= 0.0 => Some(*v as u64),
+        _ => None,
+    }
+}
+
+/// Token required by `Repository::delete_all`, making full collection deletion explicit.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmDeleteAll;
+
+/// Result of `Repository::insert_many_unordered`.
+#[derive(Debug, Clone)]
+pub struct PartialInsertResult {
+    /// `_id` of each inserted document, keyed by the position of the document in the input
+    pub inserted_ids: HashMap<usize, Bson>,
+    /// Errors of documents that couldn't be inserted, each referring to the position of the document in the input
+    pub write_errors: Vec<IndexedWriteError>,
+    /// Set when the write concern could not be satisfied. Succeeded inserts are not rolled back in such case.
+    pub write_concern_error: Option<WriteConcernError>,
+}
+
+/// Behavior of `Repository::copy_to` when some documents of a batch can't be inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyErrorPolicy {
+    /// Stop at the first failing document and return the error
+    Abort,
+    /// Skip failing documents (duplicate keys, validation errors...) and keep copying
+    Continue,
+}
+
 /// Associate a `mongodb::Collection` and a specific `Model`.
 ///
 /// This type can safely be copied and passed around because `std::sync::Arc` is used internally.
@@ -46,6 +424,11 @@ pub struct BulkUpdateUpsertResult {
 pub struct Repository<M: Model> {
     db: mongodb::Database, // FIXME: temporary keep reference to database object for `bulk_update` operation
     coll: mongodb::Collection<M>,
+    comment: Option<String>,
+    batch_size: Option<u32>,
+    max_time: Option<Duration>,
+    default_sort: Option<Document>,
+    default_projection: Option<Document>,
 }
 
 impl<M: Model> Deref for Repository<M> {
@@ -60,6 +443,11 @@ impl<M: Model> Clone for Repository<M> {
         Self {
             db: self.db.clone(),
             coll: self.coll.clone_with_type(),
+            comment: self.comment.clone(),
+            batch_size: self.batch_size,
+            max_time: self.max_time,
+            default_sort: self.default_sort.clone(),
+            default_projection: self.default_projection.clone(),
         }
     }
 }
@@ -73,25 +461,318 @@ impl<M: Model> Repository<M> {
             db.collection(M::CollConf::collection_name())
         };
 
-        Self { db, coll }
+        Self {
+            db,
+            coll,
+            comment: None,
+            batch_size: None,
+            max_time: None,
+            default_sort: None,
+            default_projection: None,
+        }
     }
 
     /// Create a new repository with associated collection options (override `Model::coll_options`).
     pub fn new_with_options(db: mongodb::Database, options: CollectionOptions) -> Self {
         let coll = db.collection_with_options(M::CollConf::collection_name(), options);
-        Self { db, coll }
+        Self {
+            db,
+            coll,
+            comment: None,
+            batch_size: None,
+            max_time: None,
+            default_sort: None,
+            default_projection: None,
+        }
+    }
+
+    /// Create a new repository for the collection named `collection_name` instead of `M::collection_name`.
+    ///
+    /// This allows a single `Model` to be stored in multiple collections chosen at runtime
+    /// (e.g. one collection per tenant). `CollectionConfig::collection_options` still applies.
+    /// Indexes of such collections are synchronized using `sync_indexes_with_collection_name`.
+    pub fn new_with_collection_name(
+        db: mongodb::Database,
+        collection_name: impl Into<String>,
+    ) -> Self {
+        let collection_name = collection_name.into();
+        let coll = if let Some(options) = M::CollConf::collection_options() {
+            db.collection_with_options(&collection_name, options)
+        } else {
+            db.collection(&collection_name)
+        };
+
+        Self {
+            db,
+            coll,
+            comment: None,
+            batch_size: None,
+            max_time: None,
+            default_sort: None,
+            default_projection: None,
+        }
+    }
+
+    /// Returns a copy of this repository for the partition `M::collection_name` + `"_"` + `suffix`.
+    ///
+    /// Intended for append-only data manually partitioned into per-period collections (e.g. `events_2024_06`)
+    /// without declaring a `CollectionConfig` per partition. The copy keeps the read preference, read and write
+    /// concerns, as well as the settings of this repository (comment, batch size, max time, default sort and projection).
+    ///
+    /// The suffix is always appended to `M::collection_name`, even if this repository was created with
+    /// `new_with_collection_name`: use `new_with_collection_name` directly for names combining tenants and periods.
+    /// Indexes of each partition are synchronized using `sync_indexes_with_collection_name`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Event { kind: String }
+    /// # impl Model for Event { type CollConf = EventCollConf; }
+    /// # struct EventCollConf;
+    /// # impl CollectionConfig for EventCollConf { fn collection_name() -> &'static str { "events" } }
+    /// use mongodm::prelude::*;
+    /// use mongodm::sync_indexes_with_collection_name;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let june = db.repository::<Event>().for_partition("2024_06");
+    /// assert_eq!(june.name(), "events_2024_06");
+    ///
+    /// sync_indexes_with_collection_name::<EventCollConf>(&db, june.name())
+    ///     .await
+    ///     .unwrap();
+    /// june.insert_one(Event { kind: String::from("login") }).await.unwrap();
+    /// # }
+    /// ```
+    pub fn for_partition(&self, suffix: &str) -> Self {
+        let options = CollectionOptions::builder()
+            .selection_criteria(self.coll.selection_criteria().cloned())
+            .read_concern(self.coll.read_concern().cloned())
+            .write_concern(self.coll.write_concern().cloned())
+            .build();
+        let name = format!("{}_{}", M::CollConf::collection_name(), suffix);
+        let mut repo = self.clone();
+        repo.coll = self.db.collection_with_options(&name, options);
+        repo
+    }
+
+    /// Returns the names of the collections of the database starting with `prefix`, sorted alphabetically.
+    ///
+    /// Meant for tooling iterating over collections sharing a model, such as the partitions created with
+    /// `for_partition` (prefix `M::collection_name` + `"_"`) or per-tenant collections created with
+    /// `new_with_collection_name`. The prefix is matched literally and case-sensitively: regex metacharacters
+    /// (such as `.` or `*`) are escaped before being sent in the `listCollections` name filter. Views are
+    /// listed as well.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Event { kind: String }
+    /// # impl Model for Event { type CollConf = EventCollConf; }
+    /// # struct EventCollConf;
+    /// # impl CollectionConfig for EventCollConf { fn collection_name() -> &'static str { "events" } }
+    /// use mongodm::prelude::*;
+    /// use mongodm::sync_indexes_with_collection_name;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<Event>();
+    /// let prefix = format!("{}_", repository.collection_name());
+    /// for partition in repository.list_sibling_collections(&prefix).await.unwrap() {
+    ///     sync_indexes_with_collection_name::<EventCollConf>(&db, &partition)
+    ///         .await
+    ///         .unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub async fn list_sibling_collections(&self, prefix: &str) -> Result<Vec<String>> {
+        let filter = doc! { "name": { "$regex": format!("^{}", h_escape_regex(prefix)) } };
+        let mut names = self.db.list_collection_names().filter(filter).await?;
+        names.sort();
+        Ok(names)
     }
 
     /// Returns associated `M::collection_name`.
+    ///
+    /// This may differ from the actual collection name for repositories created with
+    /// `new_with_collection_name`: use `name` from the underlying collection to get the latter.
     pub fn collection_name(&self) -> &'static str {
         M::CollConf::collection_name()
     }
 
+    /// Returns the namespace of the underlying collection, as `"{database}.{collection}"`.
+    ///
+    /// Namespaces identify collections in server logs, profiler entries and `currentOp` output, and in
+    /// administrative commands such as `renameCollection` or `shardCollection`. Note that `$lookup` only joins
+    /// collections of the same database (cross-database joins are limited to Atlas Data Federation): use
+    /// `$merge` (see `merge_into`), which accepts a target in another database, to write across databases.
+    ///
+    /// ```
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo() {
+    /// // No connection is established until an operation is run
+    /// let client = MongoClient::with_options(MongoClientOptions::default()).unwrap();
+    /// let repository = client.database("app").repository::<User>();
+    /// assert_eq!(repository.namespace(), "app.user");
+    /// assert_eq!(repository.for_partition("2024").namespace(), "app.user_2024");
+    /// # }
+    /// # let rt = tokio::runtime::Runtime::new().unwrap();
+    /// # rt.block_on(demo());
+    /// ```
+    pub fn namespace(&self) -> String {
+        self.coll.namespace().to_string()
+    }
+
     /// Returns underlying `mongodb::Collection`.
     pub fn get_underlying(&self) -> mongodb::Collection<M> {
         self.coll.clone_with_type()
     }
 
+    /// Returns a copy of this repository attaching a `comment` to the operations it issues,
+    /// making them identifiable in the database profiler, logs and `currentOp` output.
+    ///
+    /// The comment is attached by MongODM helpers only: `find_with_defaults`, `find_by_ids`,
+    /// `find_by_ids_ordered`, `paginate_after`, `aggregate_as`, `aggregate_models`, `aggregate_one`, `aggregate_paginated`,
+    /// `update_one_returning_old`, `upsert_and_fetch`, `upsert_returning_old`, as well as the `find_models`,
+    /// `count` and `exists` methods of `RepositoryBackend` (and their `_with_hint` variants).
+    /// A comment explicitly provided in options takes precedence. Methods of the underlying
+    /// `mongodb::Collection` are not affected.
+    pub fn with_comment(&self, comment: impl Into<String>) -> Self {
+        let mut repo = self.clone();
+        repo.comment = Some(comment.into());
+        repo
+    }
+
+    /// Returns a copy of this repository requesting batches of `batch_size` documents from the server
+    /// when iterating over results of MongODM find helpers.
+    ///
+    /// Smaller batches bound the memory used by the client, larger batches save network round trips.
+    /// This is unrelated to `limit`, which bounds the total number of returned documents: when both are
+    /// set, the server stops sending batches once `limit` documents have been returned.
+    ///
+    /// Applies to `find_with_defaults`, `find_by_ids`, `find_by_ids_ordered`, `paginate_after`, `aggregate_as`
+    /// and `RepositoryBackend::find_models`. A batch size explicitly provided in options takes precedence.
+    pub fn with_batch_size(&self, batch_size: u32) -> Self {
+        let mut repo = self.clone();
+        repo.batch_size = Some(batch_size);
+        repo
+    }
+
+    /// Returns a copy of this repository limiting the execution time of each operation it issues on the server.
+    ///
+    /// Operations exceeding the limit fail with a `MaxTimeMSExpired` command error (code 50),
+    /// which is useful to fail fast on user-facing endpoints rather than holding a connection.
+    ///
+    /// Applies to the same helpers as `with_comment`. A limit explicitly provided in options takes precedence.
+    /// The limit covers server-side processing only, not network latency nor the time spent iterating
+    /// over a cursor on the client side.
+    pub fn with_max_time(&self, max_time: Duration) -> Self {
+        let mut repo = self.clone();
+        repo.max_time = Some(max_time);
+        repo
+    }
+
+    /// Returns a copy of this repository sorting results of MongODM find helpers by `sort` when none is provided.
+    ///
+    /// This overrides `CollectionConfig::default_sort` for this view only, and applies wherever the latter
+    /// does (`find_with_defaults`, `find_as`, `RepositoryBackend::find_models`...). A sort explicitly
+    /// provided in options takes precedence.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     last_seen: BsonDateTime,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// // Configured once, for instance when building the application state
+    /// let recent_users = db
+    ///     .repository::<User>()
+    ///     .read_secondary_preferred()
+    ///     .with_default_sort(doc! { f!(last_seen in User): -1 })
+    ///     .with_default_projection(doc! { f!(name in User): 1, f!(last_seen in User): 1 });
+    ///
+    /// let users = recent_users.find_models(doc! {}).await.unwrap();
+    /// # }
+    /// ```
+    pub fn with_default_sort(&self, sort: Document) -> Self {
+        let mut repo = self.clone();
+        repo.default_sort = Some(sort);
+        repo
+    }
+
+    /// Returns a copy of this repository overriding `CollectionConfig::default_projection` for this view only.
+    ///
+    /// See `with_default_sort`. A projection explicitly provided in options takes precedence.
+    pub fn with_default_projection(&self, projection: Document) -> Self {
+        let mut repo = self.clone();
+        repo.default_projection = Some(projection);
+        repo
+    }
+
+    /// Returns a copy of this repository using the given selection criteria (read preference) for reads.
+    ///
+    /// Unlike other views, this applies to all read operations, including methods of the underlying
+    /// `mongodb::Collection`. A selection criteria explicitly provided in options takes precedence.
+    /// Read and write concerns of the collection are kept.
+    pub fn with_selection_criteria(&self, selection_criteria: SelectionCriteria) -> Self {
+        let options = CollectionOptions::builder()
+            .selection_criteria(selection_criteria)
+            .read_concern(self.coll.read_concern().cloned())
+            .write_concern(self.coll.write_concern().cloned())
+            .build();
+        let mut repo = self.clone();
+        repo.coll = self.db.collection_with_options(self.coll.name(), options);
+        repo
+    }
+
+    /// Returns a copy of this repository reading from secondaries when available, from the primary otherwise.
+    ///
+    /// Shorthand for `with_selection_criteria` with `ReadPreference::SecondaryPreferred`.
+    /// Secondaries may lag behind the primary: recent writes may not be visible yet.
+    pub fn read_secondary_preferred(&self) -> Self {
+        self.with_selection_criteria(SelectionCriteria::ReadPreference(
+            ReadPreference::SecondaryPreferred {
+                options: Default::default(),
+            },
+        ))
+    }
+
+    fn h_selection_criteria(&self) -> SelectionCriteria {
+        h_selection_criteria(self.coll.selection_criteria())
+    }
+
+    fn h_comment(&self) -> Option<Bson> {
+        self.comment.clone().map(Bson::String)
+    }
+
     /// Convert this repository to use another `Model`. Only compiles if both `Model::CollConf` are identicals.
     ///
     /// # Example
@@ -225,6 +906,11 @@ impl<M: Model> Repository<M> {
         Repository {
             db: self.db,
             coll: self.coll.clone_with_type(),
+            comment: self.comment,
+            batch_size: self.batch_size,
+            max_time: self.max_time,
+            default_sort: self.default_sort,
+            default_projection: self.default_projection,
         }
     }
 
@@ -233,6 +919,16 @@ impl<M: Model> Repository<M> {
     /// This will be removed once support for bulk update is added to the official driver.
     /// [see](https://jira.mongodb.org/browse/RUST-531) for tracking progress on this feature in the official driver.
     ///
+    /// Updates are sent as a raw `update` command, which the driver never retries: unlike native write
+    /// operations, a network error or a failover returns an error even when retryable writes are enabled
+    /// (see `retryable_writes_enabled`). The command may or may not have been applied in such case.
+    ///
+    /// Updates exceeding the limits of a single command (100,000 operations or 16 MiB) are split into multiple
+    /// commands sent sequentially, and their results are summed up (`BulkUpdateUpsertResult::index` always refers
+    /// to the position in `updates`). Updates are ordered: the server stops at the first failing update, and the
+    /// following commands are not sent. Failures are reported in `BulkUpdateResult::write_errors`, indexed by position
+    /// in `updates` as well: all the updates after the first one listed there were not attempted.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -282,15 +978,32 @@ impl<M: Model> Repository<M> {
     {
         self.coll.bulk_update(&self.db, updates).await
     }
-}
 
-/// MongODM-provided utilities functions on `mongodb::Collection<M>`.
-#[async_trait]
-pub trait CollectionExt {
-    /// Apply multiple update operations in bulk.
+    /// Apply multiple update operations in bulk using the given write concern.
     ///
-    /// This will be removed once support for bulk update is added to the official driver.
-    /// [see](https://jira.mongodb.org/browse/RUST-531) for tracking progress on this feature in the official driver.
+    /// See `CollectionExt::bulk_update_with_write_concern`.
+    pub async fn bulk_update_with_write_concern<V, U>(
+        &self,
+        updates: V,
+        write_concern: WriteConcern,
+    ) -> Result<BulkUpdateResult>
+    where
+        V: Borrow<Vec<U>> + Send + Sync,
+        U: Borrow<BulkUpdate> + Send + Sync,
+    {
+        self.coll
+            .bulk_update_with_write_concern(&self.db, updates, write_concern)
+            .await
+    }
+
+    /// Count documents matching the filter, using the collection metadata when there is no filter.
+    ///
+    /// Without filter (`None` or an empty document), `estimatedDocumentCount` is used: the count is read from
+    /// the collection metadata without scanning anything, but it is approximate. It may be off after an unclean
+    /// shutdown, includes orphaned documents on sharded clusters, and is not supported in transactions.
+    /// Otherwise, `countDocuments` returns the exact number of matching documents (both use this repository's
+    /// comment and max time). The driver forbids filters on estimated counts: use `RepositoryBackend::count`
+    /// for an exact count of the whole collection.
     ///
     /// # Example
     ///
@@ -298,39 +1011,2062 @@ pub trait CollectionExt {
     /// # use serde::{Serialize, Deserialize};
     /// # #[derive(Serialize, Deserialize)]
     /// # struct User {
-    /// #     name: String,
-    /// #     age: i64,
+    /// #     age: i32,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
     /// # }
     /// use mongodm::prelude::*;
-    /// /* ... */
-    /// # async fn demo(_db: mongodb::Database) {
-    /// let db: mongodb::Database; /* exists */
-    /// # db = _db;
-    /// let collection = db.collection::<User>("user");
-    /// /* ... */
-    /// let bulk_update_res = collection
-    ///     .bulk_update(&db, &vec![
-    ///         &BulkUpdate {
-    ///             query: doc! { f!(name in User): "Dane" },
-    ///             update: doc! { Set: { f!(age in User): 12 } },
-    ///             options: None,
-    ///         },
-    ///         &BulkUpdate {
-    ///             query: doc! { f!(name in User): "David" },
-    ///             update: doc! { Set: { f!(age in User): 30 } },
-    ///             options: None,
-    ///         },
-    ///     ])
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<User>();
+    /// // Fast, approximate
+    /// let total = repository.smart_count(None).await.unwrap();
+    /// // Exact
+    /// let adults = repository
+    ///     .smart_count(doc! { f!(age in User): { GreaterThanEqual: 18 } })
     ///     .await
     ///     .unwrap();
-    /// assert_eq!(bulk_update_res.nb_affected, 2);
-    /// assert_eq!(bulk_update_res.nb_modified, 2);
     /// # }
     /// ```
-    async fn bulk_update<V, U>(
-        &self,
-        db: &mongodb::Database,
+    pub async fn smart_count(&self, filter: impl Into<Option<Document>>) -> Result<u64> {
+        match filter.into().filter(|filter| !filter.is_empty()) {
+            None => {
+                let options = EstimatedDocumentCountOptions::builder()
+                    .comment(self.h_comment())
+                    .max_time(self.max_time)
+                    .build();
+                self.coll
+                    .estimated_document_count()
+                    .with_options(options)
+                    .await
+            }
+            Some(filter) => RepositoryBackend::count(self, filter).await,
+        }
+    }
+
+    /// Find documents matching the filter, applying defaults from `CollectionConfig` for unset options.
+    ///
+    /// `CollectionConfig::default_sort` and `CollectionConfig::default_projection` are used unless
+    /// a sort or a projection is explicitly provided in `options`, or overridden for this repository
+    /// with `with_default_sort` and `with_default_projection`.
+    pub async fn find_with_defaults(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<ModelCursor<M>> {
+        let cursor = self
+            .coll
+            .clone_with_type::<Document>()
+            .find(filter)
+            .with_options(self.h_find_options(options.into()))
+            .await?;
+        Ok(ModelCursor::new(cursor))
+    }
+
+    /// Same as `find_with_defaults`, but also returns the number of documents the cursor is expected to yield.
+    ///
+    /// Meant for progress reporting of long scans. The total comes from a `count_documents` run before
+    /// the find and honoring `skip`, `limit`, `hint` and `collation` of `options`: it costs an extra round
+    /// trip and, for large collections, a scan of the matching index entries or documents.
+    /// The count is a snapshot: documents inserted or deleted concurrently make it drift from the actual
+    /// number of yielded documents, so it should be treated as an estimate.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let (total, mut users) = db
+    ///     .repository::<User>()
+    ///     .find_with_total(doc! {}, None)
+    ///     .await
+    ///     .unwrap();
+    /// let mut done = 0;
+    /// while let Some(user) = users.next().await {
+    ///     let _user = user.unwrap();
+    ///     done += 1;
+    ///     println!("{}/{}", done, total.max(done));
+    /// }
+    /// # }
+    /// ```
+    pub async fn find_with_total(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<(u64, ModelCursor<M>)> {
+        let options = self.h_find_options(options.into());
+        let count_options = CountOptions::builder()
+            .skip(options.skip)
+            .limit(options.limit.filter(|l| *l != 0).map(i64::unsigned_abs))
+            .hint(options.hint.clone())
+            .collation(options.collation.clone())
+            .comment(options.comment.clone())
+            .max_time(options.max_time)
+            .build();
+        let total = self
+            .coll
+            .count_documents(filter.clone())
+            .with_options(count_options)
+            .await?;
+        let cursor = self
+            .coll
+            .clone_with_type::<Document>()
+            .find(filter)
+            .with_options(options)
+            .await?;
+        Ok((total, ModelCursor::new(cursor)))
+    }
+
+    /// Same as `find_with_defaults`, but with a filter statically tied to `M` (see `filter!`).
+    pub async fn find_filtered(
+        &self,
+        filter: ModelFilter<M>,
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<ModelCursor<M>> {
+        self.find_with_defaults(filter.into_document(), options)
+            .await
+    }
+
+    /// Find the first document matching a filter statically tied to `M` (see `filter!`).
+    ///
+    /// `CollectionConfig::default_sort` and `CollectionConfig::default_projection` are applied.
+    pub async fn find_one_filtered(&self, filter: ModelFilter<M>) -> Result<Option<M>> {
+        let options = FindOptions::builder().limit(1).build();
+        self.find_with_defaults(filter.into_document(), options)
+            .await?
+            .try_next()
+            .await
+    }
+
+    /// Find documents matching the filter, keeping only the fields of `projection`, and stream them as `T`.
+    ///
+    /// Meant for list endpoints returning a few fields of many documents: only projected fields are sent
+    /// by the server, and they are deserialized into a lightweight `T` instead of complete models.
+    /// The projection must agree with `T`: fields of `T` which are not projected must be `Option` or
+    /// `#[serde(default)]`, otherwise deserialization fails. Note that `_id` is returned unless explicitly
+    /// excluded with `"_id": 0`, which matters for `#[serde(deny_unknown_fields)]` structs: `project_exclude_id!`
+    /// builds such projections from checked field names.
+    ///
+    /// `projection` replaces the projection of `options` and `CollectionConfig::default_projection`,
+    /// other defaults are applied as done by `find_with_defaults`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     age: i32,
+    /// #     bio: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct UserListItem {
+    ///     name: String,
+    /// }
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let mut items = db
+    ///     .repository::<User>()
+    ///     .find_as::<UserListItem>(
+    ///         doc! { f!(age in User): { GreaterThan: 18 } },
+    ///         doc! { "_id": 0, f!(name in User): 1 },
+    ///         None,
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    /// while let Some(item) = items.next().await {
+    ///     println!("{}", item.unwrap().name);
+    /// }
+    /// # }
+    /// ```
+    pub async fn find_as<T>(
+        &self,
+        filter: Document,
+        projection: Document,
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<ModelCursor<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut options = options.into().unwrap_or_default();
+        options.projection = Some(projection);
+        let cursor = self
+            .coll
+            .clone_with_type::<Document>()
+            .find(filter)
+            .with_options(self.h_find_options(Some(options)))
+            .await?;
+        Ok(ModelCursor::new(cursor))
+    }
+
+    fn h_find_options(&self, options: Option<FindOptions>) -> FindOptions {
+        let mut options = options.unwrap_or_default();
+        if options.sort.is_none() {
+            options.sort = self.default_sort.clone().or_else(M::CollConf::default_sort);
+        }
+        if options.projection.is_none() {
+            options.projection = self
+                .default_projection
+                .clone()
+                .or_else(M::CollConf::default_projection);
+        }
+        if options.comment.is_none() {
+            options.comment = self.h_comment();
+        }
+        if options.batch_size.is_none() {
+            options.batch_size = self.batch_size;
+        }
+        if options.max_time.is_none() {
+            options.max_time = self.max_time;
+        }
+        options
+    }
+
+    /// Find the document matching the filter with the greatest value for `sort_field`.
+    ///
+    /// `sort_field` should be covered by an index, otherwise all matching documents are sorted in memory
+    /// by the server.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Login {
+    /// #     user: String,
+    /// #     at: BsonDateTime,
+    /// # }
+    /// # impl Model for Login {
+    /// #     type CollConf = LoginCollConf;
+    /// # }
+    /// # struct LoginCollConf;
+    /// # impl CollectionConfig for LoginCollConf {
+    /// #     fn collection_name() -> &'static str { "login" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// /* ... */
+    /// # async fn demo(db: mongodb::Database) {
+    /// let last_login = db
+    ///     .repository::<Login>()
+    ///     .find_latest(doc! { f!(user in Login): "David" }, f!(at in Login))
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn find_latest(&self, filter: Document, sort_field: &str) -> Result<Option<M>> {
+        self.h_find_first(filter, doc! { sort_field: -1 }).await
+    }
+
+    /// Find the document matching the filter with the lowest value for `sort_field`. See `find_latest`.
+    pub async fn find_earliest(&self, filter: Document, sort_field: &str) -> Result<Option<M>> {
+        self.h_find_first(filter, doc! { sort_field: 1 }).await
+    }
+
+    async fn h_find_first(&self, filter: Document, sort: Document) -> Result<Option<M>> {
+        let options = FindOptions::builder().sort(sort).limit(1).build();
+        self.find_with_defaults(filter, options)
+            .await?
+            .try_next()
+            .await
+    }
+
+    /// Fetch a page of documents using keyset pagination.
+    ///
+    /// Documents are sorted by `sort_field` then by `_id` to break ties, and only documents located
+    /// after the given cursor are returned. Unlike `skip`, the cost of fetching a page doesn't grow
+    /// with its position, provided a compound index on `sort_field` and `_id` exists. `sort_field` may be
+    /// a dotted path to a field of an embedded document.
+    ///
+    /// `limit` must be greater than 0: an error is returned otherwise (the server treats a limit of 0 as no limit).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     age: i64,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// /* ... */
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<User>();
+    ///
+    /// let mut after = None;
+    /// loop {
+    ///     let page = repository
+    ///         .paginate_after(doc! {}, f!(age in User), after, 50)
+    ///         .await
+    ///         .unwrap();
+    ///     /* ... use page.items ... */
+    ///     match page.next {
+    ///         Some(next) => after = Some(next),
+    ///         None => break,
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn paginate_after(
+        &self,
+        filter: Document,
+        sort_field: &str,
+        after: Option<KeysetCursor>,
+        limit: u64,
+    ) -> Result<KeysetPage<M>> {
+        if limit == 0 {
+            return Err(
+                std::io::Error::other("keyset pagination limit must be greater than 0").into(),
+            );
+        }
+
+        let by_id = sort_field == "_id";
+
+        let filter = match after {
+            Some(after) if by_id => doc! { "$and": [filter, { "_id": { "$gt": after.id } }] },
+            Some(after) => doc! {
+                "$and": [
+                    filter,
+                    { "$or": [
+                        { sort_field: { "$gt": &after.value } },
+                        { sort_field: after.value, "_id": { "$gt": after.id } },
+                    ] },
+                ]
+            },
+            None => filter,
+        };
+
+        let sort = if by_id {
+            doc! { "_id": 1 }
+        } else {
+            doc! { sort_field: 1, "_id": 1 }
+        };
+
+        let options = FindOptions::builder()
+            .sort(sort)
+            .limit(i64::try_from(limit).unwrap_or(i64::MAX))
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .batch_size(self.batch_size)
+            .build();
+        let docs: Vec<Document> = self
+            .coll
+            .clone_with_type::<Document>()
+            .find(filter)
+            .with_options(options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let next = match docs.last() {
+            Some(last) if docs.len() as u64 == limit => {
+                Some(KeysetCursor::h_after(last, sort_field))
+            }
+            _ => None,
+        };
+
+        let mut items = Vec::with_capacity(docs.len());
+        for doc in docs {
+            items.push(h_from_document(doc)?);
+        }
+
+        Ok(KeysetPage { items, next })
+    }
+
+    /// Run an aggregation pipeline, deserializing the resulting documents into `T`.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/aggregate/)
+    ///
+    /// The pipeline is typically built with the `pipeline!` macro, whose output is accepted as is.
+    /// Unlike `aggregate` from the underlying `mongodb::Collection` (reachable through `Deref`), which yields
+    /// raw `Document`s, results are typed and repository settings apply. See `aggregate_models` for pipelines
+    /// keeping the shape of `M`.
+    ///
+    /// Comment, batch size and max time set on this repository are used unless provided in `options`.
+    ///
+    /// Each `$group` or blocking `$sort` stage is limited to 100 megabytes of memory. Large pipelines
+    /// exceeding this limit fail unless `allow_disk_use` is set, in which case the stage writes temporary
+    /// files to disk: the pipeline succeeds but is significantly slower, so consider adding an index
+    /// or filtering earlier instead when possible.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Login {
+    /// #     user: String,
+    /// # }
+    /// # impl Model for Login {
+    /// #     type CollConf = LoginCollConf;
+    /// # }
+    /// # struct LoginCollConf;
+    /// # impl CollectionConfig for LoginCollConf {
+    /// #     fn collection_name() -> &'static str { "login" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use futures_util::TryStreamExt;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct LoginCount {
+    ///     #[serde(rename = "_id")]
+    ///     user: String,
+    ///     count: i32,
+    /// }
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let counts: Vec<LoginCount> = db
+    ///     .repository::<Login>()
+    ///     .aggregate_as(
+    ///         pipeline! [
+    ///             Group: { "_id": f!(@user in Login), "count": { Sum: 1 } },
+    ///             Sort: { "count": -1 },
+    ///         ],
+    ///         MongoAggregateOptions::builder().allow_disk_use(true).build(),
+    ///     )
+    ///     .await
+    ///     .unwrap()
+    ///     .try_collect()
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn aggregate_as<T>(
+        &self,
+        pipeline: impl IntoIterator<Item = Document>,
+        options: impl Into<Option<AggregateOptions>>,
+    ) -> Result<ModelCursor<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let cursor = self
+            .coll
+            .aggregate(pipeline)
+            .with_options(self.h_aggregate_options(options.into()))
+            .await?;
+        Ok(ModelCursor::new(cursor))
+    }
+
+    /// Run an aggregation pipeline yielding documents of the shape of `M`, such as `$match`, `$sort` or `$sample` stages.
+    ///
+    /// Same as `aggregate_as::<M>`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let mut sample = db
+    ///     .repository::<User>()
+    ///     .aggregate_models(pipeline! [ Sample: { "size": 10 } ], None)
+    ///     .await
+    ///     .unwrap();
+    /// while let Some(user) = sample.next().await {
+    ///     println!("{}", user.unwrap().name);
+    /// }
+    /// # }
+    /// ```
+    pub async fn aggregate_models(
+        &self,
+        pipeline: impl IntoIterator<Item = Document>,
+        options: impl Into<Option<AggregateOptions>>,
+    ) -> Result<ModelCursor<M>> {
+        self.aggregate_as(pipeline, options).await
+    }
+
+    /// Run an aggregation pipeline and return its first resulting document, deserialized into `T`.
+    ///
+    /// See `aggregate_as` regarding `options` and `allow_disk_use`.
+    pub async fn aggregate_one<T>(
+        &self,
+        pipeline: impl IntoIterator<Item = Document>,
+        options: impl Into<Option<AggregateOptions>>,
+    ) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        self.aggregate_as(pipeline, options).await?.try_next().await
+    }
+
+    /// Count documents matching `filter`, grouped by the value of `field`.
+    ///
+    /// Runs a `$match` and `$group` aggregation. Documents where `field` is missing or `null` are counted
+    /// under the `None` key. Values of `field` that can't be deserialized as `K` make the whole call fail.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     country: Option<String>,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use std::collections::HashMap;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let users_per_country: HashMap<Option<String>, u64> = db
+    ///     .repository::<User>()
+    ///     .count_by(f!(country in User), None)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn count_by<K>(
+        &self,
+        field: &str,
+        filter: impl Into<Option<Document>>,
+    ) -> Result<HashMap<Option<K>, u64>>
+    where
+        K: DeserializeOwned + Eq + Hash,
+    {
+        #[derive(Deserialize)]
+        struct Group<K> {
+            #[serde(rename = "_id")]
+            key: Option<K>,
+            count: u64,
+        }
+
+        let pipeline = vec![
+            doc! { "$match": filter.into().unwrap_or_default() },
+            doc! { "$group": { "_id": format!("${}", field), "count": { "$sum": 1 } } },
+        ];
+        let mut cursor = self.aggregate_as::<Group<K>>(pipeline, None).await?;
+
+        let mut counts = HashMap::new();
+        while let Some(group) = cursor.try_next().await? {
+            counts.insert(group.key, group.count);
+        }
+        Ok(counts)
+    }
+
+    fn h_aggregate_options(&self, options: Option<AggregateOptions>) -> AggregateOptions {
+        let mut options = options.unwrap_or_default();
+        if options.comment.is_none() {
+            options.comment = self.h_comment();
+        }
+        if options.batch_size.is_none() {
+            options.batch_size = self.batch_size;
+        }
+        if options.max_time.is_none() {
+            options.max_time = self.max_time;
+        }
+        options
+    }
+
+    /// Fetch a page of results along with the total count of matching documents in a single round trip.
+    ///
+    /// A `$facet` stage is used to run both the `$count` and the `$skip` / `$limit` branches on the
+    /// documents matching `match_stage` and sorted by `sort` (an empty `sort` leaves the natural order).
+    /// `page` is zero-based.
+    ///
+    /// Unlike `paginate_after`, the server still has to walk through all skipped documents.
+    pub async fn aggregate_paginated<T>(
+        &self,
+        match_stage: Document,
+        sort: Document,
+        page: u64,
+        per_page: u64,
+    ) -> Result<Page<T>>
+    where
+        T: DeserializeOwned,
+    {
+        if per_page == 0 {
+            return Err(std::io::Error::other("per_page must be greater than zero").into());
+        }
+
+        let skip = i64::try_from(page.saturating_mul(per_page)).unwrap_or(i64::MAX);
+        let limit = i64::try_from(per_page).unwrap_or(i64::MAX);
+
+        let mut pipeline = vec![doc! { "$match": match_stage }];
+        if !sort.is_empty() {
+            pipeline.push(doc! { "$sort": sort });
+        }
+        pipeline.push(doc! {
+            "$facet": {
+                "metadata": [{ "$count": "total" }],
+                "data": [{ "$skip": skip }, { "$limit": limit }],
+            }
+        });
+
+        let mut cursor = self
+            .coll
+            .aggregate(pipeline)
+            .with_options(self.h_aggregate_options(None))
+            .await?;
+        let mut facet = cursor.try_next().await?.unwrap_or_default();
+
+        // `$count` yields no document at all when nothing matches
+        let total = match facet.get_array("metadata").ok().and_then(|m| m.first()) {
+            Some(Bson::Document(metadata)) => match metadata.get("total") {
+                Some(Bson::Int32(total)) => *total as u64,
+                Some(Bson::Int64(total)) => *total as u64,
+                _ => 0,
+            },
+            _ => 0,
+        };
+
+        let mut items = Vec::new();
+        if let Some(Bson::Array(data)) = facet.remove("data") {
+            items.reserve(data.len());
+            for doc in data {
+                match doc {
+                    Bson::Document(doc) => items.push(h_from_document(doc)?),
+                    other => {
+                        return Err(std::io::Error::other(format!(
+                            "unexpected value in aggregation results: {}",
+                            other
+                        ))
+                        .into())
+                    }
+                }
+            }
+        }
+
+        Ok(Page {
+            items,
+            total,
+            page,
+            per_page,
+        })
+    }
+
+    /// Run `f` in a transaction, retrying on transient errors as recommended by the mongo documentation.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/core/transactions-in-applications/)
+    ///
+    /// A session is started from the client of the associated database and must be passed to each
+    /// operation of the transaction. The whole transaction is retried (calling `f` again) on errors
+    /// labeled `TransientTransactionError`, and the commit alone on errors labeled
+    /// `UnknownTransactionCommitResult`. Retries stop after `TRANSACTION_MAX_RETRY_TIME`,
+    /// returning the last error.
+    ///
+    /// Transactions require a replica set or a sharded cluster.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     age: i64,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// /* ... */
+    /// # async fn demo(db: mongodb::Database) -> Result<(), MongoError> {
+    /// let repository = db.repository::<User>();
+    ///
+    /// let count = repository
+    ///     .transaction(|session| {
+    ///         let repository = repository.clone();
+    ///         Box::pin(async move {
+    ///             repository
+    ///                 .insert_one(User { name: "David".to_owned(), age: 35 })
+    ///                 .session(&mut *session)
+    ///                 .await?;
+    ///             repository.count_documents(doc! {}).session(session).await
+    ///         })
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn transaction<F, T>(&self, mut f: F) -> Result<T>
+    where
+        F: for<'a> FnMut(&'a mut ClientSession) -> BoxFuture<'a, Result<T>> + Send,
+        T: Send,
+    {
+        let start = Instant::now();
+        let mut session = self.db.client().start_session().await?;
+
+        'transaction: loop {
+            session.start_transaction().await?;
+
+            let ret = match f(&mut session).await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    // Transaction may already have been aborted by the server or by `f`
+                    let _ = session.abort_transaction().await;
+                    if e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                        && start.elapsed() < TRANSACTION_MAX_RETRY_TIME
+                    {
+                        continue 'transaction;
+                    }
+                    return Err(e);
+                }
+            };
+
+            loop {
+                match session.commit_transaction().await {
+                    Ok(()) => return Ok(ret),
+                    Err(e) if start.elapsed() >= TRANSACTION_MAX_RETRY_TIME => return Err(e),
+                    Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => continue,
+                    Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) => {
+                        continue 'transaction
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Find a single document matching the filter, retrying on transient errors according to `policy`.
+    ///
+    /// Reads have no side effect and are safe to retry. The driver already retries a failed read once
+    /// (unless `retryReads` is disabled) right away: this adds retries spaced out with an exponential
+    /// backoff, to ride out longer network interruptions or elections. By default, errors matching
+    /// `is_transient_read_error` are retried: network errors, server selection failures and failover
+    /// related command errors. Deserialization errors and other command errors are returned immediately,
+    /// as is the last error once retries are exhausted.
+    ///
+    /// Keep in mind that each attempt may wait up to the server selection timeout of the client
+    /// (30 seconds by default) before failing.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use mongodm::RetryPolicy;
+    /// use std::time::Duration;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let policy = RetryPolicy {
+    ///     max_retries: 5,
+    ///     initial_backoff: Duration::from_millis(50),
+    ///     ..Default::default()
+    /// };
+    /// let user = db
+    ///     .repository::<User>()
+    ///     .find_one_retrying(doc! { f!(name in User): "David" }, policy)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn find_one_retrying(
+        &self,
+        filter: Document,
+        policy: RetryPolicy,
+    ) -> Result<Option<M>> {
+        let defaults = self.h_find_options(None);
+        let options = FindOneOptions::builder()
+            .sort(defaults.sort)
+            .projection(defaults.projection)
+            .comment(defaults.comment)
+            .max_time(defaults.max_time)
+            .build();
+        let coll = self.coll.clone_with_type::<Document>();
+
+        let mut retry = 0;
+        let found = loop {
+            match coll
+                .find_one(filter.clone())
+                .with_options(options.clone())
+                .await
+            {
+                Ok(found) => break found,
+                Err(e) if retry < policy.max_retries && (policy.retry_if)(&e) => {
+                    tokio::time::sleep(policy.h_backoff(retry)).await;
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        // Deserialized outside of the loop: failures are not transient
+        found.map(h_from_document).transpose()
+    }
+
+    /// Fetch the document whose `_id` is `id`, or `None` if there is none.
+    ///
+    /// Shorthand for `find_one(doc! { "_id": id })`, for the common case of models keyed by an `ObjectId`.
+    /// Documents keyed by other types (strings, integers, compound ids) are fetched with `find_one` directly.
+    /// Unlike `find_one`, the comment, max time and default projection of this repository apply.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<User>();
+    /// let id = repository.insert_one(User { name: String::from("David") }).await.unwrap().inserted_id;
+    /// let user = repository.get(id.as_object_id().unwrap()).await.unwrap();
+    /// assert_eq!(user.unwrap().name, "David");
+    /// # }
+    /// ```
+    pub async fn get(&self, id: ObjectId) -> Result<Option<M>> {
+        let defaults = self.h_find_options(None);
+        let options = FindOneOptions::builder()
+            .projection(defaults.projection)
+            .comment(defaults.comment)
+            .max_time(defaults.max_time)
+            .build();
+        self.coll
+            .find_one(doc! { "_id": id })
+            .with_options(options)
+            .await
+    }
+
+    /// Fetch all documents whose `_id` is one of the given ids, in a single query.
+    ///
+    /// Mongo doesn't return documents in the order of the `$in` operator and missing ids are
+    /// simply absent from the results. See `find_by_ids_ordered` to get results matching the input order.
+    pub async fn find_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<M>> {
+        self.find_with_defaults(doc! { "_id": { "$in": ids } }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Same as `find_by_ids`, but results are returned in the same order as the input ids
+    /// with `None` in place of missing documents.
+    ///
+    /// Reordering happens client-side, at the cost of holding all fetched documents in memory
+    /// until they are all received.
+    pub async fn find_by_ids_ordered(&self, ids: &[ObjectId]) -> Result<Vec<Option<M>>> {
+        let mut docs: Vec<Document> = self
+            .coll
+            .clone_with_type::<Document>()
+            .find(doc! { "_id": { "$in": ids } })
+            .with_options(self.h_find_options(None))
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut by_id = HashMap::with_capacity(docs.len());
+        for doc in docs.drain(..) {
+            if let Ok(id) = doc.get_object_id("_id") {
+                by_id.insert(id, doc);
+            }
+        }
+
+        let mut ret = Vec::with_capacity(ids.len());
+        for id in ids {
+            ret.push(by_id.get(id).cloned().map(h_from_document).transpose()?);
+        }
+        Ok(ret)
+    }
+
+    /// Update a single document and returns `true` if it was actually modified.
+    ///
+    /// A document matching the filter but already in the requested state is not modified,
+    /// in which case `false` is returned. Use `update_one_matched` to know whether a document matched.
+    ///
+    /// `update` must only contain update operators, see `update_models`.
+    pub async fn update_one_bool(&self, filter: Document, update: Document) -> Result<bool> {
+        h_validate_update(&update)?;
+        let res = self.coll.update_one(filter, update).await?;
+        Ok(res.modified_count > 0)
+    }
+
+    /// Update a single document and returns `true` if a document matched the filter, modified or not.
+    pub async fn update_one_matched(&self, filter: Document, update: Document) -> Result<bool> {
+        h_validate_update(&update)?;
+        let res = self.coll.update_one(filter, update).await?;
+        Ok(res.matched_count > 0)
+    }
+
+    /// Update the document whose `_id` is `id`.
+    ///
+    /// `update` must only contain update operators, see `update_models`: a replacement document is rejected
+    /// before reaching the server. Use `UpdateResult::matched_count` to know whether the document exists.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     age: i32,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database, id: ObjectId) {
+    /// let res = db
+    ///     .repository::<User>()
+    ///     .update_by_id(id, doc! { Inc: { f!(age in User): 1 } }, None)
+    ///     .await
+    ///     .unwrap();
+    /// assert_eq!(res.matched_count, 1);
+    /// # }
+    /// ```
+    pub async fn update_by_id(
+        &self,
+        id: impl Into<Bson>,
+        update: Document,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> Result<mongodb::results::UpdateResult> {
+        h_validate_update(&update)?;
+        self.coll
+            .update_one(doc! { "_id": id.into() }, update)
+            .with_options(options)
+            .await
+    }
+
+    /// Update a single document and returns it as it was before the update, or `None` if no document matched.
+    ///
+    /// Uses `findOneAndUpdate` with `ReturnDocument::Before`: the update and the read of the previous version
+    /// are a single atomic operation, so no concurrent write can happen in between, unlike reading the document
+    /// then updating it. Useful to record changes in an audit log.
+    pub async fn update_one_returning_old(
+        &self,
+        filter: Document,
+        update: Document,
+    ) -> Result<Option<M>> {
+        self.h_find_one_and_update(filter, update, false, ReturnDocument::Before)
+            .await
+    }
+
+    /// Update the document matching `filter`, inserting it if none matches, and returns the updated or inserted document.
+    ///
+    /// This is the atomic "get or create" primitive, using `findOneAndUpdate` with `upsert: true` and
+    /// `ReturnDocument::After`: a document is always returned. On insertion, the new document is built
+    /// from the equality conditions of `filter` then `update` is applied, use `$setOnInsert` for fields
+    /// only set on creation.
+    ///
+    /// Concurrent upserts with the same filter can insert duplicates unless a unique index covers the
+    /// filter fields, in which case one of them fails with a duplicate key error and can be retried.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     visits: i64,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let user = db
+    ///     .repository::<User>()
+    ///     .upsert_and_fetch(
+    ///         doc! { f!(name in User): "David" },
+    ///         doc! { Inc: { f!(visits in User): 1 } },
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn upsert_and_fetch(&self, filter: Document, update: Document) -> Result<M> {
+        self.h_find_one_and_update(filter, update, true, ReturnDocument::After)
+            .await?
+            .ok_or_else(|| std::io::Error::other("no document returned by findOneAndUpdate").into())
+    }
+
+    /// Same as `upsert_and_fetch`, but returns the document as it was before the update, or `None` if it was inserted.
+    pub async fn upsert_returning_old(
+        &self,
+        filter: Document,
+        update: Document,
+    ) -> Result<Option<M>> {
+        self.h_find_one_and_update(filter, update, true, ReturnDocument::Before)
+            .await
+    }
+
+    async fn h_find_one_and_update(
+        &self,
+        filter: Document,
+        update: Document,
+        upsert: bool,
+        return_document: ReturnDocument,
+    ) -> Result<Option<M>> {
+        h_validate_update(&update)?;
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(upsert)
+            .return_document(return_document)
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        self.coll
+            .clone_with_type::<Document>()
+            .find_one_and_update(filter, update)
+            .with_options(options)
+            .await?
+            .map(h_from_document)
+            .transpose()
+    }
+
+    /// Atomically increment `field` of the document matching `filter` by `by`, and returns the new value.
+    ///
+    /// This is the usual mongo recipe for sequence generators, using `findOneAndUpdate`. The document
+    /// is upserted if none matches the filter, in which case the counter starts at `by`.
+    /// `field` may be a dotted path to an embedded field.
+    pub async fn increment_and_get(&self, filter: Document, field: &str, by: i64) -> Result<i64> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let doc = self
+            .coll
+            .clone_with_type::<Document>()
+            .find_one_and_update(filter, doc! { "$inc": { field: by } })
+            .with_options(options)
+            .await?
+            .ok_or_else(|| std::io::Error::other("no document returned by findOneAndUpdate"))?;
+
+        let mut value = Some(&Bson::Document(doc));
+        for key in field.split('.') {
+            value = match value {
+                Some(Bson::Document(doc)) => doc.get(key),
+                _ => None,
+            };
+        }
+
+        match value {
+            Some(Bson::Int32(value)) => Ok(i64::from(*value)),
+            Some(Bson::Int64(value)) => Ok(*value),
+            Some(Bson::Double(value)) if value.fract() == 0.0 => Ok(*value as i64),
+            other => Err(std::io::Error::other(format!(
+                "counter '{}' is not an integer: {:?}",
+                field, other
+            ))
+            .into()),
+        }
+    }
+
+    /// Insert a document expiring at `expire_at`, and returns its `_id`.
+    ///
+    /// The expiration date is stored in the `EXPIRE_AT_FIELD` field, added to the serialized model.
+    /// A TTL index must be declared on this field for documents to be removed, see `Index::new_expire_at`.
+    /// The model must not deny unknown fields to be read back.
+    ///
+    /// ```
+    /// use mongodm::prelude::*;
+    /// use mongodm::EXPIRE_AT_FIELD;
+    ///
+    /// struct SessionCollConf;
+    ///
+    /// impl CollectionConfig for SessionCollConf {
+    ///     fn collection_name() -> &'static str {
+    ///         "session"
+    ///     }
+    ///
+    ///     fn indexes() -> Indexes {
+    ///         Indexes::new().with(Index::new_expire_at(EXPIRE_AT_FIELD))
+    ///     }
+    /// }
+    /// ```
+    pub async fn insert_expiring(&self, model: &M, expire_at: BsonDateTime) -> Result<Bson> {
+        let mut doc = h_to_document(model)?;
+        doc.insert(EXPIRE_AT_FIELD, expire_at);
+        Ok(self
+            .coll
+            .clone_with_type::<Document>()
+            .insert_one(doc)
+            .await?
+            .inserted_id)
+    }
+
+    /// Delete all documents of the associated collection and returns the number of deleted documents.
+    ///
+    /// Requiring the `ConfirmDeleteAll` token makes wiping a collection intentional (an empty filter
+    /// passed by mistake to `delete_many` has the same effect) and easy to grep for.
+    /// Indexes are kept, unlike `drop`.
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use mongodm::ConfirmDeleteAll;
+    /// /* ... */
+    /// # async fn demo(db: mongodb::Database) {
+    /// let nb_deleted = db
+    ///     .repository::<User>()
+    ///     .delete_all(ConfirmDeleteAll)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn delete_all(&self, _confirm: ConfirmDeleteAll) -> Result<u64> {
+        Ok(self.coll.delete_many(doc! {}).await?.deleted_count)
+    }
+
+    /// Insert all documents that can be inserted, reporting the failing ones instead of stopping at the first error.
+    ///
+    /// Documents are inserted with `ordered: false`: the server attempts every document regardless of
+    /// previous failures, and may insert them in any order. There is no atomicity across documents, an
+    /// import interrupted by an error (including a network error, returned as `Err`) leaves the already
+    /// inserted documents in place. This is typically used for idempotent imports, where duplicate key
+    /// errors on documents already imported are expected and can be ignored.
+    ///
+    /// An `_id` is generated for documents without one, so that ids of inserted documents can be reported.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database, users: Vec<User>) {
+    /// let res = db.repository::<User>().insert_many_unordered(&users).await.unwrap();
+    ///
+    /// // Duplicate key
+    /// let unexpected = res.write_errors.iter().filter(|e| e.code != 11000).count();
+    /// assert_eq!(unexpected, 0);
+    /// # }
+    /// ```
+    pub async fn insert_many_unordered(&self, models: &[M]) -> Result<PartialInsertResult> {
+        let mut docs = Vec::with_capacity(models.len());
+        for model in models {
+            let mut doc = h_to_document(model)?;
+            if !doc.contains_key("_id") {
+                doc.insert("_id", ObjectId::new());
+            }
+            docs.push(doc);
+        }
+        let ids: Vec<Bson> = docs
+            .iter()
+            .map(|doc| doc.get("_id").cloned().unwrap_or(Bson::Null))
+            .collect();
+
+        let mut result = PartialInsertResult {
+            inserted_ids: HashMap::new(),
+            write_errors: Vec::new(),
+            write_concern_error: None,
+        };
+        if docs.is_empty() {
+            return Ok(result);
+        }
+
+        let options = InsertManyOptions::builder().ordered(false).build();
+        match self
+            .coll
+            .clone_with_type::<Document>()
+            .insert_many(docs)
+            .with_options(options)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) => match *e.kind {
+                mongodb::error::ErrorKind::InsertMany(err) => {
+                    result.write_errors = err.write_errors.unwrap_or_default();
+                    result.write_concern_error = err.write_concern_error;
+                }
+                _ => return Err(e),
+            },
+        }
+
+        result.inserted_ids = ids
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !result.write_errors.iter().any(|e| e.index == *i))
+            .collect();
+        Ok(result)
+    }
+
+    /// Stream documents matching `filter` into the collection of `dest`, converting them with `transform`.
+    ///
+    /// Documents are read and inserted `batch_size` at a time, so that arbitrarily large collections can be
+    /// copied without holding them in memory. Returns the number of inserted documents.
+    ///
+    /// Whole documents are read: the default sort and projection of `CollectionConfig` are not applied.
+    /// With `CopyErrorPolicy::Abort`, documents inserted before the failing one are left in `dest`.
+    /// With `CopyErrorPolicy::Continue`, only write errors are skipped: other errors (network, write concern...)
+    /// still stop the copy.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct UserV2 {
+    /// #     display_name: String,
+    /// # }
+    /// # impl Model for UserV2 {
+    /// #     type CollConf = UserV2CollConf;
+    /// # }
+    /// # struct UserV2CollConf;
+    /// # impl CollectionConfig for UserV2CollConf {
+    /// #     fn collection_name() -> &'static str { "user_v2" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use mongodm::CopyErrorPolicy;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let copied = db
+    ///     .repository::<User>()
+    ///     .copy_to(
+    ///         &db.repository::<UserV2>(),
+    ///         doc! {},
+    ///         |user| UserV2 { display_name: user.name },
+    ///         500,
+    ///         CopyErrorPolicy::Abort,
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn copy_to<N, F>(
+        &self,
+        dest: &Repository<N>,
+        filter: Document,
+        transform: F,
+        batch_size: usize,
+        on_error: CopyErrorPolicy,
+    ) -> Result<u64>
+    where
+        N: Model,
+        F: Fn(M) -> N,
+    {
+        if batch_size == 0 {
+            return Err(std::io::Error::other("batch_size must be greater than zero").into());
+        }
+
+        let options = FindOptions::builder()
+            .batch_size(u32::try_from(batch_size).ok())
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        let mut cursor: ModelCursor<M> = ModelCursor::new(
+            self.coll
+                .clone_with_type::<Document>()
+                .find(filter)
+                .with_options(options)
+                .await?,
+        );
+        let insert_options = InsertManyOptions::builder()
+            .ordered(on_error == CopyErrorPolicy::Abort)
+            .build();
+
+        let mut copied = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            let item = cursor.try_next().await?;
+            let done = item.is_none();
+            if let Some(item) = item {
+                batch.push(transform(item));
+            }
+
+            if !batch.is_empty() && (done || batch.len() == batch_size) {
+                let len = batch.len() as u64;
+                match dest
+                    .coll
+                    .insert_many(batch.drain(..))
+                    .with_options(insert_options.clone())
+                    .await
+                {
+                    Ok(res) => copied += res.inserted_ids.len() as u64,
+                    Err(e) if on_error == CopyErrorPolicy::Continue => match e.kind.as_ref() {
+                        mongodb::error::ErrorKind::InsertMany(err)
+                            if err.write_concern_error.is_none() =>
+                        {
+                            let failed = err.write_errors.as_ref().map_or(0, Vec::len) as u64;
+                            copied += len.saturating_sub(failed);
+                        }
+                        _ => return Err(e),
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if done {
+                return Ok(copied);
+            }
+        }
+    }
+
+    /// Create a single index on the associated collection, without synchronizing other indexes.
+    ///
+    /// Mongo treats this as a no-op if an identical index already exists, which makes it suitable
+    /// for indexes lazily created the first time a feature is used.
+    /// Returns the name of the index.
+    ///
+    /// Note that a subsequent `sync_indexes` will drop this index unless it is declared in `CollectionConfig::indexes`.
+    pub async fn ensure_index(&self, index: Index) -> Result<String> {
+        let index_doc = index.clone().try_into_document()?;
+
+        h_run_command(
+            &self.db,
+            Indexes::new()
+                .with(index)
+                .create_indexes_command(self.coll.name()),
+        )
+        .await?;
+
+        if let Ok(name) = index_doc.get_str("name") {
+            return Ok(name.to_owned());
+        }
+
+        // Name was assigned by the server (see `Index::without_auto_name`)
+        let key = index_doc
+            .get_document("key")
+            .map_err(std::io::Error::other)?
+            .to_string();
+        self.list_index_infos()
+            .await?
+            .into_iter()
+            .find(|info| info.key.to_string() == key)
+            .map(|info| info.name)
+            .ok_or_else(|| std::io::Error::other("created index not found").into())
+    }
+
+    /// List indexes currently existing on the associated collection.
+    ///
+    /// Returns an empty list if the collection doesn't exist yet.
+    /// The raw `Document` version is available through the underlying collection `list_indexes` method.
+    ///
+    /// Uses the read preference of the collection (primary if none).
+    pub async fn list_index_infos(&self) -> Result<Vec<IndexInfo>> {
+        let selection_criteria = self.h_selection_criteria();
+        let existing = match h_list_indexes(&self.db, self.coll.name(), selection_criteria).await {
+            Ok(existing) => existing,
+            Err(e) => match e.kind.as_ref() {
+                mongodb::error::ErrorKind::Command(err) if err.code == 26 => return Ok(Vec::new()),
+                _ => return Err(e),
+            },
+        };
+
+        let mut infos = Vec::with_capacity(existing.len());
+        for index in existing {
+            infos.push(from_document(index)?);
+        }
+        Ok(infos)
+    }
+
+    /// Explicitly create the associated collection.
+    ///
+    /// Collections are otherwise implicitly created on first insert or index creation. An explicit
+    /// creation is required for collections with encrypted fields (see `CollectionConfig::encrypted_fields`,
+    /// only available with the `in-use-encryption` feature), so that the server and the driver create
+    /// the auxiliary metadata collections.
+    ///
+    /// The collection is created with `CollectionConfig::create_collection_options`.
+    /// Fails if the collection already exists, see `provision` to create it only when absent.
+    pub async fn create_collection(&self) -> Result<()> {
+        self.db
+            .create_collection(self.coll.name())
+            .with_options(h_create_collection_options::<M::CollConf>())
+            .await
+    }
+
+    /// Returns the query plan of a find operation, as chosen by the server, using the `explain` command.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/explain/)
+    ///
+    /// The query is planned exactly as `find_with_defaults` would run it (default sort and projection
+    /// included), with the `executionStats` verbosity: the winning plan is actually executed to report
+    /// examined keys and documents, but rejected plans are not. Use `ExplainSummary::from_explain` to
+    /// extract the main figures, for instance to assert in tests that a query is covered by an index.
+    ///
+    /// Max time, comment and the options shaping the query (`let`, `allow_disk_use`, `min`, `max`, `return_key`,
+    /// `show_record_id`...) are sent as well. Options only affecting how a cursor is iterated (`batch_size`,
+    /// `cursor_type`, `no_cursor_timeout`...), as well as `read_concern` and `selection_criteria`, are ignored:
+    /// uses the read preference of the collection (primary if none).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use mongodm::ExplainSummary;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let options = MongoFindOptions::builder()
+    ///     .projection(doc! { "_id": 0, f!(name in User): 1 })
+    ///     .build();
+    /// let explain = db
+    ///     .repository::<User>()
+    ///     .explain(doc! { f!(name in User): "David" }, options)
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// let summary = ExplainSummary::from_explain(&explain);
+    /// assert_eq!(summary.index_name.as_deref(), Some("name_1"));
+    /// assert!(summary.is_covered());
+    /// # }
+    /// ```
+    pub async fn explain(
+        &self,
+        filter: Document,
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<Document> {
+        let options = self.h_find_options(options.into());
+
+        let find = h_find_command(self.coll.name(), filter, options)?;
+
+        let selection_criteria = self.h_selection_criteria();
+        h_run_command_with_selection_criteria(
+            &self.db,
+            doc! { "explain": find, "verbosity": "executionStats" },
+            selection_criteria,
+        )
+        .await
+    }
+
+    /// Returns the execution plan of an aggregation pipeline, as chosen by the server, using the `explain` command.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/explain/)
+    ///
+    /// The `aggregate` command is wrapped in an `explain` command with the `executionStats` verbosity:
+    /// the pipeline is actually run, and stages report their returned documents and estimated execution time.
+    /// (`aggregate` with `explain: true` only supports the `queryPlanner` verbosity, without any figure.)
+    /// Comment and max time set on this repository are used unless provided in `options`.
+    /// Use `AggregateExplainSummary::from_explain` to extract the main figures.
+    ///
+    /// The shape of the output depends on the server version and on how much of the pipeline could be pushed
+    /// down to the query layer. Uses the read preference of the collection (primary if none).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Login {
+    /// #     user: String,
+    /// # }
+    /// # impl Model for Login {
+    /// #     type CollConf = LoginCollConf;
+    /// # }
+    /// # struct LoginCollConf;
+    /// # impl CollectionConfig for LoginCollConf {
+    /// #     fn collection_name() -> &'static str { "login" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use mongodm::AggregateExplainSummary;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let explain = db
+    ///     .repository::<Login>()
+    ///     .explain_aggregate(
+    ///         pipeline! [
+    ///             Match: { f!(user in Login): "David" },
+    ///             Group: { "_id": null, "count": { Sum: 1 } },
+    ///         ],
+    ///         None,
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    /// let summary = AggregateExplainSummary::from_explain(&explain);
+    /// assert!(summary.query.index_name.is_some(), "$match doesn't use an index");
+    /// # }
+    /// ```
+    pub async fn explain_aggregate(
+        &self,
+        pipeline: impl IntoIterator<Item = Document>,
+        options: impl Into<Option<AggregateOptions>>,
+    ) -> Result<Document> {
+        let options = self.h_aggregate_options(options.into());
+
+        let pipeline: Vec<Document> = pipeline.into_iter().collect();
+        let mut aggregate =
+            doc! { "aggregate": self.coll.name(), "pipeline": pipeline, "cursor": {} };
+        if let Some(allow_disk_use) = options.allow_disk_use {
+            aggregate.insert("allowDiskUse", allow_disk_use);
+        }
+        if let Some(hint) = options.hint {
+            aggregate.insert("hint", to_bson(&hint)?);
+        }
+        if let Some(collation) = options.collation {
+            aggregate.insert("collation", to_bson(&collation)?);
+        }
+        if let Some(comment) = options.comment {
+            aggregate.insert("comment", comment);
+        }
+        if let Some(let_vars) = options.let_vars {
+            aggregate.insert("let", let_vars);
+        }
+        if let Some(max_time) = options.max_time {
+            aggregate.insert("maxTimeMS", to_bson(&(max_time.as_millis() as i64))?);
+        }
+
+        let selection_criteria = self.h_selection_criteria();
+        h_run_command_with_selection_criteria(
+            &self.db,
+            doc! { "explain": aggregate, "verbosity": "executionStats" },
+            selection_criteria,
+        )
+        .await
+    }
+
+    /// Check that the database is reachable by sending the lightweight `ping` command.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/ping/)
+    ///
+    /// Uses the read preference of the collection (primary if none), so a successful ping means the
+    /// repository can currently be used for reads.
+    ///
+    /// The time spent waiting for a suitable server is bounded by the `server_selection_timeout`
+    /// client option, 30 seconds by default. For health endpoints, prefer a dedicated client with a
+    /// short timeout so that a down database is reported quickly rather than after half a minute.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    /// use std::time::Duration;
+    ///
+    /// # async fn demo() {
+    /// let mut options = MongoClientOptions::parse("mongodb://localhost:27017").await.unwrap();
+    /// options.server_selection_timeout = Some(Duration::from_secs(2));
+    /// let db = MongoClient::with_options(options).unwrap().database("app");
+    ///
+    /// // To be called from the readiness probe of the web framework
+    /// let ready = db.repository::<User>().ping().await.is_ok();
+    /// # }
+    /// ```
+    pub async fn ping(&self) -> Result<()> {
+        let selection_criteria = self.h_selection_criteria();
+        h_run_command_with_selection_criteria(&self.db, doc! { "ping": 1 }, selection_criteria)
+            .await?;
+        Ok(())
+    }
+
+    /// Run an arbitrary command against the database owning this repository, returning the server reply as is.
+    ///
+    /// An escape hatch for commands not wrapped by MongODM, without holding the `Database` separately.
+    /// The command runs on the primary. See `run_command_checked` to surface failures reported in the reply.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<User>();
+    /// let reply = repository
+    ///     .run_command_checked(doc! { "collMod": repository.name(), "validationLevel": "moderate" })
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn run_command(&self, command: Document) -> Result<Document> {
+        self.db.run_command(command).await
+    }
+
+    /// Same as `run_command`, but replies reporting a failure are returned as `ErrorKind::Command` errors.
+    ///
+    /// See `check_command_reply`.
+    pub async fn run_command_checked(&self, command: Document) -> Result<Document> {
+        h_run_command(&self.db, command).await
+    }
+
+    /// Returns storage statistics of the associated collection using the `collStats` command.
+    ///
+    /// Uses the read preference of the collection (primary if none).
+    pub async fn stats(&self) -> Result<CollectionStats> {
+        let selection_criteria = self.h_selection_criteria();
+        let ret = h_run_command_with_selection_criteria(
+            &self.db,
+            doc! { "collStats": self.coll.name() },
+            selection_criteria,
+        )
+        .await?;
+        Ok(from_document(ret)?)
+    }
+
+    /// Check the structures of the associated collection and its indexes using the `validate` command.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/validate/)
+    ///
+    /// Prefer `full: false` for routine checks: a full validation is much slower, and takes an exclusive
+    /// lock on the collection with some storage engines and server versions, blocking reads and writes
+    /// until completion.
+    pub async fn validate(&self, full: bool) -> Result<ValidationReport> {
+        let ret = h_run_command(
+            &self.db,
+            doc! { "validate": self.coll.name(), "full": full },
+        )
+        .await?;
+        Ok(from_document(ret)?)
+    }
+
+    /// Rename the associated collection, keeping it in the same database.
+    ///
+    /// [Mongo manual](https://docs.mongodb.com/manual/reference/command/renameCollection/)
+    ///
+    /// If a collection named `new_name` already exists, it is dropped beforehand when `drop_target`
+    /// is `true`, otherwise the command fails.
+    ///
+    /// `renameCollection` is an admin command: it runs against the `admin` database and requires
+    /// the corresponding privileges. This repository still targets the previous collection name
+    /// once renamed.
+    pub async fn rename(&self, new_name: &str, drop_target: bool) -> Result<()> {
+        let db_name = self.db.name();
+        h_run_command(
+            &self.db.client().database("admin"),
+            doc! {
+                "renameCollection": format!("{}.{}", db_name, self.coll.name()),
+                "to": format!("{}.{}", db_name, new_name),
+                "dropTarget": drop_target,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Watch changes on the associated collection, including the state of documents before and after each change.
+    ///
+    /// Requires MongoDB 6.0+ and the `changeStreamPreAndPostImages` option to be enabled on the collection,
+    /// otherwise an error is returned. Pre-images are available for update, replace and delete events
+    /// while post-images are available for insert, update and replace events.
+    pub async fn watch_full(&self) -> Result<ModelChangeStream<M>> {
+        self.h_watch_full(Vec::new()).await
+    }
+
+    /// Same as `watch_full`, but only for changes on the document whose `_id` is `id`.
+    ///
+    /// Changes are matched on `documentKey._id`, which is set for all operations on a document:
+    /// deletes of the document are delivered too. `_id` is immutable, so the stream follows the same
+    /// document for its whole life, including its re-creation after a delete.
+    ///
+    /// Each change stream holds a server cursor and, unless the stream is idle, a connection polling it:
+    /// watching thousands of documents individually (e.g. one stream per connected UI) keeps as many
+    /// cursors open on the server. In such case, prefer a single `watch_full` stream shared by all
+    /// subscribers and dispatched on the client side using `ModelChange::document_key`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// # }
+    /// # impl Model for User {
+    /// #     type CollConf = UserCollConf;
+    /// # }
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// # async fn demo(db: mongodb::Database, id: ObjectId) {
+    /// let mut changes = db.repository::<User>().watch_one(id).await.unwrap();
+    /// while let Some(change) = changes.next().await {
+    ///     let change = change.unwrap();
+    ///     match change.after {
+    ///         Some(user) => println!("user is now named {}", user.name),
+    ///         None => println!("user was deleted"),
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn watch_one(&self, id: impl Into<Bson>) -> Result<ModelChangeStream<M>> {
+        self.h_watch_full(vec![doc! { "$match": { "documentKey._id": id.into() } }])
+            .await
+    }
+
+    async fn h_watch_full(&self, pipeline: Vec<Document>) -> Result<ModelChangeStream<M>> {
+        let spec = self
+            .db
+            .list_collections()
+            .filter(doc! { "name": self.coll.name() })
+            .await?
+            .try_next()
+            .await?;
+        let pre_and_post_images_enabled = spec
+            .and_then(|spec| spec.options.change_stream_pre_and_post_images)
+            .map(|option| option.enabled)
+            .unwrap_or(false);
+        if !pre_and_post_images_enabled {
+            return Err(std::io::Error::other(format!(
+                "pre and post images are not enabled on collection '{}' (see `changeStreamPreAndPostImages` collection option)",
+                self.coll.name()
+            ))
+            .into());
+        }
+
+        let stream = self
+            .coll
+            .watch()
+            .pipeline(pipeline)
+            .full_document(FullDocumentType::WhenAvailable)
+            .full_document_before_change(FullDocumentBeforeChangeType::WhenAvailable)
+            .await?;
+
+        Ok(ModelChangeStream::new(stream))
+    }
+
+    /// Insert a single document and returns it with its `_id` populated.
+    ///
+    /// When the model doesn't carry an `_id` yet, the `ObjectId` generated on insertion is assigned with
+    /// `WithId::set_id`, saving a `find_one(doc! { "_id": .. })` round trip. Models already carrying an `_id` are returned as-is
+    /// (the `_id` must be skipped when `None`, otherwise a `null` `_id` is inserted).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User {
+    ///     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    ///     id: Option<ObjectId>,
+    ///     name: String,
+    /// }
+    ///
+    /// impl Model for User {
+    ///     type CollConf = UserCollConf;
+    /// }
+    ///
+    /// impl WithId for User {
+    ///     fn id(&self) -> Option<ObjectId> {
+    ///         self.id
+    ///     }
+    ///
+    ///     fn set_id(&mut self, id: ObjectId) {
+    ///         self.id = Some(id);
+    ///     }
+    /// }
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let user = db
+    ///     .repository::<User>()
+    ///     .insert_one_and_get(User { id: None, name: String::from("David") })
+    ///     .await
+    ///     .unwrap();
+    /// assert!(user.id.is_some());
+    /// # }
+    /// ```
+    pub async fn insert_one_and_get(&self, mut model: M) -> Result<M>
+    where
+        M: WithId,
+    {
+        let inserted_id = self.insert_model(&model).await?;
+        if model.id().is_none() {
+            match inserted_id {
+                Bson::ObjectId(id) => model.set_id(id),
+                other => {
+                    return Err(std::io::Error::other(format!(
+                        "inserted document has a non-ObjectId _id: {}",
+                        other
+                    ))
+                    .into())
+                }
+            }
+        }
+        Ok(model)
+    }
+
+    /// Upsert multiple documents at once, replacing existing documents by their `_id`.
+    ///
+    /// Documents are sent through `bulk_update`: a single `update` command, or several commands sent sequentially
+    /// when exceeding the limits of a single command (see `bulk_update`). Documents without an id are
+    /// inserted with a freshly generated `ObjectId` which is reported in `BulkUpdateResult::upserted`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # struct UserCollConf;
+    /// # impl CollectionConfig for UserCollConf {
+    /// #     fn collection_name() -> &'static str { "user" }
+    /// # }
+    /// use mongodm::prelude::*;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User {
+    ///     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    ///     id: Option<ObjectId>,
+    ///     name: String,
+    /// }
+    ///
+    /// impl Model for User {
+    ///     type CollConf = UserCollConf;
+    /// }
+    ///
+    /// impl WithId for User {
+    ///     fn id(&self) -> Option<ObjectId> {
+    ///         self.id
+    ///     }
+    ///
+    ///     fn set_id(&mut self, id: ObjectId) {
+    ///         self.id = Some(id);
+    ///     }
+    /// }
+    ///
+    /// # async fn demo(db: mongodb::Database) {
+    /// let repository = db.repository::<User>();
+    /// let res = repository
+    ///     .save_many(&[
+    ///         User { id: Some(ObjectId::new()), name: String::from("Dane") },
+    ///         User { id: None, name: String::from("David") },
+    ///     ])
+    ///     .await
+    ///     .unwrap();
+    /// assert_eq!(res.upserted.len(), 2);
+    /// # }
+    /// ```
+    pub async fn save_many(&self, models: &[M]) -> Result<BulkUpdateResult>
+    where
+        M: WithId,
+    {
+        if models.is_empty() {
+            return Ok(BulkUpdateResult {
+                nb_affected: 0,
+                nb_modified: 0,
+                upserted: Vec::new(),
+                write_errors: Vec::new(),
+                write_concern_error: None,
+            });
+        }
+
+        let mut updates = Vec::with_capacity(models.len());
+        for model in models {
+            let id = model.id().unwrap_or_else(ObjectId::new);
+            let mut replacement = h_to_document(model)?;
+            // The `_id` is taken from the query on upsert and can't be changed on replacement.
+            replacement.remove("_id");
+            updates.push(BulkUpdate {
+                query: doc! { "_id": id },
+                update: replacement,
+                options: Some(UpdateOptions::builder().upsert(true).build()),
+            });
+        }
+
+        self.bulk_update(&updates).await
+    }
+}
+
+/// MongODM-provided utilities functions on `mongodb::Collection<M>`.
+#[async_trait]
+pub trait CollectionExt {
+    /// Apply multiple update operations in bulk.
+    ///
+    /// This will be removed once support for bulk update is added to the official driver.
+    /// [see](https://jira.mongodb.org/browse/RUST-531) for tracking progress on this feature in the official driver.
+    ///
+    /// Updates are sent as a raw `update` command, which the driver never retries: unlike native write
+    /// operations, a network error or a failover returns an error even when retryable writes are enabled
+    /// (see `retryable_writes_enabled`). The command may or may not have been applied in such case.
+    ///
+    /// Updates exceeding the limits of a single command (100,000 operations or 16 MiB) are split into multiple
+    /// commands sent sequentially, and their results are summed up (`BulkUpdateUpsertResult::index` always refers
+    /// to the position in `updates`). Updates are ordered: the server stops at the first failing update, and the
+    /// following commands are not sent. Failures are reported in `BulkUpdateResult::write_errors`, indexed by position
+    /// in `updates` as well: all the updates after the first one listed there were not attempted.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use serde::{Serialize, Deserialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct User {
+    /// #     name: String,
+    /// #     age: i64,
+    /// # }
+    /// use mongodm::prelude::*;
+    /// /* ... */
+    /// # async fn demo(_db: mongodb::Database) {
+    /// let db: mongodb::Database; /* exists */
+    /// # db = _db;
+    /// let collection = db.collection::<User>("user");
+    /// /* ... */
+    /// let bulk_update_res = collection
+    ///     .bulk_update(&db, &vec![
+    ///         &BulkUpdate {
+    ///             query: doc! { f!(name in User): "Dane" },
+    ///             update: doc! { Set: { f!(age in User): 12 } },
+    ///             options: None,
+    ///         },
+    ///         &BulkUpdate {
+    ///             query: doc! { f!(name in User): "David" },
+    ///             update: doc! { Set: { f!(age in User): 30 } },
+    ///             options: None,
+    ///         },
+    ///     ])
+    ///     .await
+    ///     .unwrap();
+    /// assert_eq!(bulk_update_res.nb_affected, 2);
+    /// assert_eq!(bulk_update_res.nb_modified, 2);
+    /// # }
+    /// ```
+    async fn bulk_update<V, U>(
+        &self,
+        db: &mongodb::Database,
+        updates: V,
+    ) -> Result<BulkUpdateResult>
+    where
+        V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
+        U: 'async_trait + Send + Sync + Borrow<BulkUpdate>;
+
+    /// Same as `bulk_update`, but using the given write concern instead of the one of the collection.
+    ///
+    /// Setting `w_timeout` along with `w: majority` prevents waiting forever for acknowledgments on a
+    /// degraded replica set. When it expires, the command doesn't fail: the error is reported in
+    /// `BulkUpdateResult::write_concern_error`, and the updates may have been applied.
+    async fn bulk_update_with_write_concern<V, U>(
+        &self,
+        db: &mongodb::Database,
         updates: V,
+        write_concern: WriteConcern,
     ) -> Result<BulkUpdateResult>
     where
         V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
@@ -348,39 +3084,799 @@ impl<M: Send + Sync> CollectionExt for mongodb::Collection<M> {
         V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
         U: 'async_trait + Send + Sync + Borrow<BulkUpdate>,
     {
-        let updates = updates.borrow();
-        let mut update_docs = Vec::with_capacity(updates.len());
-        for u in updates {
-            let u = u.borrow();
-            let mut doc = doc! {
-                "q": &u.query,
-                "u": &u.update,
-                "multi": false,
-            };
-            if let Some(options) = &u.options {
-                if let Some(ref upsert) = options.upsert {
-                    doc.insert("upsert", upsert);
-                }
-                if let Some(ref collation) = options.collation {
-                    doc.insert("collation", to_bson(collation)?);
-                }
-                if let Some(ref array_filters) = options.array_filters {
-                    doc.insert("arrayFilters", array_filters);
-                }
-                if let Some(ref hint) = options.hint {
-                    doc.insert("hint", to_bson(hint)?);
-                }
+        let commands = h_bulk_update_commands(self.name(), updates.borrow(), self.write_concern())?;
+        h_run_bulk_update(db, commands).await
+    }
+
+    async fn bulk_update_with_write_concern<V, U>(
+        &self,
+        db: &mongodb::Database,
+        updates: V,
+        write_concern: WriteConcern,
+    ) -> Result<BulkUpdateResult>
+    where
+        V: 'async_trait + Send + Sync + Borrow<Vec<U>>,
+        U: 'async_trait + Send + Sync + Borrow<BulkUpdate>,
+    {
+        let commands = h_bulk_update_commands(self.name(), updates.borrow(), Some(&write_concern))?;
+        h_run_bulk_update(db, commands).await
+    }
+}
+
+/// Maximum number of operations in a single write command (`maxWriteBatchSize`).
+const MAX_WRITE_BATCH_SIZE: usize = 100_000;
+
+/// Maximum total size of the operations of a single write command: `maxBsonObjectSize`, the server
+/// accepting an additional 16 KiB for the rest of the command.
+const MAX_WRITE_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+fn h_bulk_update_commands<U: Borrow<BulkUpdate>>(
+    collection_name: &str,
+    updates: &[U],
+    write_concern: Option<&WriteConcern>,
+) -> Result<Vec<(Document, u64)>> {
+    let mut update_docs = Vec::with_capacity(updates.len());
+    for u in updates {
+        let u = u.borrow();
+        let mut doc = doc! {
+            "q": &u.query,
+            "u": &u.update,
+            "multi": false,
+        };
+        if let Some(options) = &u.options {
+            if let Some(ref upsert) = options.upsert {
+                doc.insert("upsert", upsert);
+            }
+            if let Some(ref collation) = options.collation {
+                doc.insert("collation", to_bson(collation)?);
+            }
+            if let Some(ref array_filters) = options.array_filters {
+                doc.insert("arrayFilters", array_filters);
+            }
+            if let Some(ref hint) = options.hint {
+                doc.insert("hint", to_bson(hint)?);
             }
-            update_docs.push(doc);
         }
+        update_docs.push(doc);
+    }
+
+    let write_concern = write_concern.map(to_bson).transpose()?;
+    let mut commands = Vec::new();
+    for (chunk, offset) in
+        h_split_write_batch(update_docs, MAX_WRITE_BATCH_SIZE, MAX_WRITE_BATCH_BYTES)?
+    {
         let mut command = doc! {
-            "update": self.name(),
-            "updates": update_docs,
+            "update": collection_name,
+            "updates": chunk,
+        };
+        if let Some(write_concern) = &write_concern {
+            command.insert("writeConcern", write_concern.clone());
+        }
+        commands.push((command, offset));
+    }
+    Ok(commands)
+}
+
+/// Split operations into chunks of at most `max_count` operations and `max_bytes` bytes, along with the
+/// index of their first operation. An operation larger than `max_bytes` is sent alone and rejected by the server.
+fn h_split_write_batch(
+    docs: Vec<Document>,
+    max_count: usize,
+    max_bytes: usize,
+) -> Result<Vec<(Vec<Document>, u64)>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_bytes = 0;
+    let mut offset = 0;
+    for (i, doc) in docs.into_iter().enumerate() {
+        // Array elements are keyed by their index: element type, key and trailing null byte
+        let bytes = mongodb::bson::to_vec(&doc)?.len() + i.to_string().len() + 2;
+        if !chunk.is_empty() && (chunk.len() == max_count || chunk_bytes + bytes > max_bytes) {
+            chunks.push((std::mem::take(&mut chunk), offset));
+            chunk_bytes = 0;
+            offset = i as u64;
+        }
+        chunk.push(doc);
+        chunk_bytes += bytes;
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push((chunk, offset));
+    }
+    Ok(chunks)
+}
+
+async fn h_run_bulk_update(
+    db: &mongodb::Database,
+    commands: Vec<(Document, u64)>,
+) -> Result<BulkUpdateResult> {
+    let mut total = BulkUpdateResult {
+        nb_affected: 0,
+        nb_modified: 0,
+        upserted: Vec::new(),
+        write_errors: Vec::new(),
+        write_concern_error: None,
+    };
+    for (command, offset) in commands {
+        let res: BulkUpdateResult = from_document(db.run_command(command).await?).map_err(|e| {
+            std::io::Error::other(format!("failed to parse bulk update response: {}", e))
+        })?;
+        // Updates are ordered: the server stops at the first write error, so do the following commands
+        let write_errors = !res.write_errors.is_empty();
+        total.h_merge(res, offset);
+        if write_errors {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// Basic CRUD operations implemented both by `Repository` and by the in-memory `MemoryRepository`.
+///
+/// Writing business logic against this trait allows to unit test it with a `MemoryRepository`
+/// instead of requiring a running mongo instance.
+#[async_trait]
+pub trait RepositoryBackend<M: Model>: Send + Sync {
+    /// Insert a single document and returns its `_id`.
+    ///
+    /// Models not serializing into a document are rejected with an error naming the model type.
+    /// `Repository` only checks this in debug builds, or when the `model-checks` feature is enabled.
+    async fn insert_model(&self, model: &M) -> Result<Bson>;
+
+    /// Find a single document matching the filter.
+    async fn find_one_model(&self, filter: Document) -> Result<Option<M>>;
+
+    /// Find all documents matching the filter, sorted by `CollectionConfig::default_sort` if any.
+    async fn find_models(&self, filter: Document) -> Result<Vec<M>>;
+
+    /// Same as `find_models`, but forcing the query planner to use the given index.
+    ///
+    /// The hint can either be an index name (`Hint::Name`) or a key document (`Hint::Keys`),
+    /// preferably built with `field!` (eg: `Hint::Keys(doc! { f!(age in User): 1 })`).
+    /// Backends without indexes (such as `MemoryRepository`) ignore the hint.
+    async fn find_models_with_hint(&self, filter: Document, hint: Hint) -> Result<Vec<M>> {
+        let _ = hint;
+        self.find_models(filter).await
+    }
+
+    /// Count documents matching the filter.
+    async fn count(&self, filter: Document) -> Result<u64>;
+
+    /// Same as `count`, but forcing the query planner to use the given index.
+    ///
+    /// Note that `estimated_document_count` relies on collection metadata and doesn't
+    /// support hints: use this method when a specific index must be used for counting.
+    /// Backends without indexes (such as `MemoryRepository`) ignore the hint.
+    async fn count_with_hint(&self, filter: Document, hint: Hint) -> Result<u64> {
+        let _ = hint;
+        self.count(filter).await
+    }
+
+    /// Returns `true` if at least one document matches the filter.
+    async fn exists(&self, filter: Document) -> Result<bool> {
+        Ok(self.count(filter).await? > 0)
+    }
+
+    /// Same as `exists`, but forcing the query planner to use the given index.
+    ///
+    /// Backends without indexes (such as `MemoryRepository`) ignore the hint.
+    async fn exists_with_hint(&self, filter: Document, hint: Hint) -> Result<bool> {
+        Ok(self.count_with_hint(filter, hint).await? > 0)
+    }
+
+    /// Apply an update to all documents matching the filter and returns the number of modified documents.
+    ///
+    /// An empty update, or an update containing fields which are not update operators (ie: a replacement
+    /// document, which would overwrite whole documents), is rejected before reaching the server.
+    async fn update_models(&self, filter: Document, update: Document) -> Result<u64>;
+
+    /// Delete all documents matching the filter and returns the number of deleted documents.
+    async fn delete_models(&self, filter: Document) -> Result<u64>;
+}
+
+#[async_trait]
+impl<M: Model> RepositoryBackend<M> for Repository<M> {
+    async fn insert_model(&self, model: &M) -> Result<Bson> {
+        // Extra serialization pass, so only in debug builds or when explicitly enabled
+        #[cfg(any(debug_assertions, feature = "model-checks"))]
+        h_to_document(model)?;
+
+        Ok(self.coll.insert_one(model).await?.inserted_id)
+    }
+
+    async fn find_one_model(&self, filter: Document) -> Result<Option<M>> {
+        self.coll.find_one(filter).await
+    }
+
+    async fn find_models(&self, filter: Document) -> Result<Vec<M>> {
+        self.find_with_defaults(filter, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn find_models_with_hint(&self, filter: Document, hint: Hint) -> Result<Vec<M>> {
+        let options = FindOptions::builder().hint(hint).build();
+        self.find_with_defaults(filter, options)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    async fn count(&self, filter: Document) -> Result<u64> {
+        let options = CountOptions::builder()
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        self.coll
+            .count_documents(filter)
+            .with_options(options)
+            .await
+    }
+
+    async fn count_with_hint(&self, filter: Document, hint: Hint) -> Result<u64> {
+        let options = CountOptions::builder()
+            .hint(hint)
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        self.coll
+            .count_documents(filter)
+            .with_options(options)
+            .await
+    }
+
+    async fn exists(&self, filter: Document) -> Result<bool> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        self.h_exists(filter, options).await
+    }
+
+    async fn exists_with_hint(&self, filter: Document, hint: Hint) -> Result<bool> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .hint(hint)
+            .comment(self.h_comment())
+            .max_time(self.max_time)
+            .build();
+        self.h_exists(filter, options).await
+    }
+
+    async fn update_models(&self, filter: Document, update: Document) -> Result<u64> {
+        h_validate_update(&update)?;
+        Ok(self.coll.update_many(filter, update).await?.modified_count)
+    }
+
+    async fn delete_models(&self, filter: Document) -> Result<u64> {
+        Ok(self.coll.delete_many(filter).await?.deleted_count)
+    }
+}
+
+impl<M: Model> Repository<M> {
+    async fn h_exists(&self, filter: Document, options: FindOneOptions) -> Result<bool> {
+        let found = self
+            .coll
+            .clone_with_type::<Document>()
+            .find_one(filter)
+            .with_options(options)
+            .await?;
+        Ok(found.is_some())
+    }
+}
+
+/// Options to create the collection of `CollConf` with, see `CollectionConfig::create_collection_options`.
+pub(crate) fn h_create_collection_options<CollConf: CollectionConfig>() -> CreateCollectionOptions {
+    #[allow(unused_mut)]
+    let mut options = CollConf::create_collection_options().unwrap_or_default();
+    #[cfg(feature = "in-use-encryption")]
+    if let Some(encrypted_fields) = CollConf::encrypted_fields() {
+        options.encrypted_fields = Some(encrypted_fields);
+    }
+    options
+}
+
+/// Serialize a model into a document, naming the model type when it doesn't serialize into a document.
+///
+/// Newtypes over primitives or enums with unit variants serialize into non-document BSON values,
+/// which is otherwise reported by the server or by `bson` with a confusing error message.
+pub(crate) fn h_to_document<T: Serialize>(model: &T) -> Result<Document> {
+    match to_bson(model)? {
+        Bson::Document(doc) => Ok(doc),
+        other => Err(std::io::Error::other(format!(
+            "`{}` is not a document: it serializes into a BSON {:?} (models must serialize into a struct or a map)",
+            std::any::type_name::<T>(),
+            other.element_type()
+        ))
+        .into()),
+    }
+}
+
+/// Build the `find` command equivalent to a find operation with the given options.
+fn h_find_command(
+    collection_name: &str,
+    filter: Document,
+    options: FindOptions,
+) -> Result<Document> {
+    let mut find = doc! { "find": collection_name, "filter": filter };
+    if let Some(sort) = options.sort {
+        find.insert("sort", sort);
+    }
+    if let Some(projection) = options.projection {
+        find.insert("projection", projection);
+    }
+    if let Some(skip) = options.skip {
+        find.insert("skip", to_bson(&skip)?);
+    }
+    if let Some(limit) = options.limit {
+        find.insert("limit", limit);
+    }
+    if let Some(hint) = options.hint {
+        find.insert("hint", to_bson(&hint)?);
+    }
+    if let Some(collation) = options.collation {
+        find.insert("collation", to_bson(&collation)?);
+    }
+    if let Some(comment) = options.comment {
+        find.insert("comment", comment);
+    }
+    if let Some(max_time) = options.max_time {
+        find.insert("maxTimeMS", to_bson(&(max_time.as_millis() as i64))?);
+    }
+    if let Some(let_vars) = options.let_vars {
+        find.insert("let", let_vars);
+    }
+    if let Some(allow_disk_use) = options.allow_disk_use {
+        find.insert("allowDiskUse", allow_disk_use);
+    }
+    if let Some(min) = options.min {
+        find.insert("min", min);
+    }
+    if let Some(max) = options.max {
+        find.insert("max", max);
+    }
+    if let Some(return_key) = options.return_key {
+        find.insert("returnKey", return_key);
+    }
+    if let Some(show_record_id) = options.show_record_id {
+        find.insert("showRecordId", show_record_id);
+    }
+    Ok(find)
+}
+
+/// Value at the (possibly dotted) `path` of `doc`, looking through embedded documents.
+pub(crate) fn h_get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    match path.split_once('.') {
+        Some((head, rest)) => match doc.get(head)? {
+            Bson::Document(sub) => h_get_path(sub, rest),
+            _ => None,
+        },
+        None => doc.get(path),
+    }
+}
+
+/// Escape regex metacharacters, for `s` to be matched literally by a `$regex`.
+fn h_escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Ensure an update document is made of update operators only.
+pub(crate) fn h_validate_update(update: &Document) -> Result<()> {
+    if update.is_empty() {
+        return Err(std::io::Error::other("update document is empty").into());
+    }
+    if let Some(key) = update.keys().find(|key| !key.starts_with('$')) {
+        return Err(std::io::Error::other(format!(
+            "update document contains '{}' which is not an update operator (replacement documents are not allowed here)",
+            key
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_stats_from_coll_stats() {
+        let stats: CollectionStats = from_document(doc! {
+            "ns": "db.coll",
+            "size": 1250i64,
+            "count": 10,
+            "avgObjSize": 125,
+            "storageSize": 20480,
+            "nindexes": 2,
+            "totalIndexSize": 40960i64,
+            "ok": 1.0,
+        })
+        .unwrap();
+
+        assert_eq!(stats.count, Some(10));
+        assert_eq!(stats.size, Some(1250));
+        assert_eq!(stats.avg_obj_size, Some(125.0));
+        assert_eq!(stats.storage_size, Some(20480));
+        assert_eq!(stats.n_indexes, Some(2));
+        assert_eq!(stats.total_index_size, Some(40960));
+        assert_eq!(stats.other, doc! { "ns": "db.coll", "ok": 1.0 });
+
+        let stats: CollectionStats = from_document(doc! { "ns": "db.coll", "ok": 1.0 }).unwrap();
+        assert_eq!(stats.count, None);
+    }
+
+    #[test]
+    fn keyset_cursor_nested_field() {
+        let doc = doc! { "_id": 1, "name": "David", "address": { "city": "Montreal" } };
+        assert_eq!(
+            KeysetCursor::h_after(&doc, "address.city"),
+            KeysetCursor {
+                value: Bson::from("Montreal"),
+                id: Bson::Int32(1),
+            }
+        );
+        assert_eq!(
+            KeysetCursor::h_after(&doc, "name").value,
+            Bson::from("David")
+        );
+        assert_eq!(KeysetCursor::h_after(&doc, "address.zip").value, Bson::Null);
+    }
+
+    #[test]
+    fn retryable_writes() {
+        let mut options = ClientOptions::default();
+        assert!(retryable_writes_enabled(&options));
+        options.retry_writes = Some(false);
+        assert!(!retryable_writes_enabled(&options));
+    }
+
+    #[test]
+    fn validation_report_from_validate() {
+        let report: ValidationReport = from_document(doc! {
+            "ns": "db.coll",
+            "nInvalidDocuments": 0,
+            "nrecords": 10,
+            "nIndexes": 1,
+            "indexDetails": { "_id_": { "valid": true } },
+            "valid": true,
+            "warnings": [],
+            "errors": [],
+            "ok": 1.0,
+        })
+        .unwrap();
+
+        assert!(report.valid);
+        assert!(report.warnings.is_empty());
+        assert_eq!(report.n_invalid_documents, Some(0));
+        assert_eq!(
+            report.index_details,
+            Some(doc! { "_id_": { "valid": true } })
+        );
+
+        let report: ValidationReport = from_document(doc! {
+            "valid": false,
+            "errors": ["corrupted index"],
+        })
+        .unwrap();
+        assert!(!report.valid);
+        assert_eq!(report.errors, vec!["corrupted index"]);
+    }
+
+    #[test]
+    fn bulk_update_write_concern() {
+        let write_concern = WriteConcern::builder()
+            .w(Acknowledgment::Majority)
+            .w_timeout(Duration::from_secs(5))
+            .build();
+        let (command, _) = &h_bulk_update_commands(
+            "users",
+            &[BulkUpdate {
+                query: doc! { "name": "David" },
+                update: doc! { "$set": { "age": 30 } },
+                options: None,
+            }],
+            Some(&write_concern),
+        )
+        .unwrap()[0];
+        assert_eq!(
+            command.get_document("writeConcern").unwrap(),
+            &doc! { "w": "majority", "wtimeout": 5000 }
+        );
+
+        let res: BulkUpdateResult = from_document(doc! {
+            "n": 1,
+            "nModified": 1,
+            "writeConcernError": {
+                "code": 64,
+                "codeName": "WriteConcernFailed",
+                "errmsg": "waiting for replication timed out",
+            },
+            "ok": 1.0,
+        })
+        .unwrap();
+        assert_eq!(res.nb_modified, 1);
+        assert_eq!(res.write_concern_error.unwrap().code, 64);
+    }
+
+    #[test]
+    fn bulk_update_split() {
+        let updates: Vec<BulkUpdate> = (0..250_000)
+            .map(|i| BulkUpdate {
+                query: doc! { "_id": i },
+                update: doc! { "$set": { "n": i } },
+                options: None,
+            })
+            .collect();
+        let commands = h_bulk_update_commands("users", &updates, None).unwrap();
+        let lens: Vec<(usize, u64)> = commands
+            .iter()
+            .map(|(command, offset)| (command.get_array("updates").unwrap().len(), *offset))
+            .collect();
+        assert_eq!(
+            lens,
+            vec![(100_000, 0), (100_000, 100_000), (50_000, 200_000)]
+        );
+
+        // Split by size, large operations going alone
+        let docs: Vec<Document> = [10, 10, 60, 10, 200, 10]
+            .iter()
+            .map(|len| doc! { "s": "x".repeat(*len) })
+            .collect();
+        let chunks = h_split_write_batch(docs, 1000, 120).unwrap();
+        let lens: Vec<(usize, u64)> = chunks
+            .iter()
+            .map(|(chunk, offset)| (chunk.len(), *offset))
+            .collect();
+        assert_eq!(lens, vec![(2, 0), (2, 2), (1, 4), (1, 5)]);
+
+        let chunks = h_split_write_batch(Vec::new(), 1000, 100).unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        // Upserted indexes are shifted into the global index space
+        let ids = [ObjectId::new(), ObjectId::new()];
+        let mut total: BulkUpdateResult = from_document(
+            doc! { "n": 2, "nModified": 1, "upserted": [{ "index": 1, "_id": ids[0] }] },
+        )
+        .unwrap();
+        let res: BulkUpdateResult = from_document(
+            doc! { "n": 3, "nModified": 2, "upserted": [{ "index": 0, "_id": ids[1] }] },
+        )
+        .unwrap();
+        total.h_merge(res, 100_000);
+        assert_eq!(total.nb_affected, 5);
+        assert_eq!(total.nb_modified, 3);
+        assert_eq!(
+            total
+                .upserted
+                .iter()
+                .map(|u| (u.index, u.id))
+                .collect::<Vec<_>>(),
+            vec![(1, ids[0]), (100_000, ids[1])]
+        );
+
+        // So are write errors
+        let res: BulkUpdateResult = from_document(doc! {
+            "n": 0,
+            "nModified": 0,
+            "writeErrors": [{ "index": 3, "code": 11000, "errmsg": "E11000 duplicate key error" }],
+        })
+        .unwrap();
+        total.h_merge(res, 200_000);
+        assert_eq!(total.write_errors.len(), 1);
+        assert_eq!(total.write_errors[0].index, 200_003);
+        assert_eq!(total.write_errors[0].code, 11000);
+    }
+
+    #[test]
+    fn find_command() {
+        let options = FindOptions::builder()
+            .sort(doc! { "age": -1 })
+            .limit(10)
+            .max_time(Duration::from_secs(2))
+            .let_vars(doc! { "min": 18 })
+            .allow_disk_use(true)
+            .min(doc! { "age": 18 })
+            .max(doc! { "age": 65 })
+            .batch_size(100)
+            .build();
+        assert_eq!(
+            h_find_command("users", doc! { "name": "David" }, options).unwrap(),
+            doc! {
+                "find": "users",
+                "filter": { "name": "David" },
+                "sort": { "age": -1 },
+                "limit": 10i64,
+                "maxTimeMS": 2000i64,
+                "let": { "min": 18 },
+                "allowDiskUse": true,
+                "min": { "age": 18 },
+                "max": { "age": 65 },
+            }
+        );
+    }
+
+    #[test]
+    fn explain_summary() {
+        // Classic engine, covered query
+        let explain = doc! {
+            "explainVersion": "1",
+            "queryPlanner": {
+                "winningPlan": {
+                    "stage": "PROJECTION_COVERED",
+                    "inputStage": { "stage": "IXSCAN", "indexName": "name_1" },
+                },
+                "rejectedPlans": [],
+            },
+            "executionStats": {
+                "nReturned": 1,
+                "totalKeysExamined": 1,
+                "totalDocsExamined": 0,
+            },
+            "ok": 1.0,
+        };
+        let summary = ExplainSummary::from_explain(&explain);
+        assert_eq!(
+            summary,
+            ExplainSummary {
+                stage: Some("PROJECTION_COVERED".to_owned()),
+                index_name: Some("name_1".to_owned()),
+                docs_examined: Some(0),
+                keys_examined: Some(1),
+                n_returned: Some(1),
+            }
+        );
+        assert!(summary.is_covered());
+
+        // Slot-based engine, collection scan
+        let explain = doc! {
+            "explainVersion": "2",
+            "queryPlanner": {
+                "winningPlan": {
+                    "queryPlan": { "stage": "COLLSCAN" },
+                    "slotBasedPlan": {},
+                },
+            },
+            "executionStats": {
+                "nReturned": 3_i64,
+                "totalKeysExamined": 0_i64,
+                "totalDocsExamined": 10_i64,
+            },
         };
-        if let Some(ref write_concern) = self.write_concern() {
-            command.insert("writeConcern", to_bson(write_concern)?);
+        let summary = ExplainSummary::from_explain(&explain);
+        assert_eq!(summary.stage.as_deref(), Some("COLLSCAN"));
+        assert_eq!(summary.index_name, None);
+        assert_eq!(summary.docs_examined, Some(10));
+        assert!(!summary.is_covered());
+
+        assert_eq!(
+            ExplainSummary::from_explain(&doc! {}),
+            ExplainSummary::default()
+        );
+    }
+
+    #[test]
+    fn aggregate_explain_summary() {
+        let explain = doc! {
+            "explainVersion": "1",
+            "stages": [
+                {
+                    "$cursor": {
+                        "queryPlanner": {
+                            "winningPlan": {
+                                "stage": "PROJECTION_COVERED",
+                                "inputStage": { "stage": "IXSCAN", "indexName": "user_1" },
+                            },
+                        },
+                        "executionStats": { "nReturned": 4, "totalKeysExamined": 4, "totalDocsExamined": 0 },
+                    },
+                    "nReturned": 4_i64,
+                },
+                { "$group": { "_id": null, "count": { "$sum": 1 } }, "nReturned": 1_i64 },
+            ],
+            "ok": 1.0,
+        };
+        let summary = AggregateExplainSummary::from_explain(&explain);
+        assert_eq!(summary.stages, vec!["$cursor", "$group"]);
+        assert_eq!(summary.query.index_name.as_deref(), Some("user_1"));
+        assert!(summary.query.is_covered());
+
+        // Whole pipeline pushed down to the query layer, on a sharded collection
+        let explain = doc! {
+            "shards": {
+                "shard0": {
+                    "queryPlanner": { "winningPlan": { "stage": "COLLSCAN" } },
+                    "executionStats": { "nReturned": 2, "totalDocsExamined": 10 },
+                },
+            },
+        };
+        let summary = AggregateExplainSummary::from_explain(&explain);
+        assert!(summary.stages.is_empty());
+        assert_eq!(summary.query.stage.as_deref(), Some("COLLSCAN"));
+        assert_eq!(summary.query.docs_examined, Some(10));
+    }
+
+    #[test]
+    fn escape_regex() {
+        assert_eq!(h_escape_regex("events_2024"), "events_2024");
+        assert_eq!(
+            h_escape_regex(r"a.b*c+d?e(f)[g]{h}|i^j$k\l"),
+            r"a\.b\*c\+d\?e\(f\)\[g\]\{h\}\|i\^j\$k\\l"
+        );
+    }
+
+    #[test]
+    fn validate_update() {
+        assert!(
+            h_validate_update(&doc! { "$set": { "name": "David" }, "$inc": { "age": 1 } }).is_ok()
+        );
+
+        let err = h_validate_update(&doc! {}).unwrap_err().to_string();
+        assert!(err.contains("empty"), "{}", err);
+
+        let err = h_validate_update(&doc! { "name": "David" })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'name'"), "{}", err);
+
+        let err = h_validate_update(&doc! { "$set": { "age": 36 }, "name": "David" })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'name'"), "{}", err);
+    }
+
+    #[test]
+    fn non_document_model() {
+        #[derive(Serialize)]
+        struct UserId(i32);
+
+        let err = h_to_document(&UserId(3)).unwrap_err().to_string();
+        assert!(err.contains("UserId` is not a document"), "{}", err);
+        assert!(err.contains("Int32"), "{}", err);
+
+        #[derive(Serialize)]
+        struct User {
+            id: i32,
         }
-        let res = db.run_command(command).await?;
-        Ok(from_document(res)?)
+
+        assert_eq!(h_to_document(&User { id: 3 }).unwrap(), doc! { "id": 3 });
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.h_backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.h_backoff(1), Duration::from_millis(200));
+        assert_eq!(
+            policy.h_backoff(4),
+            Duration::from_secs(1) + Duration::from_millis(600)
+        );
+        assert_eq!(policy.h_backoff(5), Duration::from_secs(2));
+        assert_eq!(policy.h_backoff(100), Duration::from_secs(2));
+
+        let network =
+            mongodb::error::Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert!(is_transient_read_error(&network));
+
+        let command = |code: i32| {
+            mongodb::error::Error::from(mongodb::error::ErrorKind::Command(
+                from_document(doc! { "code": code, "codeName": "", "errmsg": "" }).unwrap(),
+            ))
+        };
+        // NotWritablePrimary
+        assert!(is_transient_read_error(&command(10107)));
+        // Unauthorized
+        assert!(!is_transient_read_error(&command(13)));
+
+        let deserialization =
+            mongodb::bson::from_document::<Vec<i32>>(doc! { "a": 1 }).unwrap_err();
+        assert!(!is_transient_read_error(&deserialization.into()));
     }
 }
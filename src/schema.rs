@@ -0,0 +1,178 @@
+//! `$jsonSchema` validators generated from Rust types.
+
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+
+/// Type with a known `$jsonSchema` representation.
+///
+/// [Mongo manual](https://docs.mongodb.com/manual/reference/operator/query/jsonSchema/)
+///
+/// Implemented for common primitive types, `Option`, `Vec` and bson types.
+/// Structs can implement it by being declared inside the `bson_schema!` macro.
+pub trait BsonSchema {
+    /// `$jsonSchema` document describing values of this type.
+    fn bson_schema() -> Document;
+
+    /// Whether a field of this type must be present in the document.
+    ///
+    /// This method has a default implementation returning `true`.
+    fn is_required() -> bool {
+        true
+    }
+}
+
+macro_rules! impl_bson_schema {
+    ($bson_type:literal: $( $ty:ty ),+) => {
+        $(
+            impl BsonSchema for $ty {
+                fn bson_schema() -> Document {
+                    doc! { "bsonType": $bson_type }
+                }
+            }
+        )+
+    };
+}
+
+impl_bson_schema!("string": String, str);
+impl_bson_schema!("int": i8, i16, i32, u8, u16);
+impl_bson_schema!("long": i64, u32, u64);
+impl_bson_schema!("double": f32, f64);
+impl_bson_schema!("bool": bool);
+impl_bson_schema!("objectId": ObjectId);
+impl_bson_schema!("date": DateTime);
+impl_bson_schema!("object": Document);
+
+impl BsonSchema for Bson {
+    fn bson_schema() -> Document {
+        // Any value
+        Document::new()
+    }
+}
+
+impl<T: BsonSchema + ?Sized> BsonSchema for &T {
+    fn bson_schema() -> Document {
+        T::bson_schema()
+    }
+
+    fn is_required() -> bool {
+        T::is_required()
+    }
+}
+
+impl<T: BsonSchema> BsonSchema for Vec<T> {
+    fn bson_schema() -> Document {
+        doc! { "bsonType": "array", "items": T::bson_schema() }
+    }
+}
+
+impl<T: BsonSchema> BsonSchema for Option<T> {
+    fn bson_schema() -> Document {
+        // `None` is serialized as `null` unless skipped
+        let mut schema = T::bson_schema();
+        if let Some(Bson::String(bson_type)) = schema.get("bsonType") {
+            let bson_type = bson_type.clone();
+            schema.insert("bsonType", vec![Bson::String(bson_type), "null".into()]);
+        }
+        schema
+    }
+
+    fn is_required() -> bool {
+        false
+    }
+}
+
+/// Declare a struct and implement `BsonSchema` for it from its fields, keeping the `$jsonSchema`
+/// validator in sync with the Rust definition.
+///
+/// All fields are required except `Option` ones. Fields are recursively described using their own
+/// `BsonSchema` implementation, so nested structs must be declared with `bson_schema!` too.
+///
+/// Like `field!`, this won't work with `#[serde(rename = "...")]`, `#[serde(rename_all = "...")]`
+/// or `#[serde(flatten)]`, and generic structs are not supported.
+///
+/// # Example
+///
+/// ```
+/// use mongodm::prelude::*;
+/// use mongodm::bson_schema;
+/// use serde::{Serialize, Deserialize};
+///
+/// bson_schema! {
+///     #[derive(Serialize, Deserialize)]
+///     struct User {
+///         name: String,
+///         age: i32,
+///         nickname: Option<String>,
+///         address: Address,
+///     }
+/// }
+///
+/// bson_schema! {
+///     #[derive(Serialize, Deserialize)]
+///     struct Address {
+///         city: String,
+///     }
+/// }
+///
+/// assert_eq!(
+///     User::bson_schema(),
+///     doc! {
+///         "bsonType": "object",
+///         "required": ["name", "age", "address"],
+///         "properties": {
+///             "name": { "bsonType": "string" },
+///             "age": { "bsonType": "int" },
+///             "nickname": { "bsonType": ["string", "null"] },
+///             "address": {
+///                 "bsonType": "object",
+///                 "required": ["city"],
+///                 "properties": {
+///                     "city": { "bsonType": "string" },
+///                 },
+///             },
+///         },
+///     }
+/// );
+///
+/// // To be used as a collection validator
+/// let options = MongoCreateCollectionOptions::builder()
+///     .validator(doc! { "$jsonSchema": User::bson_schema() })
+///     .build();
+/// ```
+#[macro_export]
+macro_rules! bson_schema {
+    (
+        $( #[$meta:meta] )*
+        $vis:vis struct $name:ident {
+            $( $( #[$field_meta:meta] )* $field_vis:vis $field:ident : $ty:ty ),* $(,)?
+        }
+    ) => {
+        $( #[$meta] )*
+        $vis struct $name {
+            $( $( #[$field_meta] )* $field_vis $field : $ty ),*
+        }
+
+        impl $crate::BsonSchema for $name {
+            fn bson_schema() -> $crate::mongo::bson::Document {
+                #[allow(unused_mut)]
+                let mut required: ::std::vec::Vec<$crate::mongo::bson::Bson> = ::std::vec::Vec::new();
+                #[allow(unused_mut)]
+                let mut properties = $crate::mongo::bson::Document::new();
+                $(
+                    if <$ty as $crate::BsonSchema>::is_required() {
+                        required.push(stringify!($field).into());
+                    }
+                    properties.insert(stringify!($field), <$ty as $crate::BsonSchema>::bson_schema());
+                )*
+
+                let mut schema = $crate::mongo::bson::doc! { "bsonType": "object" };
+                // `required` must contain at least one element
+                if !required.is_empty() {
+                    schema.insert("required", required);
+                }
+                schema.insert("properties", properties);
+                schema
+            }
+        }
+    };
+}
@@ -1,10 +1,14 @@
 #[macro_use]
 extern crate pretty_assertions;
 
-use mongodb::bson::{doc, Document};
-use mongodb::options::ClientOptions;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{ClientOptions, Collation, CollationStrength, CreateCollectionOptions};
 use mongodb::Client;
-use mongodm::{sync_indexes, CollectionConfig, Index, IndexOption, Indexes};
+use mongodm::{
+    field, provision, sync_indexes, sync_indexes_and_wait, sync_indexes_with_collection_name,
+    CollectionConfig, Index, IndexOption, Indexes, Model, ToRepository,
+};
 
 struct OneSyncCollConf;
 
@@ -239,3 +243,386 @@ async fn multiple_sync() {
         }
     );
 }
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct EnsureIndex {
+    field: i64,
+}
+
+impl Model for EnsureIndex {
+    type CollConf = EnsureIndexCollConf;
+}
+
+struct EnsureIndexCollConf;
+
+impl CollectionConfig for EnsureIndexCollConf {
+    fn collection_name() -> &'static str {
+        "ensure_index"
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn ensure_index() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<EnsureIndex>();
+    repository.drop().await.unwrap();
+
+    let index = Index::new("field").with_option(IndexOption::Unique);
+    assert_eq!(
+        repository.ensure_index(index.clone()).await.unwrap(),
+        "field_1"
+    );
+    // Creating the same index again is a no-op
+    assert_eq!(repository.ensure_index(index).await.unwrap(), "field_1");
+
+    let infos = repository.list_index_infos().await.unwrap();
+    assert_eq!(infos.len(), 2);
+    assert_eq!(infos[1].name, "field_1");
+    assert_eq!(infos[1].key, doc! { "field": 1 });
+    assert!(infos[1].unique);
+}
+
+struct CollatedSyncCollConf;
+
+impl CollectionConfig for CollatedSyncCollConf {
+    fn collection_name() -> &'static str {
+        "collated_sync"
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(
+            Index::new("field")
+                .with_option(IndexOption::Unique)
+                .with_collation(
+                    Collation::builder()
+                        .locale("en")
+                        .strength(CollationStrength::Secondary)
+                        .build(),
+                ),
+        )
+    }
+}
+
+async fn index_since(db: &mongodb::Database, collection_name: &str, name: &str) -> Bson {
+    let mut cursor = db
+        .collection::<Document>(collection_name)
+        .aggregate(vec![
+            doc! { "$indexStats": {} },
+            doc! { "$match": { "name": name } },
+        ])
+        .await
+        .unwrap();
+    let stats = cursor.try_next().await.unwrap().unwrap();
+    stats
+        .get_document("accesses")
+        .unwrap()
+        .get("since")
+        .unwrap()
+        .clone()
+}
+
+#[tokio::test]
+#[ignore]
+async fn collated_sync() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let collection_name = CollatedSyncCollConf::collection_name();
+    db.collection::<Document>(collection_name)
+        .drop()
+        .await
+        .unwrap();
+
+    sync_indexes::<CollatedSyncCollConf>(&db).await.unwrap();
+    let since = index_since(&db, collection_name, "field_1").await;
+
+    // The server expands the collation with defaults, which must not cause the index to be recreated
+    sync_indexes::<CollatedSyncCollConf>(&db).await.unwrap();
+    assert_eq!(index_since(&db, collection_name, "field_1").await, since);
+}
+
+#[allow(dead_code)]
+struct NestedUser {
+    profile: NestedProfile,
+}
+
+#[allow(dead_code)]
+struct NestedProfile {
+    city: String,
+    country: String,
+}
+
+struct NestedSyncCollConf;
+
+impl CollectionConfig for NestedSyncCollConf {
+    fn collection_name() -> &'static str {
+        "nested_sync"
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(
+            Index::new(field!((profile in NestedUser).(country in NestedProfile)))
+                .with_key(field!((profile in NestedUser).(city in NestedProfile)))
+                .with_option(IndexOption::Unique),
+        )
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn nested_sync() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let collection_name = NestedSyncCollConf::collection_name();
+    db.collection::<Document>(collection_name)
+        .drop()
+        .await
+        .unwrap();
+
+    sync_indexes::<NestedSyncCollConf>(&db).await.unwrap();
+    let since = index_since(&db, collection_name, "profile.country_1_profile.city_1").await;
+
+    // Re-syncing with dotted keys is a no-op
+    sync_indexes::<NestedSyncCollConf>(&db).await.unwrap();
+    assert_eq!(
+        index_since(&db, collection_name, "profile.country_1_profile.city_1").await,
+        since
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn sync_and_wait() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let collection_name = OneSyncCollConf::collection_name();
+    db.collection::<Document>(collection_name)
+        .drop()
+        .await
+        .unwrap();
+
+    sync_indexes_and_wait::<OneSyncCollConf>(&db).await.unwrap();
+
+    let names: Vec<String> = db
+        .collection::<Document>(collection_name)
+        .list_index_names()
+        .await
+        .unwrap();
+    assert!(names.contains(&String::from("field_1")), "{:?}", names);
+}
+
+struct CompoundTextSyncCollConf;
+
+impl CollectionConfig for CompoundTextSyncCollConf {
+    fn collection_name() -> &'static str {
+        "compound_text_sync"
+    }
+
+    fn indexes() -> Indexes {
+        let mut index = Index::new("tenant");
+        index.add_key_with_text("body");
+        Indexes::new().with(index)
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn compound_text_sync() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let collection_name = CompoundTextSyncCollConf::collection_name();
+    db.collection::<Document>(collection_name)
+        .drop()
+        .await
+        .unwrap();
+
+    sync_indexes::<CompoundTextSyncCollConf>(&db).await.unwrap();
+    let since = index_since(&db, collection_name, "tenant_1_body_text").await;
+
+    // The scalar prefix is kept in the stored key, which must not cause the index to be recreated
+    sync_indexes::<CompoundTextSyncCollConf>(&db).await.unwrap();
+    assert_eq!(
+        index_since(&db, collection_name, "tenant_1_body_text").await,
+        since
+    );
+}
+
+struct ProvisionCollConf;
+
+impl CollectionConfig for ProvisionCollConf {
+    fn collection_name() -> &'static str {
+        "provision"
+    }
+
+    fn create_collection_options() -> Option<CreateCollectionOptions> {
+        Some(
+            CreateCollectionOptions::builder()
+                .validator(doc! { "field": { "$type": "string" } })
+                .build(),
+        )
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(Index::new("field").with_option(IndexOption::Unique))
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn provision_collection() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let coll = db.collection::<Document>(ProvisionCollConf::collection_name());
+    coll.drop().await.unwrap();
+
+    let report = provision::<ProvisionCollConf>(&db).await.unwrap();
+    assert!(report.collection_created);
+    assert_eq!(report.indexes_created, vec!["field_1".to_owned()]);
+    assert!(report.indexes_dropped.is_empty());
+
+    // Validator is in place before any write
+    assert!(coll.insert_one(doc! { "field": 3 }).await.is_err());
+
+    let report = provision::<ProvisionCollConf>(&db).await.unwrap();
+    assert_eq!(report, Default::default());
+}
+
+struct UniqueIfPresentCollConf;
+
+impl CollectionConfig for UniqueIfPresentCollConf {
+    fn collection_name() -> &'static str {
+        "unique_if_present"
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(Index::new_unique_if_present(
+            "email",
+            mongodm::query::BsonTypeName::String,
+        ))
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn unique_if_present() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let coll = db.collection::<Document>(UniqueIfPresentCollConf::collection_name());
+    coll.drop().await.unwrap();
+
+    let report = provision::<UniqueIfPresentCollConf>(&db).await.unwrap();
+    assert_eq!(report.indexes_created, vec!["email_1".to_owned()]);
+
+    coll.insert_many(vec![
+        doc! { "name": "a" },
+        doc! { "name": "b" },
+        doc! { "name": "c", "email": Bson::Null },
+        doc! { "name": "d", "email": Bson::Null },
+        doc! { "name": "e", "email": "e@example.com" },
+    ])
+    .await
+    .unwrap();
+    assert!(coll
+        .insert_one(doc! { "name": "f", "email": "e@example.com" })
+        .await
+        .is_err());
+
+    // Partial filter is recognized as in sync
+    let report = provision::<UniqueIfPresentCollConf>(&db).await.unwrap();
+    assert_eq!(report, Default::default());
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PartitionedEvent {
+    field: i64,
+}
+
+impl Model for PartitionedEvent {
+    type CollConf = PartitionedEventCollConf;
+}
+
+struct PartitionedEventCollConf;
+
+impl CollectionConfig for PartitionedEventCollConf {
+    fn collection_name() -> &'static str {
+        "partitioned_event"
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(Index::new("field").with_option(IndexOption::Unique))
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn partitioned_collections() {
+    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = Client::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<PartitionedEvent>();
+    for suffix in ["2024_05", "2024_06"] {
+        let partition = repository.for_partition(suffix);
+        assert_eq!(partition.name(), format!("partitioned_event_{}", suffix));
+        partition.drop().await.unwrap();
+
+        sync_indexes_with_collection_name::<PartitionedEventCollConf>(&db, partition.name())
+            .await
+            .unwrap();
+        partition
+            .insert_one(PartitionedEvent { field: 1 })
+            .await
+            .unwrap();
+        // Each partition has its own unique index
+        partition
+            .insert_one(PartitionedEvent { field: 1 })
+            .await
+            .unwrap_err();
+    }
+
+    let partitions = repository
+        .list_sibling_collections("partitioned_event_")
+        .await
+        .unwrap();
+    assert_eq!(
+        partitions,
+        vec!["partitioned_event_2024_05", "partitioned_event_2024_06"]
+    );
+    // Matched literally, not as a regex
+    assert!(repository
+        .list_sibling_collections("partitioned.event")
+        .await
+        .unwrap()
+        .is_empty());
+}
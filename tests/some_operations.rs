@@ -1,9 +1,13 @@
 #[macro_use]
 extern crate pretty_assertions;
 
-use futures_util::StreamExt;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::event::command::CommandEvent;
+use mongodb::event::EventHandler;
 use mongodm::prelude::*;
+use mongodm::CopyErrorPolicy;
 use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
 
 struct UserCollConf;
 
@@ -198,4 +202,1069 @@ async fn bulk_updates() {
         .unwrap();
     assert_eq!(user_dane.name, "David");
     assert_eq!(user_dane.age, 30);
+
+    // Renaming Teri to David violates the unique index: the following update is not attempted
+    let bulk_update_res = repository
+        .bulk_update(&vec![
+            &BulkUpdate {
+                query: doc! { f!(name in User): "Dane" },
+                update: doc! { Set: { f!(age in User): 13 } },
+                options: None,
+            },
+            &BulkUpdate {
+                query: doc! { f!(name in User): "Teri" },
+                update: doc! { Set: { f!(name in User): "David" } },
+                options: None,
+            },
+            &BulkUpdate {
+                query: doc! { f!(name in User): "Edna" },
+                update: doc! { Set: { f!(age in User): 58 } },
+                options: None,
+            },
+        ])
+        .await
+        .unwrap();
+    assert_eq!(bulk_update_res.nb_modified, 1);
+    assert_eq!(bulk_update_res.write_errors.len(), 1);
+    assert_eq!(bulk_update_res.write_errors[0].index, 1);
+    assert_eq!(bulk_update_res.write_errors[0].code, 11000);
+
+    let user_edna = repository
+        .find_one(doc! { f!(name in User): "Edna" })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user_edna.age, 57);
+}
+
+#[tokio::test]
+#[ignore]
+async fn keyset_pagination() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    sync_indexes::<UserCollConf>(&db).await.unwrap();
+
+    let users = (0..7).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i % 3, // ties on the sort field
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let mut ages = Vec::new();
+    let mut after = None;
+    loop {
+        let page = repository
+            .paginate_after(doc! {}, f!(age in User), after, 3)
+            .await
+            .unwrap();
+        ages.extend(page.items.into_iter().map(|u| u.age));
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(ages, vec![20, 20, 20, 21, 21, 22, 22]);
+
+    assert!(repository
+        .paginate_after(doc! {}, f!(age in User), None, 0)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+#[ignore]
+async fn keyset_pagination_nested_field() {
+    struct ContactCollConf;
+
+    impl CollectionConfig for ContactCollConf {
+        fn collection_name() -> &'static str {
+            "keyset_pagination_nested_field"
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Address {
+        city: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Contact {
+        name: String,
+        address: Address,
+    }
+
+    impl Model for Contact {
+        type CollConf = ContactCollConf;
+    }
+
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<Contact>();
+    repository.drop().await.unwrap();
+
+    let contacts = ["Quebec", "Montreal", "Ottawa", "Montreal", "Toronto"]
+        .iter()
+        .enumerate()
+        .map(|(i, city)| Contact {
+            name: format!("contact{}", i),
+            address: Address {
+                city: city.to_string(),
+            },
+        });
+    repository.insert_many(contacts).await.unwrap();
+
+    let mut cities = Vec::new();
+    let mut after = None;
+    loop {
+        let page = repository
+            .paginate_after(
+                doc! {},
+                f!((address in Contact).(city in Address)),
+                after,
+                2,
+            )
+            .await
+            .unwrap();
+        cities.extend(page.items.into_iter().map(|c| c.address.city));
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(
+        cities,
+        vec!["Montreal", "Montreal", "Ottawa", "Quebec", "Toronto"]
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn aggregate_paginated() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    sync_indexes::<UserCollConf>(&db).await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let page = repository
+        .aggregate_paginated::<User>(doc! {}, doc! { f!(age in User): 1 }, 1, 2)
+        .await
+        .unwrap();
+    assert_eq!(page.total, 5);
+    assert_eq!(
+        page.items.into_iter().map(|u| u.age).collect::<Vec<_>>(),
+        vec![22, 23]
+    );
+
+    let page = repository
+        .aggregate_paginated::<User>(doc! { f!(age in User): 99 }, doc! {}, 0, 2)
+        .await
+        .unwrap();
+    assert_eq!(page.total, 0);
+    assert!(page.items.is_empty());
+}
+
+#[tokio::test]
+#[ignore]
+async fn find_as() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..3).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i,
+        info: "lengthy".repeat(100),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    #[derive(Deserialize)]
+    struct UserName {
+        name: String,
+    }
+
+    let names: Vec<String> = repository
+        .find_as::<UserName>(
+            doc! { f!(age in User): { GreaterThan: 20 } },
+            doc! { "_id": 0, f!(name in User): 1 },
+            MongoFindOptions::builder()
+                .sort(doc! { f!(age in User): 1 })
+                .build(),
+        )
+        .await
+        .unwrap()
+        .map_ok(|user| user.name)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(names, vec!["user1", "user2"]);
+}
+
+#[tokio::test]
+#[ignore]
+async fn configured_repository_view() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..3).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i,
+        info: format!("info{}", i),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let view = repository
+        .read_secondary_preferred()
+        .with_default_sort(doc! { f!(age in User): -1 })
+        .with_default_projection(
+            doc! { f!(name in User): 1, f!(age in User): 1, f!(info in User): { "$literal": "" } },
+        );
+    assert!(view.selection_criteria().is_some());
+
+    let users = view.find_models(doc! {}).await.unwrap();
+    assert_eq!(
+        users.iter().map(|u| u.age).collect::<Vec<_>>(),
+        vec![22, 21, 20]
+    );
+    assert!(users.iter().all(|u| u.info.is_empty()));
+
+    // Per-call options win over the configured defaults
+    let users: Vec<User> = view
+        .find_with_defaults(
+            doc! {},
+            MongoFindOptions::builder()
+                .sort(doc! { f!(age in User): 1 })
+                .projection(doc! { "_id": 0 })
+                .build(),
+        )
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(users[0].age, 20);
+    assert_eq!(users[0].info, "info0");
+}
+
+#[tokio::test]
+#[ignore]
+async fn find_with_total() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..10).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let (total, cursor) = repository
+        .find_with_total(
+            doc! { f!(age in User): { GreaterThanEqual: 2 } },
+            MongoFindOptions::builder().skip(1).limit(5).build(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(total, 5);
+    let users: Vec<User> = cursor.try_collect().await.unwrap();
+    assert_eq!(users.len() as u64, total);
+}
+
+#[tokio::test]
+#[ignore]
+async fn aggregate_as() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i % 2,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    #[derive(Deserialize)]
+    struct AgeCount {
+        #[serde(rename = "_id")]
+        age: i32,
+        count: i32,
+    }
+
+    let counts: Vec<AgeCount> = repository
+        .aggregate_as(
+            pipeline! [
+                Group: { "_id": f!(@age in User), "count": { Sum: 1 } },
+                Sort: { "_id": 1 },
+            ],
+            MongoAggregateOptions::builder()
+                .allow_disk_use(true)
+                .build(),
+        )
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(
+        counts.iter().map(|c| (c.age, c.count)).collect::<Vec<_>>(),
+        vec![(20, 3), (21, 2)]
+    );
+
+    let oldest: Option<User> = repository
+        .aggregate_one(pipeline! [Sort: { f!(age in User): -1 }], None)
+        .await
+        .unwrap();
+    assert_eq!(oldest.unwrap().age, 21);
+}
+
+#[tokio::test]
+#[ignore]
+async fn copy_to() {
+    struct CopyCollConf;
+
+    impl CollectionConfig for CopyCollConf {
+        fn collection_name() -> &'static str {
+            "some_operations_copy"
+        }
+
+        fn indexes() -> Indexes {
+            Indexes::new().with(Index::new(f!(name in User)).with_option(IndexOption::Unique))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct UserCopy {
+        name: String,
+    }
+
+    impl Model for UserCopy {
+        type CollConf = CopyCollConf;
+    }
+
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    let dest = db.repository::<UserCopy>();
+    dest.drop().await.unwrap();
+    sync_indexes::<CopyCollConf>(&db).await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+    dest.insert_one(UserCopy {
+        name: String::from("user3"),
+    })
+    .await
+    .unwrap();
+
+    let to_copy = |user: User| UserCopy { name: user.name };
+    assert!(repository
+        .copy_to(&dest, doc! {}, to_copy, 2, CopyErrorPolicy::Abort)
+        .await
+        .is_err());
+    dest.delete_many(doc! { f!(name in UserCopy): { NotEqual: "user3" } })
+        .await
+        .unwrap();
+
+    let copied = repository
+        .copy_to(&dest, doc! {}, to_copy, 2, CopyErrorPolicy::Continue)
+        .await
+        .unwrap();
+    assert_eq!(copied, 4);
+    assert_eq!(dest.count_documents(doc! {}).await.unwrap(), 5);
+}
+
+#[tokio::test]
+#[ignore]
+async fn insert_many_unordered() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    sync_indexes::<UserCollConf>(&db).await.unwrap();
+
+    let users: Vec<User> = ["David", "Stacey", "David", "Dane"]
+        .iter()
+        .map(|name| User {
+            name: name.to_string(),
+            age: 20,
+            info: String::new(),
+        })
+        .collect();
+
+    let res = repository.insert_many_unordered(&users).await.unwrap();
+    assert_eq!(res.write_errors.len(), 1);
+    assert_eq!(res.write_errors[0].index, 2);
+    assert_eq!(res.write_errors[0].code, 11000);
+    assert_eq!(res.inserted_ids.len(), 3);
+    assert!(!res.inserted_ids.contains_key(&2));
+    assert_eq!(repository.count_documents(doc! {}).await.unwrap(), 3);
+}
+
+#[tokio::test]
+#[ignore]
+async fn write_ndjson() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..3).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+    repository
+        .clone_with_type::<mongodm::bson::Document>()
+        .insert_one(doc! { f!(name in User): "invalid" })
+        .await
+        .unwrap();
+
+    let options = MongoFindOptions::builder()
+        .projection(doc! { "_id": 0 })
+        .sort(doc! { f!(name in User): 1 })
+        .build();
+
+    let mut out = Vec::new();
+    let written = repository
+        .find_with_defaults(doc! {}, options.clone())
+        .await
+        .unwrap()
+        .write_ndjson(&mut out, true)
+        .await
+        .unwrap();
+    assert_eq!(written, 3);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\"name\":\"user0\",\"age\":0,\"info\":\"\"}\n\
+         {\"name\":\"user1\",\"age\":1,\"info\":\"\"}\n\
+         {\"name\":\"user2\",\"age\":2,\"info\":\"\"}\n"
+    );
+
+    assert!(repository
+        .find_with_defaults(doc! {}, options)
+        .await
+        .unwrap()
+        .write_ndjson(Vec::new(), false)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+#[ignore]
+async fn count_by() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: 20 + i % 2,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+    let raw = repository.clone_with_type::<mongodm::bson::Document>();
+    raw.insert_one(doc! { f!(name in User): "no age" })
+        .await
+        .unwrap();
+    raw.insert_one(doc! { f!(name in User): "null age", f!(age in User): null })
+        .await
+        .unwrap();
+
+    let counts = repository
+        .count_by::<i32>(f!(age in User), None)
+        .await
+        .unwrap();
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts[&Some(20)], 3);
+    assert_eq!(counts[&Some(21)], 2);
+    assert_eq!(counts[&None], 2);
+
+    let counts = repository
+        .count_by::<i32>(f!(age in User), doc! { f!(age in User): 21 })
+        .await
+        .unwrap();
+    assert_eq!(counts.len(), 1);
+}
+
+#[tokio::test]
+#[ignore]
+async fn update_one_returning_old() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    repository
+        .insert_one(User {
+            name: String::from("David"),
+            age: 35,
+            info: String::new(),
+        })
+        .await
+        .unwrap();
+
+    let old = repository
+        .update_one_returning_old(
+            doc! { f!(name in User): "David" },
+            doc! { Set: { f!(age in User): 36 } },
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(old.age, 35);
+
+    assert!(repository
+        .update_one_returning_old(
+            doc! { f!(name in User): "Nobody" },
+            doc! { Set: { f!(age in User): 36 } },
+        )
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+#[ignore]
+async fn upsert_and_fetch() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let filter = doc! { f!(name in User): "David" };
+    let update = doc! {
+        Inc: { f!(age in User): 1 },
+        SetOnInsert: { f!(info in User): "created" },
+    };
+
+    assert!(repository
+        .upsert_returning_old(filter.clone(), update.clone())
+        .await
+        .unwrap()
+        .is_none());
+
+    let user = repository
+        .upsert_and_fetch(filter.clone(), update.clone())
+        .await
+        .unwrap();
+    assert_eq!(user.age, 2);
+    assert_eq!(user.info, "created");
+
+    let old = repository
+        .upsert_returning_old(filter, update)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(old.age, 2);
+    assert_eq!(repository.count_documents(doc! {}).await.unwrap(), 1);
+}
+
+#[tokio::test]
+#[ignore]
+async fn run_command() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    let reply = repository.run_command(doc! { "ping": 1 }).await.unwrap();
+    assert_eq!(reply.get_f64("ok").unwrap(), 1.0);
+
+    let err = repository
+        .run_command_checked(doc! { "collMod": "rust_mongo_orm_tests_missing_collection" })
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.kind.as_ref(), MongoErrorKind::Command(_)),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn ping() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+    db.repository::<User>().ping().await.unwrap();
+
+    let mut client_options = MongoClientOptions::parse("mongodb://localhost:1")
+        .await
+        .unwrap();
+    client_options.server_selection_timeout = Some(std::time::Duration::from_millis(100));
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+    assert!(db.repository::<User>().ping().await.is_err());
+}
+
+#[tokio::test]
+#[ignore]
+async fn increment_and_get() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let filter = doc! { f!(name in User): "counter" };
+    // Counter is created on first use
+    assert_eq!(
+        repository
+            .increment_and_get(filter.clone(), f!(age in User), 5)
+            .await
+            .unwrap(),
+        5
+    );
+    assert_eq!(
+        repository
+            .increment_and_get(filter, f!(age in User), 1)
+            .await
+            .unwrap(),
+        6
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn batch_size() {
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let mut client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    client_options.command_event_handler = Some(EventHandler::callback({
+        let started = started.clone();
+        move |event| {
+            if let CommandEvent::Started(event) = event {
+                started
+                    .lock()
+                    .unwrap()
+                    .push((event.command_name, event.command));
+            }
+        }
+    }));
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..10).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    started.lock().unwrap().clear();
+    let users: Vec<User> = repository
+        .with_batch_size(3)
+        .find_with_defaults(doc! {}, None)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 10);
+
+    let started = started.lock().unwrap();
+    let find = started.iter().find(|(name, _)| name == "find").unwrap();
+    assert_eq!(find.1.get_i32("batchSize").unwrap(), 3);
+    // 3 documents in the first batch, then 3 + 3 + 1
+    let get_mores = started.iter().filter(|(name, _)| name == "getMore").count();
+    assert_eq!(get_mores, 3);
+}
+
+#[tokio::test]
+#[ignore]
+async fn collect_up_to() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let (users, truncated) = repository
+        .find_with_defaults(doc! {}, None)
+        .await
+        .unwrap()
+        .collect_up_to(3)
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 3);
+    assert!(truncated);
+
+    let (users, truncated) = repository
+        .find_with_defaults(doc! {}, None)
+        .await
+        .unwrap()
+        .collect_up_to(10)
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 5);
+    assert!(!truncated);
+}
+
+#[tokio::test]
+#[ignore]
+async fn insert_one_and_get() {
+    struct AccountCollConf;
+
+    impl CollectionConfig for AccountCollConf {
+        fn collection_name() -> &'static str {
+            "insert_one_and_get"
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Account {
+        #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+        id: Option<ObjectId>,
+        name: String,
+    }
+
+    impl Model for Account {
+        type CollConf = AccountCollConf;
+    }
+
+    impl WithId for Account {
+        fn id(&self) -> Option<ObjectId> {
+            self.id
+        }
+
+        fn set_id(&mut self, id: ObjectId) {
+            self.id = Some(id);
+        }
+    }
+
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<Account>();
+    repository.drop().await.unwrap();
+
+    let account = repository
+        .insert_one_and_get(Account {
+            id: None,
+            name: String::from("David"),
+        })
+        .await
+        .unwrap();
+    let id = account.id.expect("id populated");
+    let found = repository
+        .find_one(doc! { "_id": id })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.name, "David");
+
+    let id = ObjectId::new();
+    let account = repository
+        .insert_one_and_get(Account {
+            id: Some(id),
+            name: String::from("Stacey"),
+        })
+        .await
+        .unwrap();
+    assert_eq!(account.id, Some(id));
+}
+
+#[tokio::test]
+#[ignore]
+async fn peekable_cursor() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..3).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    let options = MongoFindOptions::builder()
+        .sort(doc! { f!(age in User): 1 })
+        .build();
+    let mut cursor = repository
+        .find_with_defaults(doc! {}, options)
+        .await
+        .unwrap()
+        .peekable();
+
+    // Peeking doesn't consume
+    assert_eq!(cursor.peek().await.unwrap().unwrap().age, 0);
+    assert_eq!(cursor.peek().await.unwrap().unwrap().age, 0);
+    assert!(cursor.advance().await.unwrap());
+    assert_eq!(cursor.peek().await.unwrap().unwrap().age, 1);
+
+    // The peeked value is yielded by the stream
+    let rest: Vec<User> = cursor.try_collect().await.unwrap();
+    assert_eq!(rest.iter().map(|u| u.age).collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[tokio::test]
+#[ignore]
+async fn large_bulk_update() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    // More operations than a single `update` command accepts
+    let updates: Vec<BulkUpdate> = (0..100_500)
+        .map(|i| BulkUpdate {
+            query: doc! { f!(name in User): format!("user{}", i) },
+            update: doc! { Set: { f!(age in User): i, f!(info in User): "" } },
+            options: Some(MongoUpdateOptions::builder().upsert(true).build()),
+        })
+        .collect();
+    let res = repository.bulk_update(&updates).await.unwrap();
+    assert_eq!(res.nb_affected, 100_500);
+    assert_eq!(res.upserted.len(), 100_500);
+    let last = res.upserted.last().unwrap();
+    assert_eq!(last.index, 100_499);
+
+    let user = repository
+        .find_one(doc! { "_id": last.id })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.name, "user100499");
+}
+
+#[tokio::test]
+#[ignore]
+async fn smart_count() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let users = (0..5).map(|i| User {
+        name: format!("user{}", i),
+        age: i,
+        info: String::new(),
+    });
+    repository.insert_many(users).await.unwrap();
+
+    // Estimated
+    assert_eq!(repository.smart_count(None).await.unwrap(), 5);
+    assert_eq!(repository.smart_count(doc! {}).await.unwrap(), 5);
+    // Exact
+    assert_eq!(
+        repository
+            .smart_count(doc! { f!(age in User): { GreaterThanEqual: 3 } })
+            .await
+            .unwrap(),
+        2
+    );
+}
+
+#[tokio::test]
+#[ignore]
+async fn by_id() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+
+    let id = repository
+        .insert_one(User {
+            name: String::from("David"),
+            age: 35,
+            info: String::new(),
+        })
+        .await
+        .unwrap()
+        .inserted_id
+        .as_object_id()
+        .unwrap();
+
+    let res = repository
+        .update_by_id(id, doc! { Inc: { f!(age in User): 1 } }, None)
+        .await
+        .unwrap();
+    assert_eq!(res.modified_count, 1);
+    assert_eq!(repository.get(id).await.unwrap().unwrap().age, 36);
+
+    // Replacements are rejected
+    repository
+        .update_by_id(id, doc! { f!(age in User): 0 }, None)
+        .await
+        .unwrap_err();
+
+    assert!(repository.get(ObjectId::new()).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore] // requires a replica set started with `--setParameter enableTestCommands=1`
+async fn retryable_writes() {
+    let client_options = MongoClientOptions::parse("mongodb://localhost:27017")
+        .await
+        .unwrap();
+    assert!(mongodm::retryable_writes_enabled(&client_options));
+    let client = MongoClient::with_options(client_options).unwrap();
+    let db = client.database("rust_mongo_orm_tests");
+    let admin = client.database("admin");
+
+    let repository = db.repository::<User>();
+    repository.drop().await.unwrap();
+    repository
+        .insert_one(User {
+            name: String::from("David"),
+            age: 35,
+            info: String::new(),
+        })
+        .await
+        .unwrap();
+
+    // Closes the connection on the next `update` command
+    let fail_next_update = doc! {
+        "configureFailPoint": "failCommand",
+        "mode": { "times": 1 },
+        "data": { "failCommands": ["update"], "closeConnection": true },
+    };
+
+    // Native write operations are retried by the driver
+    admin.run_command(fail_next_update.clone()).await.unwrap();
+    let res = repository
+        .update_one(
+            doc! { f!(name in User): "David" },
+            doc! { Inc: { f!(age in User): 1 } },
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.modified_count, 1);
+
+    // The raw command sent by `bulk_update` is not
+    admin.run_command(fail_next_update).await.unwrap();
+    repository
+        .bulk_update(&vec![&BulkUpdate {
+            query: doc! { f!(name in User): "David" },
+            update: doc! { Inc: { f!(age in User): 1 } },
+            options: None,
+        }])
+        .await
+        .unwrap_err();
+
+    admin
+        .run_command(doc! { "configureFailPoint": "failCommand", "mode": "off" })
+        .await
+        .unwrap();
 }